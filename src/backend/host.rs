@@ -0,0 +1,162 @@
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{frontend::ast::Type, runtime};
+
+// Functions of the program hosting the compiler (see Session::register), callable from
+// Kaleidoscope through an extern of the same name. Any Rust closure will do, or an
+// extern "C" fn, as long as it takes and returns types Kaleidoscope has.
+//
+// A closure can't be called from generated code as it is, so every signature gets a
+// trampoline, an extern "C" fn taking a pointer to the closure, followed by its
+// arguments. That is the same convention Kaleidoscope's own closures are called with,
+// the extern is generated as a call to the trampoline with the closure as environment.
+// The REPL catches crashes in generated code, but not in host functions, those crash
+// the process like they would anywhere else (see runtime::outside_guard). A panic is
+// caught by the trampoline, and stops the expression like a crash would.
+#[derive(Debug)]
+pub struct HostFunction {
+    pub(crate) params: Vec<Type>,
+    pub(crate) ret: Type,
+    pub(crate) trampoline: usize,
+    closure: Box<dyn Any>,
+}
+
+impl HostFunction {
+    // Where the closure lives, it's boxed so it never moves
+    pub(crate) fn env(&self) -> usize {
+        &*self.closure as *const dyn Any as *const u8 as usize
+    }
+}
+
+// Rust types with a Kaleidoscope type they are passed as
+pub trait HostType {
+    const TYPE: Type;
+}
+
+impl HostType for f64 {
+    const TYPE: Type = Type::F64;
+}
+
+impl HostType for i64 {
+    const TYPE: Type = Type::I64;
+}
+
+impl HostType for bool {
+    const TYPE: Type = Type::Bool;
+}
+
+// Args is only there to tell apart the implementations for each number of arguments
+pub trait IntoHostFunction<Args> {
+    fn into_host_function(self) -> HostFunction;
+}
+
+macro_rules! impl_into_host_function {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoHostFunction<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: HostType,
+            $($arg: HostType),*
+        {
+            fn into_host_function(self) -> HostFunction {
+                #[allow(non_snake_case)]
+                extern "C" fn trampoline<F, R, $($arg),*>(closure: *const F, $($arg: $arg),*) -> R
+                where
+                    F: Fn($($arg),*) -> R,
+                {
+                    let result = runtime::outside_guard(|| {
+                        catch_unwind(AssertUnwindSafe(|| unsafe { (*closure)($($arg),*) }))
+                    });
+
+                    match result {
+                        Ok(result) => result,
+                        Err(payload) => {
+                            drop(payload);
+                            runtime::host_panicked()
+                        }
+                    }
+                }
+
+                HostFunction {
+                    params: vec![$($arg::TYPE),*],
+                    ret: R::TYPE,
+                    trampoline: trampoline::<F, R, $($arg),*> as *const () as usize,
+                    closure: Box::new(self),
+                }
+            }
+        }
+    };
+}
+
+impl_into_host_function!();
+impl_into_host_function!(A);
+impl_into_host_function!(A, B);
+impl_into_host_function!(A, B, C);
+impl_into_host_function!(A, B, C, D);
+impl_into_host_function!(A, B, C, D, E);
+impl_into_host_function!(A, B, C, D, E, G);
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+
+    use super::*;
+    use crate::{
+        backend::llvm_backend::BackendError,
+        session::{Session, SessionError},
+    };
+
+    #[test]
+    fn trampolines_call_the_closure() {
+        let offset = 10.0;
+        let host = (move |x: f64, times: i64| x * times as f64 + offset).into_host_function();
+
+        assert_eq!(host.params, vec![Type::F64, Type::I64]);
+        assert_eq!(host.ret, Type::F64);
+
+        // Called the way generated code calls it
+        let trampoline: extern "C" fn(*const u8, f64, i64) -> f64 = unsafe { std::mem::transmute(host.trampoline) };
+        assert_eq!(trampoline(host.env() as *const u8, 2.5, 4), 20.0);
+    }
+
+    #[test]
+    fn host_functions_are_called_with_their_types() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session.register("scale", |x: f64, by: i64| x * by as f64).unwrap();
+        session.register("either", |a: bool, b: bool| a || b).unwrap();
+
+        assert!(matches!(
+            session.register("scale", |x: f64| x),
+            Err(SessionError::Backend(BackendError::HostFunctionRegistered(_)))
+        ));
+
+        session.load("extern scale(x by: i64); extern either(a: bool b: bool) -> bool").unwrap();
+
+        assert_eq!(session.eval("scale(1.5, 4)").unwrap(), 6.0);
+        assert_eq!(session.eval("if either(1 > 2, 2 > 1) then 1 else 0").unwrap(), 1.0);
+        assert_eq!(session.eval("if either(1 > 2, 2 > 3) then 1 else 0").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn panics_in_host_functions_stop_the_expression() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session
+            .register("checked", |x: f64| {
+                assert!(x >= 0.0, "negative");
+                x
+            })
+            .unwrap();
+        session.define("extern checked(x)").unwrap();
+
+        assert!(matches!(
+            session.eval("checked(0 - 1)"),
+            Err(SessionError::Backend(BackendError::Crashed("a panic in a Rust function")))
+        ));
+        assert_eq!(session.eval("checked(2) + 1").unwrap(), 3.0);
+    }
+}
@@ -1,31 +1,50 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::iter;
 use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
+use inkwell::attributes::{Attribute, AttributeLoc};
 use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::execution_engine::JitFunction;
+use inkwell::execution_engine::{ExecutionEngine, JitFunction, UnsafeFunctionPointer};
 use inkwell::module::{Linkage, Module};
+use inkwell::intrinsics::Intrinsic;
 use inkwell::passes::PassBuilderOptions;
+use inkwell::support::load_library_permanently;
 use inkwell::targets::{CodeModel, FileType, RelocMode, Target, TargetMachine, TargetTriple};
-use inkwell::types::BasicMetadataTypeEnum;
+use inkwell::types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum, FunctionType, StructType};
 use inkwell::values::{
-    AnyValue, AnyValueEnum, BasicMetadataValueEnum, BasicValue, FunctionValue, PointerValue,
+    AnyValue, AnyValueEnum, BasicMetadataValueEnum, BasicValue, BasicValueEnum, FunctionValue,
+    FloatValue, GlobalValue, IntValue, PointerValue, StructValue,
 };
-use inkwell::FloatPredicate;
-use inkwell::OptimizationLevel;
+use inkwell::{AddressSpace, AtomicOrdering, AtomicRMWBinOp, OptimizationLevel};
+use inkwell::{FloatPredicate, IntPredicate};
 use thiserror::Error;
 
-use crate::cli::Cli;
+use crate::backend::host::HostFunction;
+use crate::runtime::{self, Stopped};
 use crate::frontend::{
-    ast::{ASTExpr, Function, Prototype},
-    lexer::Ops,
+    ast::{ASTExpr, FnSig, Function, Global, Prototype, Type},
+    lexer::{unescape, Ops},
+    prelude::prelude,
+    scope::ScopeStack,
 };
 
 type IRGenResult<'ir, 'src> = Result<AnyValueEnum<'ir>, BackendError<'src>>;
 type TopLevelSignature = unsafe extern "C" fn() -> f64;
 
+// Variables live on the stack, we keep the pointer to their slot alongside the
+// type stored there, needed to load from the (opaque) pointer
+type Variable<'ctx> = (PointerValue<'ctx>, BasicTypeEnum<'ctx>);
+
+// Where continue and break jump to inside of a loop, in that order
+type LoopTargets<'ctx> = (BasicBlock<'ctx>, BasicBlock<'ctx>);
+
 macro_rules! to_llvm_float {
     ($context:expr, $int_val:expr) => {
         $context
@@ -62,6 +81,107 @@ pub enum BackendError<'src> {
 
     #[error("Incorrect assignment of variable, left side must be a string name")]
     BadAssignment,
+
+    #[error("'{0}' used outside of a loop")]
+    OutsideOfLoop(&'static str),
+
+    #[error("Global {0} defined twice, or shares its name with a function")]
+    MultipleGlobalDefs(&'src str),
+
+    #[error("Global {0} must be initialized with a literal")]
+    NonConstantGlobal(&'src str),
+
+    #[error("Called a value that is not a function")]
+    NotAFunction,
+
+    #[error("Extern {0} doesn't match the signature of the host function registered as {0}")]
+    HostSignatureMismatch(String),
+
+    #[error("A host function is already registered as {0}")]
+    HostFunctionRegistered(String),
+
+    #[error("Failed to load shared library {0}")]
+    FailedToLoadLibrary(String),
+
+    #[error("{0} was declared before with a different signature")]
+    ConflictingDeclaration(String),
+
+    #[error("Ran out of fuel, after {0} function calls and loop iterations")]
+    OutOfFuel(u64),
+
+    #[error("Timed out after {}ms", .0.as_millis())]
+    TimedOut(Duration),
+
+    #[error("Stopped by {0}")]
+    Crashed(&'static str),
+
+    #[error("Invalid passes: {0}")]
+    InvalidPasses(String),
+}
+
+// Passes run unless told otherwise, LLVM's own pipeline for -O2, the default optimization level
+pub const DEFAULT_PASSES: &str = "default<O2>";
+
+// LLVM parses the whole pipeline before it runs any of it, so running the passes over an
+// empty module finds a pass name it doesn't know without compiling anything. The target
+// machine is the one code is generated for, some passes only exist for some targets.
+pub fn check_passes(passes: &str, options: &CodegenOptions) -> Result<(), BackendError<'static>> {
+    if passes.is_empty() {
+        return Ok(());
+    }
+
+    let context = Context::create();
+    let module = context.create_module("passcheck");
+
+    module
+        .run_passes(passes, &target_machine(options), PassBuilderOptions::create())
+        .map_err(|err| BackendError::InvalidPasses(err.to_string()))
+}
+
+// The machine code is generated for, the host unless options say otherwise
+fn target_machine(options: &CodegenOptions) -> TargetMachine {
+    let triple = match options.target.as_ref() {
+        None => TargetMachine::get_default_triple(),
+        Some(target_str) => TargetTriple::create(target_str.as_str()),
+    };
+
+    let target = Target::from_triple(&triple)
+        .expect("Unknown target: please specify a target ");
+
+    target
+        .create_target_machine(
+            &triple,
+            "generic",
+            "",
+            options.opt_level,
+            RelocMode::Default,
+            CodeModel::Default,
+        )
+        .unwrap()
+}
+
+// How code is generated, the command line fills these in from its flags
+#[derive(Debug, Clone)]
+pub struct CodegenOptions {
+    pub target: Option<String>, // A target triple, or the host when None
+    pub opt_level: OptimizationLevel,
+    pub jit: bool, // Code is JIT compiled and run, rather than compiled to a file
+    pub bounds_checks: bool,
+    pub fuel: Option<u64>, // How many function calls and loop iterations JIT code may make
+    pub timeout: Option<Duration>, // How long JIT code may run for
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        Self {
+            target: None,
+            opt_level: OptimizationLevel::Default,
+            jit: true,
+            bounds_checks: false,
+            fuel: None,
+            timeout: None,
+        }
+    }
 }
 
 // Our context object that we will pass to recursive calls of codegen
@@ -72,40 +192,189 @@ pub struct LLVMContext<'ctx> {
     builder: Builder<'ctx>,
     module: Module<'ctx>,
     machine: TargetMachine,
-    sym_table: RefCell<HashMap<String, PointerValue<'ctx>>>,
+    sym_table: RefCell<ScopeStack<Variable<'ctx>>>,
+    globals: RefCell<HashMap<String, Variable<'ctx>>>,
+    global_slots: RefCell<HashMap<String, Box<Cell<u64>>>>,
+    jit: bool,
+    loops: RefCell<Vec<LoopTargets<'ctx>>>,
+    bounds_checks: bool,
+    host_functions: RefCell<HashMap<String, HostFunction>>,
+    string_literals: RefCell<Vec<String>>,
+    fuel_limit: Option<u64>,
+    timeout: Option<Duration>,
+    fuel: Arc<AtomicI64>,
+    jit_copy: RefCell<Option<(Module<'ctx>, ExecutionEngine<'ctx>)>>,
 }
 
+// The global every fuel check takes from, see build_fuel_check
+const FUEL_GLOBAL: &str = "kaleidrs.fuel";
+
+// What the timeout leaves in it, matched by kaleidrs_out_of_fuel in io.c
+const TIMED_OUT_FUEL: i64 = i64::MIN / 2;
+
 impl<'ctx> LLVMContext<'ctx> {
-    pub fn new(context: &'ctx Context, cli_args: &Cli) -> Self {
+    pub fn new(context: &'ctx Context, options: &CodegenOptions) -> Self {
         let builder = context.create_builder();
         let module = context.create_module("kaleidrs_module");
 
-        let triple = match cli_args.target.as_ref() {
-            None => TargetMachine::get_default_triple(),
-            Some(target_str) => TargetTriple::create(target_str.as_str()),
-        };
-
-        let target = Target::from_triple(&triple)
-            .expect("Unknown target: please specify a target ");
-
-        let machine = target
-            .create_target_machine(
-                &triple,
-                "generic",
-                "",
-                cli_args.opt_level.into(),
-                RelocMode::Default,
-                CodeModel::Default,
-            )
-            .unwrap();
+        let machine = target_machine(options);
 
-        Self {
+        let llvm_ctx = Self {
             context,
             builder,
             module,
             machine,
-            sym_table: RefCell::new(HashMap::new()),
+            sym_table: RefCell::new(ScopeStack::new()),
+            globals: RefCell::new(HashMap::new()),
+            global_slots: RefCell::new(HashMap::new()),
+            jit: options.jit,
+            loops: RefCell::new(vec![]),
+            bounds_checks: options.bounds_checks,
+            host_functions: RefCell::new(HashMap::new()),
+            string_literals: RefCell::new(vec![]),
+            fuel_limit: options.fuel.filter(|_| options.jit),
+            timeout: options.timeout.filter(|_| options.jit),
+            fuel: Arc::new(AtomicI64::new(i64::MAX)),
+            jit_copy: RefCell::new(None),
+        };
+
+        for proto in prelude() {
+            proto.codegen(&llvm_ctx).expect("The prelude always generates");
+        }
+
+        // Compiled ahead of time, the prelude comes from the math library, see link_library
+        if !llvm_ctx.jit && cfg!(unix) {
+            llvm_ctx.link_library(Path::new("m")).expect("Failed to ask for the math library");
+        }
+
+        llvm_ctx
+    }
+
+    // Makes the functions of a shared library available to externs. When JIT compiling,
+    // the library is loaded into the process, where the JIT looks up symbols. Otherwise
+    // nothing is linked here, the object file only asks for it to be linked against,
+    // LLVM writes that into a .deplibs section on ELF, which lld reads, other linkers
+    // need to be passed it themselves.
+    pub fn link_library(&self, path: &Path) -> Result<(), BackendError<'static>> {
+        let name = path.display().to_string();
+
+        if self.jit {
+            return load_library_permanently(path).map_err(|_| BackendError::FailedToLoadLibrary(name));
+        }
+
+        let library = self.context.metadata_node(&[self.context.metadata_string(&name).into()]);
+
+        self.module
+            .add_global_metadata("llvm.dependent-libraries", &library)
+            .map_err(|_| BackendError::FailedToLoadLibrary(name))
+    }
+
+    // Externs named after a function of the host call that function, see host.rs.
+    // Only affects externs generated after, like any other definition. The code of those
+    // calls the closure for as long as it lives, so it can't be replaced by another.
+    pub fn register_host_function(&self, name: &str, function: HostFunction) -> Result<(), BackendError<'static>> {
+        let mut host_functions = self.host_functions.borrow_mut();
+
+        if host_functions.contains_key(name) {
+            return Err(BackendError::HostFunctionRegistered(name.to_string()));
         }
+
+        host_functions.insert(name.to_string(), function);
+        Ok(())
+    }
+
+    // The body of an extern naming a host function, passes its arguments on to the
+    // host function's trampoline, along with the closure to call. Generated code only
+    // ever runs in this process, so the addresses can be baked in as constants.
+    fn build_host_function<'src>(
+        &self,
+        proto: &Prototype<'src>,
+        host: &HostFunction,
+    ) -> IRGenResult<'ctx, 'src> {
+        let name = proto.get_name();
+
+        if host.params != proto.get_param_types() || host.ret != proto.get_return_type() {
+            return Err(BackendError::HostSignatureMismatch(name));
+        }
+
+        let sig = FnSig {
+            params: host.params.clone(),
+            ret: host.ret,
+        };
+
+        let param_types = sig
+            .params
+            .iter()
+            .map(|ty| BasicMetadataTypeEnum::from(self.llvm_type(*ty)))
+            .collect::<Vec<_>>();
+
+        let fn_type = self.llvm_type(sig.ret).fn_type(param_types.as_slice(), false);
+
+        // Declaring the same extern twice is fine, one declared without a body (in the
+        // prelude, say) gets the host function as its body
+        let fn_val = match self.module.get_function(&name) {
+            Some(existing) if existing.get_first_basic_block().is_some() => {
+                return Ok(existing.as_any_value_enum());
+            }
+
+            Some(declared) if declared.get_type() != fn_type => {
+                return Err(BackendError::ConflictingDeclaration(name));
+            }
+
+            Some(declared) => declared,
+
+            None => self.module.add_function(&name, fn_type, Some(Linkage::External)),
+        };
+
+        for (param, param_name) in fn_val.get_params().iter().zip(proto.get_param_names()) {
+            param.set_name(param_name);
+        }
+
+        // Builds off to the side, externs can come in the middle of generating other code
+        let builder = self.context.create_builder();
+        builder.position_at_end(self.context.append_basic_block(fn_val, "entry"));
+
+        let ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let address = |addr: usize, name: &str| {
+            builder
+                .build_int_to_ptr(self.context.i64_type().const_int(addr as u64, false), ptr_type, name)
+                .expect("FATAL: LLVM failed to build int to pointer cast")
+        };
+
+        let trampoline = address(host.trampoline, "trampoline");
+        let mut call_args: Vec<BasicMetadataValueEnum> = vec![address(host.env(), "closure").into()];
+        call_args.extend(fn_val.get_params().into_iter().map(BasicMetadataValueEnum::from));
+
+        let call = builder
+            .build_indirect_call(self.closure_fn_type(&sig), trampoline, call_args.as_slice(), "hostcall")
+            .expect("Irrecoverable: LLVM failed to build call expression");
+
+        // Rust expects a bool to come as a whole byte (or more) that is 0 or 1, an i1 only
+        // promises the lowest bit, the rest has to be zeroed like C's _Bool is
+        let zeroext = self
+            .context
+            .create_enum_attribute(Attribute::get_named_enum_kind_id("zeroext"), 0);
+
+        for (idx, param) in sig.params.iter().enumerate() {
+            if *param == Type::Bool {
+                call.add_attribute(AttributeLoc::Param(idx as u32 + 1), zeroext);
+            }
+        }
+
+        if sig.ret == Type::Bool {
+            call.add_attribute(AttributeLoc::Return, zeroext);
+        }
+
+        let result = call
+            .try_as_basic_value()
+            .left()
+            .expect("Kaleidoscope functions always return a value");
+
+        builder
+            .build_return(Some(&result))
+            .expect("FATAL: LLVM failed to build a return!");
+
+        Ok(fn_val.as_any_value_enum())
     }
 
     // This method will just print the contents of the module,
@@ -133,26 +402,173 @@ impl<'ctx> LLVMContext<'ctx> {
 
     // Small helper method to remove the top level anonymous expression,
     // needed for REPL so that we don't define top level twice, just delete
-    // it and then define it again.
+    // it and then define it again. Whatever was generated along with it, its
+    // lambdas and the wrappers of functions it used as values, goes too.
     pub fn delete_top_level_expr(&self) {
-        self.module
-            .get_function("__anonymous_expr")
-            .map(|f| unsafe { f.delete() });
+        if let Some(top_level) = self.module.get_function("__anonymous_expr") {
+            self.delete_functions_after(top_level.get_previous_function(), 0);
+        }
     }
 
-    // Optimization passes
-    pub fn run_passes(&self, passes: &str) {
-        if !passes.is_empty() {
-            let pass_options = PassBuilderOptions::create();
+    // Functions are always added to the end of the module, so everything generated since
+    // `last` came after it. Those call each other in any direction (a lambda is added
+    // after the function it's written in), and LLVM doesn't want a function deleted while
+    // something still calls it. So every use of them is pointed at null first, then they
+    // can go in any order. The string literals written in them go the same way, from
+    // `literals` on in string_literals.
+    fn delete_functions_after(&self, last: Option<FunctionValue<'ctx>>, literals: usize) {
+        let mut functions = vec![];
+        let mut next = match last {
+            Some(last) => last.get_next_function(),
+            None => self.module.get_first_function(),
+        };
 
-            // Default passes
-            pass_options.set_verify_each(true);
-            pass_options.set_debug_logging(false);
+        while let Some(function) = next {
+            next = function.get_next_function();
+            functions.push(function);
+        }
 
-            self.module
-                .run_passes(passes, &self.machine, pass_options)
-                .unwrap();
+        for function in &functions {
+            let pointer = function.as_global_value().as_pointer_value();
+            pointer.replace_all_uses_with(pointer.get_type().const_null());
+        }
+
+        // The passes may have deleted some of them already, so they're looked up again
+        let literals: Vec<GlobalValue<'ctx>> = self
+            .string_literals
+            .borrow_mut()
+            .drain(literals..)
+            .filter_map(|name| self.module.get_global(&name))
+            .collect();
+
+        for global in &literals {
+            let pointer = global.as_pointer_value();
+            pointer.replace_all_uses_with(pointer.get_type().const_null());
+        }
+
+        for function in functions {
+            unsafe { function.delete() };
+        }
+
+        for global in literals {
+            unsafe { global.delete() };
+        }
+    }
+
+    fn build_function_body<'src>(
+        &self,
+        func: &Function<'src>,
+        fn_val: FunctionValue<'ctx>,
+    ) -> Result<(), BackendError<'src>> {
+        // This sets our cursor for creating instructions to the basic block
+        // for this function
+        let bb_entry = self.context.append_basic_block(fn_val, "entry");
+        self.builder.position_at_end(bb_entry);
+        self.build_fuel_check();
+
+        // Update the symbol table with the args names and references
+        // to their LLVM values.
+        self.sym_table.borrow_mut().clear();
+        for (param, name) in fn_val.get_params().into_iter().zip(func.proto.get_param_names()) {
+            // The mutable variables chapter, chapter 7, our passed arguments may be mutated.
+            // Store them all on the stack and allow the function inside to mutate them
+            // as memory objects
+
+            // Allocate the argument to stack.
+            let param_ptr = self.create_entry_block_alloca(fn_val, name, param.get_type());
+
+            // Store the value of this paramter to it's stack copy
+            self.builder
+                .build_store(param_ptr, param)
+                .expect("FATAL: LLVM failed to build store instruction");
+
+            // Add it to scope
+            self.sym_table
+                .borrow_mut()
+                .define(name, (param_ptr, param.get_type()));
+        }
+
+        // Generate code for the body of the function as an ASTExpr node
+        let ir_body = codegen_value(&func.body, self)?;
+
+        // Convert the body to the declared return type, booleans returned as doubles,
+        // or any value returned from a top level expression, which always returns double
+        let ret_type = fn_val.get_type().get_return_type().unwrap();
+        let ret_val = self.coerce(ir_body, ret_type);
+
+        // We need to add a return at the end so we return from functions we call
+        self.builder
+            .build_return(Some(&ret_val as &dyn BasicValue))
+            .expect("FATAL: LLVM failed to build a return!");
+
+        if !fn_val.verify(true) {
+            return Err(BackendError::FailedToVerifyFunc(func.proto.get_name()));
+        }
+
+        Ok(())
+    }
+
+    // Optimization passes. The REPL runs them over the whole module after every line, so
+    // the more there is defined, the longer each line takes to compile, everything is
+    // optimized again (and can be inlined into what's new).
+    pub fn run_passes(&self, passes: &str) -> Result<(), BackendError<'static>> {
+        if passes.is_empty() {
+            return Ok(());
+        }
+
+        let pass_options = PassBuilderOptions::create();
+
+        // Default passes
+        pass_options.set_verify_each(true);
+        pass_options.set_debug_logging(false);
+
+        if self.jit {
+            self.keep_declarations();
+        }
+
+        let res = self
+            .module
+            .run_passes(passes, &self.machine, pass_options)
+            .map_err(|err| BackendError::InvalidPasses(err.to_string()));
+
+        // The passes may have made a new one, so it's looked up again
+        if let Some(kept) = self.module.get_global("llvm.compiler.used") {
+            unsafe { kept.delete() };
+        }
+
+        res
+    }
+
+    // LLVM's own pipelines delete declarations nothing uses, the externs and globals code
+    // generated later on is going to use. Anything in llvm.compiler.used is left alone,
+    // so every declaration goes in there while the passes run.
+    fn keep_declarations(&self) {
+        let functions = self
+            .module
+            .get_functions()
+            .filter(|function| function.get_first_basic_block().is_none())
+            .filter(|function| !function.get_name().to_bytes().starts_with(b"llvm."))
+            .map(|function| function.as_global_value().as_pointer_value());
+
+        let globals = self
+            .module
+            .get_globals()
+            .filter(|global| global.get_initializer().is_none())
+            .map(|global| global.as_pointer_value());
+
+        let declarations = functions.chain(globals).collect::<Vec<_>>();
+
+        if declarations.is_empty() {
+            return;
         }
+
+        let ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+        let array = ptr_type.const_array(&declarations);
+
+        let used = self.module.add_global(array.get_type(), None, "llvm.compiler.used");
+        used.set_linkage(Linkage::Appending);
+        used.set_section(Some("llvm.metadata"));
+        used.set_initializer(&array);
     }
 
     pub fn compile(&self, path: &Path, file_type: FileType) -> () {
@@ -163,37 +579,888 @@ impl<'ctx> LLVMContext<'ctx> {
 
     // JIT evalution, creates an ExecutionEngine object, JIT compiles the function,
     // then attempts to call the function, will return the resulting floating point val.
-    pub unsafe fn jit_eval(&self) -> Result<f64, BackendError> {
+    //
+    // Stack overflows, aborts and other crashes are caught on unix, and come back as errors.
+    // Unsafe because it runs whatever code was generated, which is only as safe as the
+    // program it came from.
+    pub unsafe fn jit_eval(&self) -> Result<f64, BackendError<'static>> {
         let exec_engine = self
             .module
             .create_jit_execution_engine(OptimizationLevel::None)
             .expect("FATAL: Failed to create JIT execution engine!");
 
+        // Every global is a declaration in the module, point them at their slots
+        for (name, slot) in self.global_slots.borrow().iter() {
+            if let Some(global) = self.module.get_global(name) {
+                exec_engine.add_global_mapping(&global, slot.as_ptr() as usize);
+            }
+        }
+
+        if let Some(fuel) = self.module.get_global(FUEL_GLOBAL) {
+            exec_engine.add_global_mapping(&fuel, self.fuel.as_ptr() as usize);
+        }
+
         let jitted_fn: JitFunction<'ctx, TopLevelSignature> = exec_engine
             .get_function("__anonymous_expr")
             .expect("FATAL: symbol '__anonymous_expr' not present in module!");
 
-        let res = jitted_fn.call();
+        let fuel = self.fuel_limit.map_or(i64::MAX, |limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        self.fuel.store(fuel, Ordering::SeqCst);
+
+        // Running out of time is running out of fuel, a thread takes what's left once the
+        // time is up, leaving far less than none, see kaleidrs_out_of_fuel. Dropping the
+        // sender wakes it up early when the code is done first.
+        let watchdog = self.timeout.map(|timeout| {
+            let (done, wait) = mpsc::channel::<()>();
+            let fuel = self.fuel.clone();
+
+            let thread = thread::spawn(move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = wait.recv_timeout(timeout) {
+                    fuel.store(TIMED_OUT_FUEL, Ordering::SeqCst);
+                }
+            });
+
+            (done, thread)
+        });
+
+        // A crash is caught, rather than taking the process and the session in it down,
+        // and so is the fuel check that found none left
+        let res = runtime::guarded_call(jitted_fn.as_raw());
+
+        if let Some((done, thread)) = watchdog {
+            drop(done);
+            thread.join().expect("The timeout thread doesn't panic");
+        }
+
+        // Output of the expression goes before anything printed about it
+        runtime::flush_output();
+
+        // Whatever is called from Rust later on (see jit_function) runs without a limit
+        self.fuel.store(i64::MAX, Ordering::SeqCst);
 
         exec_engine.remove_module(&self.module).unwrap();
 
-        Ok(res)
+        res.map_err(|stopped| match stopped {
+            Stopped::Crashed(what) => BackendError::Crashed(what),
+            Stopped::OutOfFuel => BackendError::OutOfFuel(self.fuel_limit.unwrap_or(u64::MAX)),
+            Stopped::TimedOut => BackendError::TimedOut(self.timeout.unwrap_or_default()),
+        })
+    }
+
+    // Looks up a function to call from Rust, compiled along with the rest of the module.
+    // An execution engine takes the module it compiles, and jit_eval wants this one back,
+    // so a copy is compiled instead. That's the whole module, so the copy is kept for the
+    // next lookup, and only compiled again for a function defined after it was made.
+    // The code lives as long as the returned function. Unsafe because F has to match the
+    // signature the function was generated with, doubles for doubles, i64 for i64 and so
+    // on, see llvm_type.
+    pub unsafe fn jit_function<'a, F: UnsafeFunctionPointer>(
+        &self,
+        name: &'a str,
+    ) -> Result<JitFunction<'ctx, F>, BackendError<'a>> {
+        match self.module.get_function(name) {
+            Some(function) if function.get_first_basic_block().is_some() => {}
+            _ => return Err(BackendError::UndefinedFunction(name)),
+        }
+
+        let mut jit_copy = self.jit_copy.borrow_mut();
+
+        let up_to_date = matches!(
+            &*jit_copy,
+            Some((module, _)) if module.get_function(name).is_some_and(|f| f.get_first_basic_block().is_some())
+        );
+
+        // Functions already defined never change, a copy that has this one can be reused
+        if !up_to_date {
+            let module = self.module.clone();
+
+            let exec_engine = module
+                .create_jit_execution_engine(OptimizationLevel::None)
+                .expect("FATAL: Failed to create JIT execution engine!");
+
+            for (global_name, slot) in self.global_slots.borrow().iter() {
+                if let Some(global) = module.get_global(global_name) {
+                    exec_engine.add_global_mapping(&global, slot.as_ptr() as usize);
+                }
+            }
+
+            if let Some(fuel) = module.get_global(FUEL_GLOBAL) {
+                exec_engine.add_global_mapping(&fuel, self.fuel.as_ptr() as usize);
+            }
+
+            // Functions looked up in the copy before keep it alive for as long as they need it
+            *jit_copy = Some((module, exec_engine));
+        }
+
+        let (_, exec_engine) = jit_copy.as_ref().expect("The copy was just made");
+
+        exec_engine
+            .get_function(name)
+            .map_err(|_| BackendError::UndefinedFunction(name))
     }
 
+    // The math functions of the prelude that are single instructions on most machines,
+    // LLVM knows what the intrinsics do, but not what a call to the C function does.
+    // Only when declared as in the prelude, a definition of the same name is left alone.
+    fn intrinsic_for(&self, function: FunctionValue<'ctx>) -> Option<FunctionValue<'ctx>> {
+        let f64_type = self.context.f64_type();
+
+        if function.get_first_basic_block().is_some()
+            || function.get_type() != f64_type.fn_type(&[f64_type.into()], false)
+        {
+            return None;
+        }
+
+        let intrinsic = match function.get_name().to_str().ok()? {
+            "sqrt" => "llvm.sqrt",
+            "fabs" => "llvm.fabs",
+            "floor" => "llvm.floor",
+            "ceil" => "llvm.ceil",
+            _ => return None,
+        };
+
+        Intrinsic::find(intrinsic)?.get_declaration(&self.module, &[f64_type.into()])
+    }
+
+    // Local variables shadow globals of the same name
+    fn lookup_variable(&self, name: &str) -> Option<Variable<'ctx>> {
+        let local = self.sym_table.borrow().lookup(name).copied();
+
+        local.or_else(|| self.globals.borrow().get(name).copied())
+    }
+
+    // Allocas always go at the very top of the entry block, that way mem2reg
+    // can promote them, even if the entry block has already been terminated.
     fn create_entry_block_alloca(
         &self,
         function: FunctionValue<'ctx>,
         var_name: &str,
+        var_type: BasicTypeEnum<'ctx>,
     ) -> PointerValue<'ctx> {
         let ir_builder = self.context.create_builder();
-        ir_builder.position_at_end(function.get_first_basic_block().unwrap());
+        let entry_bb = function.get_first_basic_block().unwrap();
+
+        match entry_bb.get_first_instruction() {
+            Some(first_insn) => ir_builder.position_before(&first_insn),
+            None => ir_builder.position_at_end(entry_bb),
+        }
 
         let alloca_insn = ir_builder
-            .build_alloca(self.context.f64_type(), var_name)
+            .build_alloca(var_type, var_name)
             .expect("FATAL: LLVM failed to build alloca instruction");
 
         alloca_insn
     }
+
+    // Map a Kaleidoscope type onto the LLVM type representing it
+    fn llvm_type(&self, ty: Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::F64 => self.context.f64_type().as_basic_type_enum(),
+            Type::I64 => self.context.i64_type().as_basic_type_enum(),
+            Type::Bool => self.context.bool_type().as_basic_type_enum(),
+
+            // Strings are passed around by value as { ptr, i64 }, the pointer to the
+            // characters (not null terminated) and the number of bytes
+            Type::Str => self
+                .context
+                .struct_type(
+                    &[
+                        self.context
+                            .i8_type()
+                            .ptr_type(AddressSpace::default())
+                            .as_basic_type_enum(),
+                        self.context.i64_type().as_basic_type_enum(),
+                    ],
+                    false,
+                )
+                .as_basic_type_enum(),
+
+            // Arrays are pointers to a block allocated by the runtime, see array_header_type
+            Type::Array => self
+                .context
+                .i8_type()
+                .ptr_type(AddressSpace::default())
+                .as_basic_type_enum(),
+
+            Type::Fn(_) => self.closure_type().as_basic_type_enum(),
+        }
+    }
+
+    // The type of a top level function, with the parameters and return type its prototype
+    // gives, doubles unless annotated otherwise
+    fn prototype_fn_type(&self, proto: &Prototype) -> FunctionType<'ctx> {
+        let param_types = proto
+            .get_param_types()
+            .into_iter()
+            .map(|ty| BasicMetadataTypeEnum::from(self.llvm_type(ty)))
+            .collect::<Vec<_>>();
+
+        self.llvm_type(proto.get_return_type())
+            .fn_type(param_types.as_slice(), false)
+    }
+
+    // Function values are closures, a pair of the code to call and the environment
+    // it was created in, { ptr, ptr }. The code takes the environment as an extra
+    // first parameter, which is null for closures that capture nothing.
+    fn closure_type(&self) -> StructType<'ctx> {
+        let ptr_type = self.context.i8_type().ptr_type(AddressSpace::default());
+
+        self.context
+            .struct_type(&[ptr_type.into(), ptr_type.into()], false)
+    }
+
+    // The type of the code of a closure with the given signature
+    fn closure_fn_type(&self, sig: &FnSig) -> FunctionType<'ctx> {
+        let mut param_types: Vec<BasicMetadataTypeEnum> = vec![self
+            .context
+            .i8_type()
+            .ptr_type(AddressSpace::default())
+            .into()];
+
+        param_types.extend(sig.params.iter().map(|ty| BasicMetadataTypeEnum::from(self.llvm_type(*ty))));
+
+        self.llvm_type(sig.ret).fn_type(param_types.as_slice(), false)
+    }
+
+    fn build_closure(&self, code: FunctionValue<'ctx>, env: PointerValue<'ctx>) -> StructValue<'ctx> {
+        let with_code = self
+            .builder
+            .build_insert_value(
+                self.closure_type().get_undef(),
+                code.as_global_value().as_pointer_value(),
+                0,
+                &"closure",
+            )
+            .expect("FATAL: LLVM failed to build insert value!");
+
+        self.builder
+            .build_insert_value(with_code, env, 1, &"closure")
+            .expect("FATAL: LLVM failed to build insert value!")
+            .into_struct_value()
+    }
+
+    // Named functions have no environment parameter, so using one as a value wraps it
+    // in a function that takes one and ignores it. The wrapper is only generated once.
+    fn get_closure_wrapper(&self, function: FunctionValue<'ctx>) -> FunctionValue<'ctx> {
+        let wrapper_name = format!("{}.closure", function.get_name().to_str().unwrap());
+
+        if let Some(wrapper) = self.module.get_function(&wrapper_name) {
+            return wrapper;
+        }
+
+        let fn_type = function.get_type();
+
+        let mut param_types: Vec<BasicMetadataTypeEnum> = vec![self
+            .context
+            .i8_type()
+            .ptr_type(AddressSpace::default())
+            .into()];
+
+        param_types.extend(fn_type.get_param_types().into_iter().map(BasicMetadataTypeEnum::from));
+
+        let wrapper_type = fn_type
+            .get_return_type()
+            .expect("Kaleidoscope functions always return a value")
+            .fn_type(param_types.as_slice(), false);
+
+        let wrapper = self
+            .module
+            .add_function(&wrapper_name, wrapper_type, Some(Linkage::Private));
+
+        // We are likely in the middle of generating another function, come back to it after
+        let resume_bb = self.builder.get_insert_block();
+
+        let entry_bb = self.context.append_basic_block(wrapper, &"entry");
+        self.builder.position_at_end(entry_bb);
+
+        let args = wrapper
+            .get_params()
+            .into_iter()
+            .skip(1)
+            .map(BasicMetadataValueEnum::from)
+            .collect::<Vec<_>>();
+
+        let call = self
+            .builder
+            .build_call(function, args.as_slice(), &"calltmp")
+            .expect("FATAL: LLVM failed to build call!");
+
+        self.builder
+            .build_return(Some(&call.try_as_basic_value().left().unwrap()))
+            .expect("FATAL: LLVM failed to build a return!");
+
+        if let Some(resume_bb) = resume_bb {
+            self.builder.position_at_end(resume_bb);
+        }
+
+        wrapper
+    }
+
+    // Generates the code of a lambda as a separate (private) function. The state of the
+    // function we were in the middle of generating is put aside while we do so, and
+    // restored whether or not the lambda was generated successfully.
+    fn build_lambda<'src>(
+        &self,
+        sig: &FnSig,
+        params: &[(&'src str, Option<Type>)],
+        captures: &[&'src str],
+        self_name: Option<&'src str>,
+        env_type: StructType<'ctx>,
+        body: &ASTExpr<'src>,
+    ) -> Result<FunctionValue<'ctx>, BackendError<'src>> {
+        // Local functions are named after themselves, with a suffix no top level function can have
+        let lambda_name = self_name.map_or("lambda".to_string(), |name| format!("{name}.local"));
+
+        let lambda = self
+            .module
+            .add_function(&lambda_name, self.closure_fn_type(sig), Some(Linkage::Private));
+
+        let resume_bb = self.builder.get_insert_block();
+        let outer_vars = self.sym_table.replace(ScopeStack::new());
+        let outer_loops = self.loops.replace(vec![]);
+
+        let lambda_res = self.build_lambda_body(lambda, params, captures, self_name, env_type, body);
+
+        self.sym_table.replace(outer_vars);
+        self.loops.replace(outer_loops);
+
+        if let Some(resume_bb) = resume_bb {
+            self.builder.position_at_end(resume_bb);
+        }
+
+        match lambda_res {
+            Ok(()) => Ok(lambda),
+            Err(err) => {
+                unsafe { lambda.delete() };
+                Err(err)
+            }
+        }
+    }
+
+    fn build_lambda_body<'src>(
+        &self,
+        lambda: FunctionValue<'ctx>,
+        params: &[(&'src str, Option<Type>)],
+        captures: &[&'src str],
+        self_name: Option<&'src str>,
+        env_type: StructType<'ctx>,
+        body: &ASTExpr<'src>,
+    ) -> Result<(), BackendError<'src>> {
+        let entry_bb = self.context.append_basic_block(lambda, &"entry");
+        self.builder.position_at_end(entry_bb);
+        self.build_fuel_check();
+
+        // Captured variables become local variables of the lambda, copied out of the
+        // environment, just like parameters are copied to the stack
+        let env_ptr = lambda.get_first_param().unwrap().into_pointer_value();
+
+        for (idx, name) in captures.iter().enumerate() {
+            let field_type = env_type.get_field_type_at_index(idx as u32).unwrap();
+
+            let field_ptr = self
+                .builder
+                .build_struct_gep(env_type, env_ptr, idx as u32, name)
+                .expect("FATAL: LLVM failed to build GEP!");
+
+            let captured_val = self
+                .builder
+                .build_load(field_type, field_ptr, name)
+                .expect("FATAL: LLVM failed to build load instruction");
+
+            let var_ptr = self.create_entry_block_alloca(lambda, name, field_type);
+
+            self.builder
+                .build_store(var_ptr, captured_val)
+                .expect("FATAL: LLVM failed to build store instruction");
+
+            self.sym_table.borrow_mut().define(name, (var_ptr, field_type));
+        }
+
+        // A local function refers to itself as a closure over the environment it was
+        // called with, which is the one it was created with
+        if let Some(name) = self_name {
+            let self_closure = self.build_closure(lambda, env_ptr);
+            let self_ptr = self.create_entry_block_alloca(lambda, name, self.closure_type().into());
+
+            self.builder
+                .build_store(self_ptr, self_closure)
+                .expect("FATAL: LLVM failed to build store instruction");
+
+            self.sym_table
+                .borrow_mut()
+                .define(name, (self_ptr, self.closure_type().into()));
+        }
+
+        // Parameters go in a scope of their own, they shadow captures and the function itself
+        self.sym_table.borrow_mut().push_scope();
+
+        for (param, (name, _)) in lambda.get_params().into_iter().skip(1).zip(params) {
+            param.set_name(name);
+
+            let param_ptr = self.create_entry_block_alloca(lambda, name, param.get_type());
+
+            self.builder
+                .build_store(param_ptr, param)
+                .expect("FATAL: LLVM failed to build store instruction");
+
+            self.sym_table
+                .borrow_mut()
+                .define(name, (param_ptr, param.get_type()));
+        }
+
+        let body_genval = codegen_value(body, self)?;
+        let ret_type = lambda.get_type().get_return_type().unwrap();
+        let ret_val = self.coerce(body_genval, ret_type);
+
+        self.builder
+            .build_return(Some(&ret_val as &dyn BasicValue))
+            .expect("FATAL: LLVM failed to build a return!");
+
+        Ok(())
+    }
+
+    // The block an array points to, its length followed by the elements themselves,
+    // { i64, [0 x double] }, matching the karray struct in src/clib/io.c
+    fn array_header_type(&self) -> StructType<'ctx> {
+        self.context.struct_type(
+            &[
+                self.context.i64_type().as_basic_type_enum(),
+                self.context.f64_type().array_type(0).as_basic_type_enum(),
+            ],
+            false,
+        )
+    }
+
+    // Asks the runtime for an array of len elements, all set to fill
+    fn build_array_alloc(&self, len: IntValue<'ctx>, fill: FloatValue<'ctx>) -> PointerValue<'ctx> {
+        let array_type = self.llvm_type(Type::Array);
+
+        let alloc_fn = self.get_runtime_function(
+            "karray_new",
+            array_type.fn_type(
+                &[
+                    self.context.i64_type().into(),
+                    self.context.f64_type().into(),
+                ],
+                false,
+            ),
+        );
+
+        self.builder
+            .build_call(alloc_fn, &[len.into(), fill.into()], &"arraytmp")
+            .expect("FATAL: LLVM failed to build call!")
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value()
+    }
+
+    fn build_array_len(&self, array: PointerValue<'ctx>) -> IntValue<'ctx> {
+        let len_ptr = self
+            .builder
+            .build_struct_gep(self.array_header_type(), array, 0, &"lenptr")
+            .expect("FATAL: LLVM failed to build GEP!");
+
+        self.builder
+            .build_load(self.context.i64_type(), len_ptr, &"len")
+            .expect("FATAL: LLVM failed to build load instruction")
+            .into_int_value()
+    }
+
+    // Indices may be doubles in untyped code, they get truncated
+    fn build_index(&self, index: BasicValueEnum<'ctx>) -> IntValue<'ctx> {
+        match index {
+            BasicValueEnum::IntValue(int_val) => int_val,
+
+            float_val => self
+                .builder
+                .build_float_to_signed_int(
+                    float_val.into_float_value(),
+                    self.context.i64_type(),
+                    &"idxtmp",
+                )
+                .expect("FATAL: LLVM failed to convert float to int"),
+        }
+    }
+
+    // Generates the array and index expressions, producing a pointer to the element
+    // they refer to. With bounds checks turned on, an out of bounds index calls into
+    // the runtime, which reports it and aborts the program.
+    fn build_element_ptr<'src>(
+        &self,
+        array: &ASTExpr<'src>,
+        index: &ASTExpr<'src>,
+    ) -> Result<PointerValue<'ctx>, BackendError<'src>> {
+        let array_ptr = codegen_value(array, self)?.into_pointer_value();
+        let index = self.build_index(codegen_value(index, self)?);
+
+        if self.bounds_checks {
+            let i64_type = self.context.i64_type();
+            let len = self.build_array_len(array_ptr);
+
+            // Unsigned comparison, so negative indices are caught as well
+            let in_bounds = self
+                .builder
+                .build_int_compare(IntPredicate::ULT, index, len, &"inbounds")
+                .expect("FATAL: LLVM failed to build int compare!");
+
+            let function = self
+                .builder
+                .get_insert_block()
+                .unwrap()
+                .get_parent()
+                .unwrap();
+
+            let oob_bb = self.context.append_basic_block(function, &"outofbounds");
+            let ok_bb = self.context.append_basic_block(function, &"inbounds");
+
+            self.builder
+                .build_conditional_branch(in_bounds, ok_bb, oob_bb)
+                .expect("FATAL: LLVM failed to build br instruction!");
+
+            self.builder.position_at_end(oob_bb);
+
+            let oob_fn = self.get_runtime_function(
+                "karray_out_of_bounds",
+                self.context
+                    .void_type()
+                    .fn_type(&[i64_type.into(), i64_type.into()], false),
+            );
+
+            self.builder
+                .build_call(oob_fn, &[index.into(), len.into()], &"")
+                .expect("FATAL: LLVM failed to build call!");
+
+            self.builder
+                .build_unreachable()
+                .expect("FATAL: LLVM failed to build unreachable!");
+
+            self.builder.position_at_end(ok_bb);
+        }
+
+        let elements_ptr = self
+            .builder
+            .build_struct_gep(self.array_header_type(), array_ptr, 1, &"elements")
+            .expect("FATAL: LLVM failed to build GEP!");
+
+        let element_ptr = unsafe {
+            self.builder
+                .build_in_bounds_gep(self.context.f64_type(), elements_ptr, &[index], &"elemptr")
+                .expect("FATAL: LLVM failed to build GEP!")
+        };
+
+        Ok(element_ptr)
+    }
+
+    // With a fuel limit or a timeout, every function call and loop iteration takes one
+    // unit of fuel from a global, and runaway code is stopped right where it is once there
+    // is none left. kaleidrs_out_of_fuel in io.c jumps back to jit_eval the way a crash
+    // would, so nothing after the check runs, side effects included.
+    //
+    // The timeout takes the fuel from another thread, so the fuel is taken atomically.
+    //   fuel = atomicrmw sub @kaleidrs.fuel, 1
+    //   fuelleft = fuel > 0
+    //   br fuelleft, fuelok, outoffuel
+    // outoffuel:
+    //   call kaleidrs_out_of_fuel(fuel)
+    //   unreachable
+    // fuelok:
+    fn build_fuel_check(&self) {
+        if self.fuel_limit.is_none() && self.timeout.is_none() {
+            return;
+        }
+
+        let i64_type = self.context.i64_type();
+        let fuel = self
+            .module
+            .get_global(FUEL_GLOBAL)
+            .unwrap_or_else(|| self.module.add_global(i64_type, None, FUEL_GLOBAL));
+
+        let fuel_before = self
+            .builder
+            .build_atomicrmw(
+                AtomicRMWBinOp::Sub,
+                fuel.as_pointer_value(),
+                i64_type.const_int(1, false),
+                AtomicOrdering::Monotonic,
+            )
+            .expect("FATAL: LLVM failed to build atomicrmw instruction");
+
+        let fuel_left = self
+            .builder
+            .build_int_compare(IntPredicate::SGT, fuel_before, i64_type.const_zero(), "fuelleft")
+            .expect("FATAL: LLVM failed to build int compare");
+
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_parent())
+            .expect("FATAL: a fuel check outside of a function");
+        let out_of_fuel_bb = self.context.append_basic_block(function, "outoffuel");
+        let fuel_ok_bb = self.context.append_basic_block(function, "fuelok");
+
+        self.builder
+            .build_conditional_branch(fuel_left, fuel_ok_bb, out_of_fuel_bb)
+            .expect("FATAL: LLVM failed to build br instruction!");
+
+        self.builder.position_at_end(out_of_fuel_bb);
+
+        let out_of_fuel_fn = self.get_runtime_function(
+            "kaleidrs_out_of_fuel",
+            self.context.void_type().fn_type(&[i64_type.into()], false),
+        );
+
+        self.builder
+            .build_call(out_of_fuel_fn, &[fuel_before.into()], &"")
+            .expect("FATAL: LLVM failed to build call!");
+
+        self.builder
+            .build_unreachable()
+            .expect("FATAL: LLVM failed to build unreachable!");
+
+        self.builder.position_at_end(fuel_ok_bb);
+    }
+
+    // Generates the body of a loop, with break and continue inside of it jumping
+    // to the given blocks. Nested loops push their own targets on top.
+    fn build_loop_body<'src>(
+        &self,
+        body: &ASTExpr<'src>,
+        continue_bb: BasicBlock<'ctx>,
+        break_bb: BasicBlock<'ctx>,
+    ) -> Result<(), BackendError<'src>> {
+        self.loops.borrow_mut().push((continue_bb, break_bb));
+        let body_genval = body.codegen(self);
+        self.loops.borrow_mut().pop();
+
+        body_genval.map(|_| ())
+    }
+
+    // Functions from the runtime library (src/clib/io.c) the backend calls on
+    // its own accord, they get declared in the module on first use.
+    fn get_runtime_function(&self, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        self.module
+            .get_function(name)
+            .unwrap_or_else(|| self.module.add_function(name, fn_type, Some(Linkage::External)))
+    }
+
+    // The type checker has already made sure any conversion asked for here is legal,
+    // so just perform the implicit ones: booleans to doubles, and integers to doubles
+    // for top level expressions which always hand back a double.
+    fn coerce(
+        &self,
+        value: BasicValueEnum<'ctx>,
+        target: BasicTypeEnum<'ctx>,
+    ) -> BasicValueEnum<'ctx> {
+        match (value, target) {
+            (BasicValueEnum::IntValue(int_val), BasicTypeEnum::FloatType(float_type)) => {
+                if int_val.get_type().get_bit_width() == 1 {
+                    to_llvm_float!(self, int_val).as_basic_value_enum()
+                } else {
+                    self.builder
+                        .build_signed_int_to_float(int_val, float_type, &"inttofp")
+                        .expect("FATAL: LLVM failed to convert int to float")
+                        .as_basic_value_enum()
+                }
+            }
+
+            (value, _) => value,
+        }
+    }
+
+    // C like truthiness, anything that is not false, zero or 0.0 is true
+    fn build_truthiness(&self, value: BasicValueEnum<'ctx>, name: &str) -> IntValue<'ctx> {
+        match value {
+            BasicValueEnum::IntValue(int_val) if int_val.get_type().get_bit_width() == 1 => int_val,
+
+            BasicValueEnum::IntValue(int_val) => self
+                .builder
+                .build_int_compare(
+                    IntPredicate::NE,
+                    int_val,
+                    int_val.get_type().const_zero(),
+                    name,
+                )
+                .expect("FATAL: LLVM failed to build int compare!"),
+
+            float_val => self
+                .builder
+                .build_float_compare(
+                    FloatPredicate::ONE,
+                    float_val.into_float_value(),
+                    self.context.f64_type().const_float(0.0),
+                    name,
+                )
+                .expect("FATAL: LLVM failed to build float compare!"),
+        }
+    }
+
+    // Integers and booleans get their own instructions when both sides of a binary
+    // operator agree on the type, returns None for operators that would have to go
+    // through doubles instead (arithmetic on booleans, user defined operators).
+    fn build_int_binop(
+        &self,
+        op: Ops,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+    ) -> Option<IntValue<'ctx>> {
+        if left.get_type() != right.get_type() {
+            return None;
+        }
+
+        let is_bool = left.get_type().get_bit_width() == 1;
+        let builder = &self.builder;
+
+        let res = match op {
+            Ops::Plus if !is_bool => builder.build_int_add(left, right, &"addtmp"),
+            Ops::Minus if !is_bool => builder.build_int_sub(left, right, &"subtmp"),
+            Ops::Mult if !is_bool => builder.build_int_mul(left, right, &"multmp"),
+            Ops::Div if !is_bool => Ok(self.build_int_division(left, right)),
+            Ops::Eq => builder.build_int_compare(IntPredicate::EQ, left, right, &"eqtmp"),
+            Ops::Neq => builder.build_int_compare(IntPredicate::NE, left, right, &"neqtmp"),
+            Ops::Lt if !is_bool => builder.build_int_compare(IntPredicate::SLT, left, right, &"lttmp"),
+            Ops::Gt if !is_bool => builder.build_int_compare(IntPredicate::SGT, left, right, &"gttmp"),
+            Ops::And => builder.build_and(left, right, &"andtmp"),
+            Ops::Or => builder.build_or(left, right, &"ortmp"),
+            Ops::Xor => builder.build_xor(left, right, &"xortmp"),
+            _ => return None,
+        };
+
+        Some(res.expect("FATAL: LLVM failed to build integer instruction!"))
+    }
+
+    // Integer division by zero, and of the smallest i64 by -1, is undefined in LLVM, the
+    // passes are free to assume it never happens. So it's checked for, and calls into the
+    // runtime, which reports it and stops the program (or the expression, in the REPL).
+    //   bad = right == 0 | (left == MIN & right == -1)
+    //   br bad, divfault, divok
+    // divfault:
+    //   call kaleidrs_division_fault(left, right)
+    //   unreachable
+    // divok:
+    //   sdiv left, right
+    fn build_int_division(&self, left: IntValue<'ctx>, right: IntValue<'ctx>) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+        let builder = &self.builder;
+
+        let by_zero = builder
+            .build_int_compare(IntPredicate::EQ, right, i64_type.const_zero(), &"byzero")
+            .expect("FATAL: LLVM failed to build int compare!");
+        let left_min = builder
+            .build_int_compare(IntPredicate::EQ, left, i64_type.const_int(i64::MIN as u64, true), &"leftmin")
+            .expect("FATAL: LLVM failed to build int compare!");
+        let right_minus_one = builder
+            .build_int_compare(IntPredicate::EQ, right, i64_type.const_all_ones(), &"rightminusone")
+            .expect("FATAL: LLVM failed to build int compare!");
+        let overflows = builder
+            .build_and(left_min, right_minus_one, &"overflows")
+            .expect("FATAL: LLVM failed to build and!");
+        let bad = builder
+            .build_or(by_zero, overflows, &"baddiv")
+            .expect("FATAL: LLVM failed to build or!");
+
+        let function = builder.get_insert_block().unwrap().get_parent().unwrap();
+        let fault_bb = self.context.append_basic_block(function, &"divfault");
+        let ok_bb = self.context.append_basic_block(function, &"divok");
+
+        builder
+            .build_conditional_branch(bad, fault_bb, ok_bb)
+            .expect("FATAL: LLVM failed to build br instruction!");
+
+        builder.position_at_end(fault_bb);
+
+        let fault_fn = self.get_runtime_function(
+            "kaleidrs_division_fault",
+            self.context
+                .void_type()
+                .fn_type(&[i64_type.into(), i64_type.into()], false),
+        );
+
+        builder
+            .build_call(fault_fn, &[left.into(), right.into()], &"")
+            .expect("FATAL: LLVM failed to build call!");
+        builder
+            .build_unreachable()
+            .expect("FATAL: LLVM failed to build unreachable!");
+
+        builder.position_at_end(ok_bb);
+
+        builder
+            .build_int_signed_div(left, right, &"divtmp")
+            .expect("FATAL: LLVM failed to build integer instruction!")
+    }
+
+    // Strings are handed over to the runtime, which knows how to concatenate and
+    // compare them, returns None for operators that do not apply to strings.
+    fn build_string_binop(
+        &self,
+        op: Ops,
+        left: StructValue<'ctx>,
+        right: StructValue<'ctx>,
+    ) -> Option<BasicValueEnum<'ctx>> {
+        let str_type = BasicMetadataTypeEnum::from(self.llvm_type(Type::Str));
+        let args = [left.into(), right.into()];
+
+        match op {
+            Ops::Plus => {
+                let concat_fn = self.get_runtime_function(
+                    "kstr_concat",
+                    self.llvm_type(Type::Str).fn_type(&[str_type, str_type], false),
+                );
+
+                self.builder
+                    .build_call(concat_fn, &args, &"concattmp")
+                    .expect("FATAL: LLVM failed to build call!")
+                    .try_as_basic_value()
+                    .left()
+            }
+
+            // kstr_compare orders strings like strcmp does, negative, zero, or positive
+            Ops::Eq | Ops::Neq | Ops::Lt | Ops::Gt => {
+                let i64_type = self.context.i64_type();
+
+                let compare_fn = self.get_runtime_function(
+                    "kstr_compare",
+                    i64_type.fn_type(&[str_type, str_type], false),
+                );
+
+                let ordering = self
+                    .builder
+                    .build_call(compare_fn, &args, &"ordtmp")
+                    .expect("FATAL: LLVM failed to build call!")
+                    .try_as_basic_value()
+                    .left()?
+                    .into_int_value();
+
+                let predicate = match op {
+                    Ops::Eq => IntPredicate::EQ,
+                    Ops::Neq => IntPredicate::NE,
+                    Ops::Lt => IntPredicate::SLT,
+                    _ => IntPredicate::SGT,
+                };
+
+                let cmp = self
+                    .builder
+                    .build_int_compare(predicate, ordering, i64_type.const_zero(), &"strcmptmp")
+                    .expect("FATAL: LLVM failed to build int compare!");
+
+                Some(cmp.as_basic_value_enum())
+            }
+
+            _ => None,
+        }
+    }
+}
+
+// Nearly every expression produces a first class value, small helper to
+// generate code for one and fetch the result as such.
+fn codegen_value<'ctx, 'src>(
+    expr: &ASTExpr<'src>,
+    context: &LLVMContext<'ctx>,
+) -> Result<BasicValueEnum<'ctx>, BackendError<'src>> {
+    expr.codegen(context).map(|anyval| {
+        BasicValueEnum::try_from(anyval).expect("FATAL: expression did not produce a value")
+    })
 }
 
 // There are three lifetimes at play when working with references from the
@@ -229,38 +1496,188 @@ where
                 Ok(float_type.const_float(*num).as_any_value_enum())
             }
 
+            // Integer literals are sign extended 64 bit constants
+            IntegerExpr(num) => {
+                let int_type = context.context.i64_type();
+                Ok(int_type.const_int(*num as u64, true).as_any_value_enum())
+            }
+
+            // Booleans are one bit integers
+            BoolExpr(value) => {
+                let bool_type = context.context.bool_type();
+                Ok(bool_type.const_int(*value as u64, false).as_any_value_enum())
+            }
+
+            // String literals become a global constant holding the characters,
+            // the value itself pairs the pointer to it with the length. Its name is
+            // remembered until the item it's written in is done, so the global can go
+            // along with it, see delete_functions_after. No literal is generated in
+            // between, so no other can take over the name of one the passes deleted.
+            StringExpr(raw) => {
+                let contents = unescape(raw);
+
+                // inkwell marks this unsafe for the pointer type it claims, pointers are opaque here
+                let global = unsafe { context.builder.build_global_string(&contents, &"strlit") }
+                    .expect("FATAL: LLVM failed to build global string!");
+
+                context
+                    .string_literals
+                    .borrow_mut()
+                    .push(global.get_name().to_string_lossy().into_owned());
+
+                let string = context.context.const_struct(
+                    &[
+                        global.as_pointer_value().as_basic_value_enum(),
+                        context
+                            .context
+                            .i64_type()
+                            .const_int(contents.len() as u64, false)
+                            .as_basic_value_enum(),
+                    ],
+                    false,
+                );
+
+                Ok(string.as_any_value_enum())
+            }
+
+            // Array literals are allocated by the runtime, then filled in one by one
+            ArrayExpr(elements) => {
+                let i64_type = context.context.i64_type();
+                let f64_type = context.context.f64_type();
+
+                let array_ptr = context.build_array_alloc(
+                    i64_type.const_int(elements.len() as u64, false),
+                    f64_type.const_zero(),
+                );
+
+                let elements_ptr = context
+                    .builder
+                    .build_struct_gep(context.array_header_type(), array_ptr, 1, &"elements")
+                    .expect("FATAL: LLVM failed to build GEP!");
+
+                for (idx, element) in elements.iter().enumerate() {
+                    let element_genval = context.coerce(codegen_value(element, context)?, f64_type.into());
+
+                    let element_ptr = unsafe {
+                        context
+                            .builder
+                            .build_in_bounds_gep(
+                                f64_type,
+                                elements_ptr,
+                                &[i64_type.const_int(idx as u64, false)],
+                                &"elemptr",
+                            )
+                            .expect("FATAL: LLVM failed to build GEP!")
+                    };
+
+                    context
+                        .builder
+                        .build_store(element_ptr, element_genval)
+                        .expect("FATAL: LLVM failed to build store instruction");
+                }
+
+                Ok(array_ptr.as_any_value_enum())
+            }
+
+            ArrayFillExpr { value, count } => {
+                let f64_type = context.context.f64_type().into();
+                let value_genval = context.coerce(codegen_value(value, context)?, f64_type);
+                let count_genval = context.build_index(codegen_value(count, context)?);
+
+                let array_ptr =
+                    context.build_array_alloc(count_genval, value_genval.into_float_value());
+
+                Ok(array_ptr.as_any_value_enum())
+            }
+
+            IndexExpr { array, index } => {
+                let element_ptr = context.build_element_ptr(array, index)?;
+
+                let load_insn = context
+                    .builder
+                    .build_load(context.context.f64_type(), element_ptr, &"elemtmp")
+                    .expect("FATAL: LLVM failed to build load instruction");
+
+                Ok(load_insn.as_any_value_enum())
+            }
+
+            // Arrays keep their length in front of the elements, strings carry it along
+            LenExpr(operand) => match codegen_value(operand, context)? {
+                BasicValueEnum::StructValue(string) => {
+                    let len = context
+                        .builder
+                        .build_extract_value(string, 1, &"len")
+                        .expect("FATAL: LLVM failed to extract string length");
+
+                    Ok(len.as_any_value_enum())
+                }
+
+                array => Ok(context
+                    .build_array_len(array.into_pointer_value())
+                    .as_any_value_enum()),
+            },
+
             // To handle variable case, make sure the variable exists in symbol table,
             // if it doesn't return error, otherwise, fetch the LLVM Value for that variable
             VariableExpr(varname) => {
-                if let Some(pointer_val) = context.sym_table.borrow().get(*varname) {
+                if let Some((pointer_val, var_type)) = context.lookup_variable(varname) {
                     let load_insn = context
                         .builder
-                        .build_load(context.context.f64_type(), *pointer_val, &varname)
+                        .build_load(var_type, pointer_val, &varname)
                         .expect("FATAL: LLVM failed to build load instruction");
 
                     Ok(load_insn.as_any_value_enum())
+                } else if let Some(function) = context.module.get_function(varname) {
+                    // Naming a function without calling it gives a closure with no environment
+                    let wrapper = context.get_closure_wrapper(function);
+                    let null_env = context
+                        .context
+                        .i8_type()
+                        .ptr_type(AddressSpace::default())
+                        .const_null();
+
+                    Ok(context.build_closure(wrapper, null_env).as_any_value_enum())
                 } else {
                     Err(BackendError::UnknownVariable(varname))
                 }
             }
 
-            // Unary Expressions, all fall into the category of overloaded operators
+            // Negating booleans and integers are builtin, the rest of the unary expressions
+            // fall into the category of overloaded operators. ! on an integer flips every bit.
             UnaryExpr { op, operand } => {
-                let fn_name = format!("unary{}", op.as_str());
+                let operand_genval = codegen_value(operand, context)?;
 
-                if let Some(unary_overload_fn) = context.module.get_function(&fn_name) {
-                    let operand_genval = operand.codegen(context).map(|anyval| {
-                        BasicMetadataValueEnum::FloatValue(anyval.into_float_value())
-                    })?;
+                match (op, operand_genval) {
+                    (Ops::Negate, BasicValueEnum::IntValue(int_val)) => {
+                        let not = context.builder.build_not(int_val, &"nottmp").unwrap();
 
-                    let unary_op_call = context
-                        .builder
-                        .build_call(unary_overload_fn, &[operand_genval], "unarytmp")
-                        .expect("FATAL: LLVM failed to build call!");
+                        Ok(not.as_any_value_enum())
+                    }
 
-                    Ok(unary_op_call.as_any_value_enum())
-                } else {
-                    Err(BackendError::UndefinedOperator(*op))
+                    (Ops::Minus, BasicValueEnum::IntValue(int_val)) => {
+                        let neg = context.builder.build_int_neg(int_val, &"negtmp").unwrap();
+
+                        Ok(neg.as_any_value_enum())
+                    }
+
+                    _overloaded => {
+                        let fn_name = format!("unary{}", op.as_str());
+
+                        let unary_overload_fn = context
+                            .module
+                            .get_function(&fn_name)
+                            .ok_or(BackendError::UndefinedOperator(*op))?;
+
+                        let operand_genval =
+                            context.coerce(operand_genval, context.context.f64_type().into());
+
+                        let unary_op_call = context
+                            .builder
+                            .build_call(unary_overload_fn, &[operand_genval.into()], "unarytmp")
+                            .expect("FATAL: LLVM failed to build call!");
+
+                        Ok(unary_op_call.as_any_value_enum())
+                    }
                 }
             }
 
@@ -270,24 +1687,28 @@ where
                 // Assignments are special cases, we only want to codegen the right
                 // then treat the left as a named symbol to store as variable name
                 if let Ops::Assign = op {
-                    // Make sure left hand side is a variable name
-                    let ptr_val = match **left {
+                    // Make sure left hand side is a variable name, or an element of an array
+                    let (ptr_val, var_type) = match **left {
                         ASTExpr::VariableExpr(name) => context
-                            .sym_table
-                            .borrow()
-                            .get(name)
-                            .copied()
+                            .lookup_variable(name)
                             .ok_or(BackendError::UnknownVariable(name)),
 
+                        ASTExpr::IndexExpr {
+                            ref array,
+                            ref index,
+                        } => context
+                            .build_element_ptr(array, index)
+                            .map(|elem_ptr| (elem_ptr, context.context.f64_type().into())),
+
                         _ => Err(BackendError::BadAssignment),
                     }?;
 
                     // Generate the right hand side, store its value in the variable, completeing assignment
-                    let right_genval = right.codegen(context)?;
+                    let right_genval = context.coerce(codegen_value(right, context)?, var_type);
 
                     context
                         .builder
-                        .build_store(ptr_val, right_genval.into_float_value())
+                        .build_store(ptr_val, right_genval)
                         .expect("FATAL: LLVM failed to build store instruction");
 
                     // Like C assignments, the right hand side is returned
@@ -296,9 +1717,32 @@ where
                     Ok(right_genval.as_any_value_enum())
                 } else {
                     // Generate both left hand and right hand sides of the expression first
-                    let left_genval = left.codegen(context).map(AnyValueEnum::into_float_value)?;
-                    let right_genval =
-                        right.codegen(context).map(AnyValueEnum::into_float_value)?;
+                    let left_genval = codegen_value(left, context)?;
+                    let right_genval = codegen_value(right, context)?;
+
+                    // Strings are dealt with by the runtime library
+                    if let (BasicValueEnum::StructValue(left_str), BasicValueEnum::StructValue(right_str)) =
+                        (left_genval, right_genval)
+                    {
+                        return context
+                            .build_string_binop(*op, left_str, right_str)
+                            .map(|str_res| str_res.as_any_value_enum())
+                            .ok_or(BackendError::UndefinedOperator(*op));
+                    }
+
+                    // Integers and booleans have their own set of instructions
+                    if let (BasicValueEnum::IntValue(left_int), BasicValueEnum::IntValue(right_int)) =
+                        (left_genval, right_genval)
+                    {
+                        if let Some(int_res) = context.build_int_binop(*op, left_int, right_int) {
+                            return Ok(int_res.as_any_value_enum());
+                        }
+                    }
+
+                    // Everything else works on doubles, booleans are promoted to 0.0/1.0
+                    let f64_type = context.context.f64_type().into();
+                    let left_genval = context.coerce(left_genval, f64_type).into_float_value();
+                    let right_genval = context.coerce(right_genval, f64_type).into_float_value();
 
                     // Apply the operator by the match statement, creating an add, subtract,... instruction
                     match *op {
@@ -338,7 +1782,8 @@ where
                             Ok(div.as_any_value_enum())
                         }
 
-                        // For the comparison operators, map() a conversion back to float, Kaleidoscope only works with floating point nums!
+                        // The comparison operators produce booleans, which are converted back to
+                        // doubles only when they are used as one.
                         Ops::Eq => {
                             let cmp = context
                                 .builder
@@ -348,7 +1793,6 @@ where
                                     right_genval,
                                     &"eqtmp",
                                 )
-                                .map(|int_val| to_llvm_float!(context, int_val))
                                 .unwrap();
 
                             Ok(cmp.as_any_value_enum())
@@ -363,7 +1807,6 @@ where
                                     right_genval,
                                     &"neqtmp",
                                 )
-                                .map(|int_val| to_llvm_float!(context, int_val))
                                 .unwrap();
 
                             Ok(cmp.as_any_value_enum())
@@ -378,7 +1821,6 @@ where
                                     right_genval,
                                     &"gttmp",
                                 )
-                                .map(|int_val| to_llvm_float!(context, int_val))
                                 .unwrap();
 
                             Ok(cmp.as_any_value_enum())
@@ -393,7 +1835,6 @@ where
                                     right_genval,
                                     &"lttmp",
                                 )
-                                .map(|int_val| to_llvm_float!(context, int_val))
                                 .unwrap();
 
                             Ok(cmp.as_any_value_enum())
@@ -417,54 +1858,199 @@ where
                                     .build_call(binary_overload_fn, args.as_slice(), &"calltmp")
                                     .expect("FATAL: LLVM failed to build call!");
 
-                                Ok(overload_call.as_any_value_enum())
-                            } else {
-                                Err(BackendError::UndefinedOperator(overloaded_op))
-                            }
-                        }
+                                Ok(overload_call.as_any_value_enum())
+                            } else {
+                                Err(BackendError::UndefinedOperator(overloaded_op))
+                            }
+                        }
+                    }
+                }
+            }
+
+            // This one is the most complex expression to handle...
+            CallExpr { ref callee, args } => {
+                // First, see if the function is defined in LLVM module, if not, we have
+                // an undefined function trying to be called
+                let function = context
+                    .module
+                    .get_function(callee)
+                    .ok_or(BackendError::UndefinedFunction(callee))?;
+
+                let param_cnt = function.count_params();
+
+                if param_cnt != args.len() as u32 {
+                    // verify parameter counts
+                    return Err(BackendError::IncorrectNumberOfArgs {
+                        func_name: callee,
+                        param_cnt,
+                    });
+                }
+
+                // Generate code for the arguments passed, call site expressions,
+                // Any of the arguments could also produce a backend error, so propogate up
+                let llvm_val_args = args
+                    .iter()
+                    .map(|arg| codegen_value(arg, context))
+                    .collect::<Result<Vec<_>, BackendError>>()?;
+
+                // Booleans passed where the function expects doubles need converting
+                let llvm_val_args: Vec<BasicMetadataValueEnum> = llvm_val_args
+                    .into_iter()
+                    .zip(function.get_type().get_param_types())
+                    .map(|(val, param_type)| context.coerce(val, param_type).into())
+                    .collect();
+
+                let function = context.intrinsic_for(function).unwrap_or(function);
+
+                // Building a call requires arguments be of type BasicMetadataValueEnum,
+                // as a slice of them, had to convert, but does produce LLVM call instruction.
+                let call = context
+                    .builder
+                    .build_call(function, llvm_val_args.as_slice(), &"calltmp")
+                    .expect("Irrecoverable: LLVM failed to build call expression");
+
+                Ok(call.as_any_value_enum())
+            }
+
+            // Calls through a closure pass its environment along as the first argument
+            ApplyExpr {
+                callee,
+                args,
+                callee_type,
+            } => {
+                let Some(Type::Fn(sig)) = callee_type else {
+                    return Err(BackendError::NotAFunction);
+                };
+
+                let closure = codegen_value(callee, context)?.into_struct_value();
+
+                let code_ptr = context
+                    .builder
+                    .build_extract_value(closure, 0, &"code")
+                    .expect("FATAL: LLVM failed to extract closure code")
+                    .into_pointer_value();
+
+                let env_ptr = context
+                    .builder
+                    .build_extract_value(closure, 1, &"env")
+                    .expect("FATAL: LLVM failed to extract closure environment");
+
+                let mut call_args: Vec<BasicMetadataValueEnum> = vec![env_ptr.into()];
+
+                for (arg, param_type) in args.iter().zip(&sig.params) {
+                    let arg_genval = codegen_value(arg, context)?;
+                    call_args.push(context.coerce(arg_genval, context.llvm_type(*param_type)).into());
+                }
+
+                let call = context
+                    .builder
+                    .build_indirect_call(
+                        context.closure_fn_type(sig),
+                        code_ptr,
+                        call_args.as_slice(),
+                        &"calltmp",
+                    )
+                    .expect("Irrecoverable: LLVM failed to build call expression");
+
+                Ok(call.as_any_value_enum())
+            }
+
+            // Lambdas are generated as functions of their own, see build_lambda. Evaluating
+            // one copies the variables it captures into a freshly allocated environment
+            // (never freed, like strings and arrays), and pairs that with the function.
+            LambdaExpr {
+                params,
+                ret_type,
+                captures,
+                self_name,
+                body,
+            } => {
+                let sig = FnSig {
+                    params: params.iter().map(|(_, ty)| ty.unwrap_or(Type::F64)).collect(),
+                    ret: ret_type.unwrap_or(Type::F64),
+                };
+
+                let captured = captures
+                    .iter()
+                    .map(|name| {
+                        let (var_ptr, var_type) = context
+                            .lookup_variable(name)
+                            .ok_or(BackendError::UnknownVariable(name))?;
+
+                        Ok(context
+                            .builder
+                            .build_load(var_type, var_ptr, name)
+                            .expect("FATAL: LLVM failed to build load instruction"))
+                    })
+                    .collect::<Result<Vec<_>, BackendError>>()?;
+
+                let env_type = context.context.struct_type(
+                    &captured.iter().map(|val| val.get_type()).collect::<Vec<_>>(),
+                    false,
+                );
+
+                let env_ptr = if captured.is_empty() {
+                    context
+                        .context
+                        .i8_type()
+                        .ptr_type(AddressSpace::default())
+                        .const_null()
+                } else {
+                    let env_ptr = context
+                        .builder
+                        .build_malloc(env_type, &"env")
+                        .expect("FATAL: LLVM failed to build malloc!");
+
+                    for (idx, captured_val) in captured.iter().enumerate() {
+                        let field_ptr = context
+                            .builder
+                            .build_struct_gep(env_type, env_ptr, idx as u32, &"captured")
+                            .expect("FATAL: LLVM failed to build GEP!");
+
+                        context
+                            .builder
+                            .build_store(field_ptr, *captured_val)
+                            .expect("FATAL: LLVM failed to build store instruction");
                     }
-                }
+
+                    env_ptr
+                };
+
+                let lambda = context.build_lambda(&sig, params, captures, *self_name, env_type, body)?;
+
+                Ok(context.build_closure(lambda, env_ptr).as_any_value_enum())
             }
 
-            // This one is the most complex expression to handle...
-            CallExpr { ref callee, args } => {
-                // First, see if the function is defined in LLVM module, if not, we have
-                // an undefined function trying to be called
+            // The closure of a local function is kept on the stack like any other variable,
+            // its body can only reach it through the environment, see build_lambda_body
+            LocalFnExpr { name, lambda, body } => {
+                let closure = codegen_value(lambda, context)?;
+
                 let function = context
-                    .module
-                    .get_function(callee)
-                    .ok_or(BackendError::UndefinedFunction(callee))?;
+                    .builder
+                    .get_insert_block()
+                    .unwrap()
+                    .get_parent()
+                    .unwrap();
 
-                let param_cnt = function.count_params();
+                let fn_ptr = context.create_entry_block_alloca(function, name, closure.get_type());
 
-                if param_cnt != args.len() as u32 {
-                    // verify parameter counts
-                    return Err(BackendError::IncorrectNumberOfArgs {
-                        func_name: callee,
-                        param_cnt,
-                    });
-                }
+                context
+                    .builder
+                    .build_store(fn_ptr, closure)
+                    .expect("FATAL: LLVM failed to build store instruction");
 
-                // Generate code for the arguments passed, call site expressions,
-                // Any of the arguments could also produce a backend error, so propogate up
-                let llvm_val_args = args
-                    .iter()
-                    .map(|arg| arg.codegen(context))
-                    .collect::<Result<Vec<_>, BackendError>>()?;
+                context.sym_table.borrow_mut().push_scope();
+                context
+                    .sym_table
+                    .borrow_mut()
+                    .define(name, (fn_ptr, closure.get_type()));
 
-                let llvm_val_args: Vec<BasicMetadataValueEnum> = llvm_val_args
-                    .into_iter()
-                    .map(|val| BasicMetadataValueEnum::FloatValue(val.into_float_value()))
-                    .collect();
+                let body_codegen = body.codegen(context);
 
-                // Building a call requires arguments be of type BasicMetadataValueEnum,
-                // as a slice of them, had to convert, but does produce LLVM call instruction.
-                let call = context
-                    .builder
-                    .build_call(function, llvm_val_args.as_slice(), &"calltmp")
-                    .expect("Irrecoverable: LLVM failed to build call expression");
+                context.sym_table.borrow_mut().pop_scope();
 
-                Ok(call.as_any_value_enum())
+                body_codegen
             }
 
             IfExpr {
@@ -472,20 +2058,10 @@ where
                 then_branch,
                 else_branch,
             } => {
-                let cond_codegen = cond.codegen(context)?;
+                let cond_codegen = codegen_value(cond, context)?;
 
-                let zero = context.context.f64_type().const_float(0.0);
-
-                // Compute the truth of the condition by comparing value of expression to zero, C like truthiness
-                let cond_bool = context
-                    .builder
-                    .build_float_compare(
-                        FloatPredicate::ONE,
-                        cond_codegen.into_float_value(),
-                        zero,
-                        &"ifcond",
-                    )
-                    .expect("FATAL: LLVM failed to build float compare!");
+                // Compute the truth of the condition, C like truthiness
+                let cond_bool = context.build_truthiness(cond_codegen, &"ifcond");
 
                 let function = context
                     .builder
@@ -510,34 +2086,55 @@ where
                 // before calling codegen() methods on then and else expressions, otherwise
                 // we would generate code in wrong basic block and mess everything up.
                 context.builder.position_at_end(bbs[0]);
-                let then_v = then_branch.codegen(context)?;
-
-                // Don't forget to branch back to merge basic block!!!
-                context
-                    .builder
-                    .build_unconditional_branch(bbs[2])
-                    .expect("FATAL: LLVM failed to build branch!");
-
+                let then_v = codegen_value(then_branch, context)?;
                 let then_bb = context.builder.get_insert_block().unwrap();
 
                 context.builder.position_at_end(bbs[1]);
-                let else_v = else_branch.codegen(context)?;
-                context
-                    .builder
-                    .build_unconditional_branch(bbs[2])
-                    .expect("FATAL: LLVM failed to build branch!");
-
+                let else_v = codegen_value(else_branch, context)?;
                 let else_bb = context.builder.get_insert_block().unwrap();
 
+                // Only now that we have seen both branches do we know the type of the
+                // result, a double if the branches disagree, so convert at the end of
+                // each branch, then don't forget to branch back to merge basic block!!!
+                // A branch that is a break or continue only reaches the merge block from
+                // the dead block left behind it, so it takes the type of the other branch
+                let phi_type = if then_branch.is_loop_control() {
+                    else_v.get_type()
+                } else if else_branch.is_loop_control() || then_v.get_type() == else_v.get_type() {
+                    then_v.get_type()
+                } else {
+                    context.context.f64_type().as_basic_type_enum()
+                };
+
+                let mut incoming = vec![];
+
+                for (branch, branch_v, branch_bb) in [
+                    (then_branch, then_v, then_bb),
+                    (else_branch, else_v, else_bb),
+                ] {
+                    context.builder.position_at_end(branch_bb);
+
+                    if branch.is_loop_control() {
+                        incoming.push((phi_type.const_zero(), branch_bb));
+                    } else {
+                        incoming.push((context.coerce(branch_v, phi_type), branch_bb));
+                    }
+
+                    context
+                        .builder
+                        .build_unconditional_branch(bbs[2])
+                        .expect("FATAL: LLVM failed to build branch!");
+                }
+
                 context.builder.position_at_end(bbs[2]);
                 let phi_node = context
                     .builder
-                    .build_phi(context.context.f64_type(), &"iftmp")
+                    .build_phi(phi_type, &"iftmp")
                     .expect("LLVM failed to create PHI!");
 
                 phi_node.add_incoming(&[
-                    (&then_v.into_float_value() as &dyn BasicValue<'ctx>, then_bb),
-                    (&else_v.into_float_value() as &dyn BasicValue<'ctx>, else_bb),
+                    (&incoming[0].0 as &dyn BasicValue<'ctx>, incoming[0].1),
+                    (&incoming[1].0 as &dyn BasicValue<'ctx>, incoming[1].1),
                 ]);
 
                 Ok(phi_node.as_any_value_enum())
@@ -553,34 +2150,39 @@ where
             //   ...
             //   bodyexpr
             //   ...
-            // loopend:
+            //   goto loopstep
+            // loopstep:                  <- continue jumps here
             //   step = stepexpr
             //   endcond = endexpr
             //
             //   curvar = load var
             //   nextvar = curvar + step
             //   store nextvar -> var
-            //   br endcond, loop, endloop
-            // outloop:
+            //   br endcond, loop, afterloop
+            // afterloop:                 <- break jumps here
             ForLoopExpr {
                 varname,
                 start,
                 end,
                 step,
                 body,
+                ..
             } => {
                 let preloop_bb = context.builder.get_insert_block().unwrap();
                 let function = preloop_bb.get_parent().unwrap();
 
-                // Create alloca for loop variable at entry block of function before start expression
-                let loop_var_ptr = context.create_entry_block_alloca(function, varname);
+                // The loop variable takes on the type of the start expression, the
+                // type checker has already converted it when the variable was annotated
+                let start_genval = codegen_value(start, context)?;
+                let var_type = start_genval.get_type();
 
-                let start_genval = start.codegen(context)?;
+                // Create alloca for loop variable at entry block of function
+                let loop_var_ptr = context.create_entry_block_alloca(function, varname, var_type);
 
                 // Store start expression into stack pointer of loop variable
                 context
                     .builder
-                    .build_store(loop_var_ptr, start_genval.into_float_value())
+                    .build_store(loop_var_ptr, start_genval)
                     .expect("FATAL: LLVM failed to build store instruction");
 
                 // Build the main loop basic block then a unconditional fall through branch
@@ -595,58 +2197,74 @@ where
                 // Set our builder cursor inside the loop
                 context.builder.position_at_end(loop_bb);
 
-                // If there is a collision with the loop variable an one outside loop, shadow the
-                // outer scope variable in favor of the loop variable, restore later below
-                let shadowed_var = context.sym_table.borrow().get(*varname).copied();
+                // The loop variable is scoped to the loop, shadowing any variable outside of
+                // it with the same name until we pop the scope again below
+                context.sym_table.borrow_mut().push_scope();
                 context
                     .sym_table
                     .borrow_mut()
-                    .insert(varname.to_string(), loop_var_ptr);
+                    .define(varname, (loop_var_ptr, var_type));
+
+                // Break and continue in the body need somewhere to jump to, the step
+                // and the exit of the loop get blocks of their own
+                let step_bb = context.context.append_basic_block(function, &"loopstep");
+                let afterloop_bb = context.context.append_basic_block(function, &"afterloop");
+
+                // Generate the body of the loop in the loop basic block, then fall through
+                // into the step from wherever the body left the builder cursor
+                context.build_loop_body(body, step_bb, afterloop_bb)?;
+
+                context
+                    .builder
+                    .build_unconditional_branch(step_bb)
+                    .expect("FATAL: LLVM failed to build branch!");
 
-                // Generate the body of the loop in the loop basic block
-                body.codegen(context)?;
+                context.builder.position_at_end(step_bb);
 
                 // Generate the step, the parser will supply the default of 1.0 if one
                 // was not given, otherwise we generate user defined
-                let step_genval = step.codegen(context)?;
+                let step_genval = codegen_value(step, context)?;
 
                 // The end condition check, generated after step
-                let end_codegen = end.codegen(context)?;
+                let end_codegen = codegen_value(end, context)?;
 
                 // Following three statements, we load the stack variable, apply step
                 // then store it back to the stack
                 let cur_val = context
                     .builder
-                    .build_load(context.context.f64_type(), loop_var_ptr, &varname)
-                    .map(|v| v.into_float_value())
+                    .build_load(var_type, loop_var_ptr, &varname)
                     .expect("FATAL: LLVM failed to build load instruction");
 
-                let next_val = context
-                    .builder
-                    .build_float_add(cur_val, step_genval.into_float_value(), &"nextvar")
-                    .unwrap();
+                let next_val = match (cur_val, step_genval) {
+                    (BasicValueEnum::IntValue(cur_int), BasicValueEnum::IntValue(step_int)) => context
+                        .builder
+                        .build_int_add(cur_int, step_int, &"nextvar")
+                        .unwrap()
+                        .as_basic_value_enum(),
+
+                    (cur_float, step_float) => context
+                        .builder
+                        .build_float_add(
+                            cur_float.into_float_value(),
+                            step_float.into_float_value(),
+                            &"nextvar",
+                        )
+                        .unwrap()
+                        .as_basic_value_enum(),
+                };
 
                 context
                     .builder
                     .build_store(loop_var_ptr, next_val)
                     .expect("FATAL: LLVM failed to build store instruction");
 
-                // Build the comparison, which will be the check to see if we branch out of the
-                // loop or continue
-                let cmp_val = context
-                    .builder
-                    .build_float_compare(
-                        FloatPredicate::OEQ,
-                        end_codegen.into_float_value(),
-                        context.context.f64_type().const_float(1.0),
-                        &"loopcond",
-                    )
-                    .expect("FATAL: LLVM failed to build comparison instruction");
-
-                let afterloop_bb = context.context.append_basic_block(function, "afterloop");
-
-                context.builder.position_at_end(loop_bb);
+                // Build the truth check of the end condition, which will decide if we
+                // branch out of the loop or continue
+                let cmp_val = context.build_truthiness(end_codegen, &"loopcond");
+                context.build_fuel_check();
 
+                // The step may have created blocks of its own, so branch back from
+                // wherever the builder cursor ended up, not necessarily the step block
                 context
                     .builder
                     .build_conditional_branch(cmp_val, loop_bb, afterloop_bb)
@@ -654,18 +2272,74 @@ where
 
                 context.builder.position_at_end(afterloop_bb);
 
-                // Above we collected a possible shadowed variable from the map
-                // of our variable symbols. If there was something that was shadowed
-                // restore it here, else, clear the loop variable from our scope
-                if let Some(variable) = shadowed_var {
-                    context
-                        .sym_table
-                        .borrow_mut()
-                        .insert(varname.to_string(), variable);
-                } else {
-                    context.sym_table.borrow_mut().remove(*varname);
+                context.sym_table.borrow_mut().pop_scope();
+
+                Ok(context
+                    .context
+                    .f64_type()
+                    .const_float(0.0)
+                    .as_any_value_enum())
+            }
+
+            // Each expression is generated in turn, only the last value is kept
+            BlockExpr(exprs) => {
+                let mut last_genval = None;
+
+                for expr in exprs {
+                    last_genval = Some(expr.codegen(context)?);
                 }
 
+                Ok(last_genval.expect("blocks are never empty"))
+            }
+
+            // Output while-loop as:
+            //   goto whilecond
+            // whilecond:                 <- continue jumps here
+            //   cond = condexpr
+            //   br cond, whilebody, afterwhile
+            // whilebody:
+            //   bodyexpr
+            //   goto whilecond
+            // afterwhile:                <- break jumps here
+            WhileExpr { cond, body } => {
+                let function = context
+                    .builder
+                    .get_insert_block()
+                    .unwrap()
+                    .get_parent()
+                    .unwrap();
+
+                let bbs = [&"whilecond", &"whilebody", &"afterwhile"]
+                    .into_iter()
+                    .map(|bb_name| context.context.append_basic_block(function, bb_name))
+                    .collect::<Vec<BasicBlock<'ctx>>>();
+
+                context
+                    .builder
+                    .build_unconditional_branch(bbs[0])
+                    .expect("FATAL: LLVM failed to build branch!");
+
+                // Condition is checked up front, the body may never run at all
+                context.builder.position_at_end(bbs[0]);
+                let cond_genval = codegen_value(cond, context)?;
+                let cond_bool = context.build_truthiness(cond_genval, &"whilecond");
+                context.build_fuel_check();
+
+                context
+                    .builder
+                    .build_conditional_branch(cond_bool, bbs[1], bbs[2])
+                    .expect("FATAL: LLVM failed to build br instruction!");
+
+                context.builder.position_at_end(bbs[1]);
+                context.build_loop_body(body, bbs[0], bbs[2])?;
+
+                context
+                    .builder
+                    .build_unconditional_branch(bbs[0])
+                    .expect("FATAL: LLVM failed to build branch!");
+
+                context.builder.position_at_end(bbs[2]);
+
                 Ok(context
                     .context
                     .f64_type()
@@ -673,9 +2347,40 @@ where
                     .as_any_value_enum())
             }
 
-            VarExpr { var_names, body } => {
-                let mut shadowed_vars: Vec<(&str, PointerValue<'ctx>)> = vec![];
+            // Jump to the targets of the innermost loop. Whatever code follows in the
+            // same expression can never run, but still needs a block to live in, so
+            // we leave the builder cursor in a fresh block with no way into it.
+            BreakExpr | ContinueExpr => {
+                let (continue_bb, break_bb) = context
+                    .loops
+                    .borrow()
+                    .last()
+                    .copied()
+                    .ok_or(BackendError::OutsideOfLoop(if let BreakExpr = self {
+                        &"break"
+                    } else {
+                        &"continue"
+                    }))?;
+
+                let target_bb = if let BreakExpr = self { break_bb } else { continue_bb };
+
+                context
+                    .builder
+                    .build_unconditional_branch(target_bb)
+                    .expect("FATAL: LLVM failed to build branch!");
+
+                let function = target_bb.get_parent().unwrap();
+                let dead_bb = context.context.append_basic_block(function, &"afterjump");
+                context.builder.position_at_end(dead_bb);
+
+                Ok(context
+                    .context
+                    .f64_type()
+                    .const_float(0.0)
+                    .as_any_value_enum())
+            }
 
+            VarExpr { var_names, body } => {
                 let function = context
                     .builder
                     .get_insert_block()
@@ -683,55 +2388,51 @@ where
                     .get_parent()
                     .unwrap();
 
+                // The variables shadow any outer ones of the same name until the scope is popped
+                context.sym_table.borrow_mut().push_scope();
+
                 // For each variable in the list, attempt to emit initializer code (if there was one given)
                 // else we give the default initializer to zero so that LLVM pointer value does not
                 // point to unitialized stack memory
-                for (ref var_name, init) in var_names.iter() {
-                    let var_init_codegen = init.as_ref().map_or_else(
-                        || Ok(context.context.f64_type().const_zero()),
-                        |initializer| {
-                            initializer
-                                .codegen(context)
-                                .map(AnyValueEnum::into_float_value)
-                        },
-                    )?;
+                for (ref var_name, annotation, init) in var_names.iter() {
+                    let var_init_codegen = match init {
+                        Some(initializer) => {
+                            let init_genval = codegen_value(initializer, context)?;
+
+                            match annotation {
+                                Some(ty) => context.coerce(init_genval, context.llvm_type(*ty)),
+                                None => init_genval,
+                            }
+                        }
+
+                        None => context
+                            .llvm_type(annotation.unwrap_or(Type::F64))
+                            .const_zero(),
+                    };
+
+                    let var_type = var_init_codegen.get_type();
 
                     // Allocate stack for variable, get pointer value
-                    let var_ptr = context.create_entry_block_alloca(function, var_name);
+                    let var_ptr = context.create_entry_block_alloca(function, var_name, var_type);
 
-                    // Store the initializer generated value, or the default of 0.0
+                    // Store the initializer generated value, or the default of zero
                     context
                         .builder
                         .build_store(var_ptr, var_init_codegen)
                         .expect("FATAL: LLVM failed to build store instruction");
 
-                    // Shadow any possible variables that have same names, override outer scope with inner scope
-                    // Do this by saving the old variable pointers in shadowed_vars vec, inserting the others in place
-                    if let Some(old_var_ptr) = context.sym_table.borrow().get(*var_name).copied() {
-                        shadowed_vars.push((var_name, old_var_ptr));
-                    }
-
+                    // Visible to the initializers of the following variables, and the body
                     context
                         .sym_table
                         .borrow_mut()
-                        .insert(var_name.to_string(), var_ptr);
+                        .define(var_name, (var_ptr, var_type));
                 }
 
                 // Generate the body that is scoped to these mutable variables
                 let body_codegen = body.codegen(context)?;
 
-                // Delete new bindings, we are done with them after body generation
-                var_names.iter().for_each(|(name, _)| {
-                    context.sym_table.borrow_mut().remove(*name);
-                });
-
-                // Restore old bindings, the variables we might have shadowed
-                shadowed_vars.iter().for_each(|(name, ptr_val)| {
-                    context
-                        .sym_table
-                        .borrow_mut()
-                        .insert(name.to_string(), *ptr_val);
-                });
+                // We are done with the bindings after body generation
+                context.sym_table.borrow_mut().pop_scope();
 
                 Ok(body_codegen.as_any_value_enum())
             }
@@ -739,123 +2440,339 @@ where
     }
 }
 
-// At prototype node, we need to establish arguments (all floats of course)
-// Add the function to module with type as fn(), fn (float) fn(float, float), etc...
+// At prototype node, we need to establish arguments, doubles unless annotated otherwise
+// Add the function to module with type as fn(), fn (float) fn(i64, bool), etc...
 impl<'ctx, 'ir, 'src> LLVMCodeGen<'ctx, 'ir, 'src> for Prototype<'src>
 where
     'ctx: 'ir,
 {
     fn codegen(&self, context: &LLVMContext<'ctx>) -> IRGenResult<'ir, 'src> {
-        use Prototype::*;
-
         let fn_name = self.get_name();
 
-        let param_types = vec![
-            BasicMetadataTypeEnum::FloatType(context.context.f64_type());
-            self.get_num_params()
-        ];
+        if let Some(host) = context.host_functions.borrow().get(&fn_name) {
+            return context.build_host_function(self, host);
+        }
+
+        let fn_type = context.prototype_fn_type(self);
 
-        let fn_type = context
-            .context
-            .f64_type()
-            .fn_type(param_types.as_slice(), false);
+        // Declaring a function again (one from the prelude, say) gives back the one there
+        // is, LLVM would otherwise add another under a new name
+        if let Some(declared) = context.module.get_function(&fn_name) {
+            return match declared.get_type() == fn_type {
+                true => Ok(declared.as_any_value_enum()),
+                false => Err(BackendError::ConflictingDeclaration(fn_name)),
+            };
+        }
 
         let fn_val = context
             .module
             .add_function(&fn_name, fn_type, Some(Linkage::External));
 
-        match self {
-            FunctionProto { args, .. } => {
-                // Set the names of params so the body expression can have resolution
-                // to the names of the parameters of function!
-                for (idx, param) in fn_val.get_params().iter().enumerate() {
-                    param.set_name(&args[idx])
-                }
+        // Set the names of params so the body expression can have resolution
+        // to the names of the parameters of function!
+        for (param, name) in fn_val.get_params().iter().zip(self.get_param_names()) {
+            param.set_name(name);
+        }
+
+        Ok(fn_val.as_any_value_enum())
+    }
+}
+
+impl<'ctx, 'ir, 'src> LLVMCodeGen<'ctx, 'ir, 'src> for Function<'src>
+where
+    'ctx: 'ir,
+{
+    // Generating a function is all or nothing. Whatever was added to the module on the
+    // way, the function itself, its prototype and any lambdas inside of it, is deleted
+    // again if the body fails to generate, so the same name can be defined again later.
+    fn codegen(&self, context: &LLVMContext<'ctx>) -> IRGenResult<'ir, 'src> {
+        let name = self.proto.get_name();
+        let last_before = context.module.get_last_function();
+        let literals_before = context.string_literals.borrow().len();
+
+        // A function that was declared before (by an extern, say) keeps that declaration
+        // until the body is done, calls to it are then pointed at the definition instead.
+        // To make sure we aren't defining functions twice, I just check if it has an
+        // entry basic block, if it does, then propogate error.
+        let (fn_val, declaration) = match context.module.get_function(&name) {
+            Some(declared) if declared.get_first_basic_block().is_some() => {
+                return Err(BackendError::MultipleFunctionDefs(name));
             }
 
-            OverloadedUnaryOpProto { arg, .. } => {
-                fn_val.get_params()[0].set_name(&arg);
+            // The host already defined it
+            _ if context.host_functions.borrow().contains_key(&name) => {
+                return Err(BackendError::MultipleFunctionDefs(name));
             }
 
-            OverloadedBinaryOpProto {
-                args: (lhs, rhs), ..
-            } => {
-                let params = fn_val.get_params();
-                params[0].set_name(&lhs);
-                params[1].set_name(&rhs);
+            // The definition's own prototype decides its signature. A declaration nothing
+            // calls yet (one from the prelude, say) makes way for any signature, calls
+            // already made have to keep getting the one they were made with.
+            Some(declared) => {
+                let fn_type = context.prototype_fn_type(&self.proto);
+
+                // The array run_passes lists declarations in lives on as a constant after
+                // the list is gone, anything else using the declaration calls it
+                let pointer = declared.as_global_value().as_pointer_value();
+                let called = iter::successors(pointer.get_first_use(), |u| u.get_next_use())
+                    .any(|u| !matches!(u.get_user(), AnyValueEnum::ArrayValue(_)));
+
+                if declared.get_type() != fn_type && called {
+                    return Err(BackendError::ConflictingDeclaration(name));
+                }
+
+                let fn_val = context
+                    .module
+                    .add_function(&name, fn_type, Some(Linkage::External));
+
+                for (param, param_name) in fn_val.get_params().iter().zip(self.proto.get_param_names()) {
+                    param.set_name(param_name);
+                }
+
+                (fn_val, Some(declared))
             }
+
+            None => (self.proto.codegen(context)?.into_function_value(), None),
+        };
+
+        if let Err(err) = context.build_function_body(self, fn_val) {
+            context.delete_functions_after(last_before, literals_before);
+            return Err(err);
+        }
+
+        // A definition stays, and its literals with it. A top level expression's are
+        // deleted along with it, see delete_top_level_expr.
+        if name != "__anonymous_expr" {
+            context.string_literals.borrow_mut().truncate(literals_before);
+        }
+
+        if let Some(declared) = declaration {
+            declared.replace_all_uses_with(fn_val);
+            unsafe { declared.delete() };
+            fn_val.as_global_value().set_name(&name);
         }
 
         Ok(fn_val.as_any_value_enum())
     }
 }
 
-impl<'ctx, 'ir, 'src> LLVMCodeGen<'ctx, 'ir, 'src> for Function<'src>
+// Globals are lowered to LLVM globals, exported from object files compiled ahead of time.
+// When JIT compiling, each input gets a new execution engine, which would start every
+// global over at its initial value. So instead, the module only declares the global,
+// and the value lives in a slot owned by the context, handed to each engine in jit_eval.
+impl<'ctx, 'ir, 'src> LLVMCodeGen<'ctx, 'ir, 'src> for Global<'src>
 where
     'ctx: 'ir,
 {
     fn codegen(&self, context: &LLVMContext<'ctx>) -> IRGenResult<'ir, 'src> {
-        // See if function has been defined, if not, generate prototype
-        // to get the LLVM function value.
-        let fn_val = match context.module.get_function(&self.proto.get_name()) {
-            Some(fn_val) => fn_val,
-            None => self.proto.codegen(context)?.into_function_value(),
+        if context.module.get_global(self.name).is_some()
+            || context.module.get_function(self.name).is_some()
+        {
+            return Err(BackendError::MultipleGlobalDefs(self.name));
+        }
+
+        let var_type = self.var_type.unwrap_or(Type::F64);
+        let llvm_type = context.llvm_type(var_type);
+
+        // The initial value, both as an LLVM constant and as it would sit in memory
+        let (init_val, init_bits) = match (self.init.as_deref(), var_type) {
+            (None, _) => (llvm_type.const_zero(), 0),
+
+            (Some(ASTExpr::NumberExpr(num)), Type::F64) => (
+                context.context.f64_type().const_float(*num).as_basic_value_enum(),
+                num.to_bits(),
+            ),
+
+            (Some(ASTExpr::IntegerExpr(int)), Type::I64) => (
+                context.context.i64_type().const_int(*int as u64, true).as_basic_value_enum(),
+                *int as u64,
+            ),
+
+            (Some(ASTExpr::BoolExpr(bool_val)), Type::F64) => {
+                let num = if *bool_val { 1.0 } else { 0.0 };
+
+                (
+                    context.context.f64_type().const_float(num).as_basic_value_enum(),
+                    f64::to_bits(num),
+                )
+            }
+
+            // An i1 is stored as a single byte
+            (Some(ASTExpr::BoolExpr(bool_val)), Type::Bool) => (
+                context.context.bool_type().const_int(*bool_val as u64, false).as_basic_value_enum(),
+                u64::from_ne_bytes([*bool_val as u8, 0, 0, 0, 0, 0, 0, 0]),
+            ),
+
+            _ => return Err(BackendError::NonConstantGlobal(self.name)),
         };
 
-        // To make sure we aren't defining functions twice, I just check if it
-        // has no entry basic block, if it does, then propogate error.
-        if fn_val.get_first_basic_block().is_some() {
-            return Err(BackendError::MultipleFunctionDefs(self.proto.get_name()));
+        let global = context.module.add_global(llvm_type, None, self.name);
+
+        if context.jit {
+            context
+                .global_slots
+                .borrow_mut()
+                .insert(self.name.to_string(), Box::new(Cell::new(init_bits)));
+        } else {
+            global.set_initializer(&init_val);
         }
 
-        // This sets our cursor for creating instructions to the basic block
-        // for this function
-        let bb_entry = context.context.append_basic_block(fn_val, "entry");
-        context.builder.position_at_end(bb_entry);
+        context
+            .globals
+            .borrow_mut()
+            .insert(self.name.to_string(), (global.as_pointer_value(), llvm_type));
 
-        // Update the symbol table with the args names and references
-        // to their LLVM values.
-        context.sym_table.borrow_mut().clear();
-        for param in fn_val.get_params() {
-            // TODO: Change the named value key to a non-owned CStr reference
-            // so I am not copying and cloning to Rust Strings
-            let owned_str = param
-                .into_float_value()
-                .get_name()
-                .to_str()
-                .unwrap()
-                .to_string();
+        Ok(global.as_pointer_value().as_any_value_enum())
+    }
+}
 
-            // The mutable variables chapter, chapter 7, our passed arguments may be mutated.
-            // Store them all on the stack and allow the function inside to mutate them
-            // as memory objects
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Allocate the argument to stack.
-            let param_ptr = context.create_entry_block_alloca(fn_val, &owned_str);
+    use inkwell::targets::InitializationConfig;
 
-            // Store the value of this paramter to it's stack copy
-            context
-                .builder
-                .build_store(param_ptr, param)
-                .expect("FATAL: LLVM failed to build store instruction");
+    use crate::session::{Session, SessionError};
 
-            // Add it to scope
-            context.sym_table.borrow_mut().insert(owned_str, param_ptr);
-        }
+    #[test]
+    fn libraries_are_loaded_or_recorded() {
+        Target::initialize_native(&InitializationConfig::default()).unwrap();
+        let context = Context::create();
 
-        // Generate code for the body of the function as an ASTExpr node
-        let ir_body = self.body.codegen(context)?;
+        // Compiling ahead of time only writes the library down, for the linker
+        let llvm_ctx = LLVMContext::new(&context, &CodegenOptions { jit: false, ..CodegenOptions::default() });
+        llvm_ctx.link_library(Path::new("/nowhere/libshapes.so")).unwrap();
 
-        // We need to add a return at the end so we return from functions we call
-        context
-            .builder
-            .build_return(Some(&ir_body.into_float_value() as &dyn BasicValue))
-            .expect("FATAL: LLVM failed to build a return!");
+        let ir = llvm_ctx.module.print_to_string().to_string();
+        assert!(ir.contains("!llvm.dependent-libraries"));
+        assert!(ir.contains("!\"/nowhere/libshapes.so\""));
 
-        if !fn_val.verify(true) {
-            return Err(BackendError::FailedToVerifyFunc(self.proto.get_name()));
+        let jit_ctx = LLVMContext::new(&context, &CodegenOptions::default());
+        assert!(matches!(
+            jit_ctx.link_library(Path::new("/nowhere/libshapes.so")),
+            Err(BackendError::FailedToLoadLibrary(_))
+        ));
+    }
+
+    #[test]
+    fn integer_division_faults_are_errors() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session.load("def div(a: i64 b: i64) -> i64 a / b; def by_zero() -> i64 1 / 0").unwrap();
+
+        for expr in ["by_zero()", "div(1, 0)", "div(0 - 4611686018427387904 - 4611686018427387904, 0 - 1)"] {
+            assert!(matches!(
+                session.eval(expr),
+                Err(SessionError::Backend(BackendError::Crashed(
+                    "an arithmetic error, like an integer division by zero"
+                )))
+            ));
         }
 
-        Ok(fn_val.as_any_value_enum())
+        assert_eq!(session.eval("div(7, 0 - 2)").unwrap(), -3.0);
+    }
+
+    #[test]
+    fn functions_are_called_from_rust() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session.define("def square(x) x * x").unwrap();
+        let square = unsafe { session.function::<unsafe extern "C" fn(f64) -> f64>("square") }.unwrap();
+        assert_eq!(unsafe { square.call(3.0) }, 9.0);
+
+        // Defined after the copy square came from was compiled
+        session.define("def cube(x: i64) -> i64 x * x * x").unwrap();
+        let cube = unsafe { session.function::<unsafe extern "C" fn(i64) -> i64>("cube") }.unwrap();
+        assert_eq!(unsafe { cube.call(2) }, 8);
+        assert_eq!(unsafe { square.call(4.0) }, 16.0);
+
+        assert!(matches!(
+            unsafe { session.function::<unsafe extern "C" fn() -> f64>("nothing") },
+            Err(SessionError::Backend(BackendError::UndefinedFunction("nothing")))
+        ));
+        assert_eq!(session.eval("square(5)").unwrap(), 25.0);
+    }
+
+    #[test]
+    fn definitions_replace_the_prelude() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session.load("def round(x: i64) -> i64 x * 2; def log(a b) a + b").unwrap();
+        assert_eq!(session.eval("round(3)").unwrap(), 6.0);
+        assert_eq!(session.eval("log(1, 2)").unwrap(), 3.0);
+
+        // Unless code defined before already calls the prelude's version
+        session.define("def cuberoot(x) cbrt(x)").unwrap();
+        assert!(matches!(
+            session.define("def cbrt(x: i64) -> i64 x"),
+            Err(SessionError::Backend(BackendError::ConflictingDeclaration(_)))
+        ));
+        assert_eq!(session.eval("cuberoot(8)").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn declarations_nothing_uses_yet_survive_the_passes() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        // The passes run after one is defined, before anything uses the global or the extern
+        session.load("global count = 2; extern fabs(x); def one() 1").unwrap();
+
+        assert_eq!(session.eval("count + fabs(0 - 1) + one()").unwrap(), 4.0);
+    }
+
+    #[test]
+    fn literals_the_passes_deleted_are_left_alone() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        // Once first is inlined, nothing uses "y" and the passes delete it
+        session.define("def first(a: str b: str) -> str a").unwrap();
+
+        assert_eq!(session.eval("first(\"x\", \"y\") == \"x\"").unwrap(), 1.0);
+        assert_eq!(session.eval("first(\"x\", \"y\") == \"y\"").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn running_out_of_fuel_stops_the_expression_where_it_is() {
+        let context = Context::create();
+        let options = CodegenOptions { fuel: Some(1000), ..CodegenOptions::default() };
+        let mut session = Session::with_options(&context, options, DEFAULT_PASSES).unwrap();
+
+        session.load("global count = 0; def spin(n: i64) -> str spin(n + 1)").unwrap();
+
+        // One unit for the expression, then one each time the loop goes around again
+        assert!(matches!(
+            session.eval("for i = 0, 1 in count = count + 1"),
+            Err(SessionError::Backend(BackendError::OutOfFuel(1000)))
+        ));
+        assert_eq!(session.eval("count").unwrap(), 1000.0);
+
+        assert!(matches!(
+            session.eval("while 1 > 0 do count = count + 1"),
+            Err(SessionError::Backend(BackendError::OutOfFuel(1000)))
+        ));
+        assert!(matches!(
+            session.eval("spin(0) == \"\""),
+            Err(SessionError::Backend(BackendError::OutOfFuel(1000)))
+        ));
+
+        // The fuel is topped up for every expression
+        assert_eq!(session.eval("for i = 0, i < 10 in count = 0").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn running_out_of_time_stops_the_expression() {
+        let context = Context::create();
+        let options = CodegenOptions { timeout: Some(Duration::from_millis(50)), ..CodegenOptions::default() };
+        let mut session = Session::with_options(&context, options, DEFAULT_PASSES).unwrap();
+
+        assert!(matches!(
+            session.eval("for i = 0, 1 in 0"),
+            Err(SessionError::Backend(BackendError::TimedOut(_)))
+        ));
+        assert_eq!(session.eval("1 + 2").unwrap(), 3.0);
     }
 }
@@ -1 +1,2 @@
+pub mod host;
 pub mod llvm_backend;
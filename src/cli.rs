@@ -1,32 +1,38 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::{
     builder::{OsStr, PossibleValue},
-    Parser, ValueEnum,
+    Parser, Subcommand, ValueEnum,
 };
 use inkwell;
 
+use crate::backend::llvm_backend::CodegenOptions;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
-    /// A positional file containing Kaleidoscope code to compile to object/assembly, if not given, starts interpreter instead
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// A positional file containing Kaleidoscope code to compile to object/assembly, if not given, starts interpreter instead. Files ending in .json or .sexp are read as syntax trees (see --emit)
     pub file: Option<PathBuf>,
 
     /// Specifies a non-native target to compile for, can be any one of the CPUs listed using "llc --version", or string parseable as LLVMTargetTriple
     #[arg(long)]
     pub target: Option<String>,
 
-    /// What optimization level to pass to LLVM
+    /// What optimization level to pass to LLVM, picks the passes run as well (see --print-passes)
     #[arg(short = 'O', long, value_enum, default_value = OptLevel::O2)]
     pub opt_level: OptLevel,
 
-    /// Comma separated list of LLVM passes (use opt for a list, also see https://www.llvm.org/docs/Passes.html)
-    #[arg(
-        short,
-        long,
-        default_value = "instcombine,reassociate,gvn,simplifycfg,mem2reg"
-    )]
-    pub passes: String,
+    /// Comma separated list of LLVM passes to run instead of the ones -O picks, in the syntax of opt -passes (also see https://www.llvm.org/docs/Passes.html)
+    #[arg(short, long)]
+    pub passes: Option<String>,
+
+    /// Prints the passes that would be run, as --passes takes them, and exits. LLVM's own pipelines are printed by name, like default<O2>
+    #[arg(long = "print-passes", alias = "print-pipeline")]
+    pub print_passes_p: bool,
 
     /// When compiling a file, specifies an output file to write to
     #[arg(short, long, default_value = "a.out")]
@@ -36,6 +42,26 @@ pub struct Cli {
     #[arg(short = 'S', long = "assembly")]
     pub asm_p: bool,
 
+    /// A shared library for extern declarations to call into, can be given more than once. When compiling a file, nothing is linked, the object file only records the library as a dependency (lld links against it, other linkers need to be told)
+    #[arg(long = "link-lib", value_name = "PATH")]
+    pub link_libs: Vec<PathBuf>,
+
+    /// Check every array index against the length of the array, aborting with a message when out of bounds
+    #[arg(long = "bounds-checks")]
+    pub bounds_checks_p: bool,
+
+    /// When interpreting, how many function calls and loop iterations an expression may make before it is stopped
+    #[arg(long, value_name = "STEPS")]
+    pub fuel: Option<u64>,
+
+    /// When interpreting, how many milliseconds an expression may run for before it is stopped
+    #[arg(long, value_name = "MS")]
+    pub timeout: Option<u64>,
+
+    /// When compiling a file, prints its syntax tree to stdout instead, in a form other tools can read
+    #[arg(long, value_enum)]
+    pub emit: Option<Emit>,
+
     /// When interpreting, prints out AST to stdout after every line entered into interpreter
     #[arg(long = "inspect-tree")]
     pub inspect_tree_p: bool,
@@ -49,17 +75,94 @@ pub struct Cli {
     pub inspect_asm_p: bool,
 }
 
+impl Cli {
+    // What --passes says to run, otherwise LLVM's own pipeline for the optimization level
+    pub fn passes(&self) -> &str {
+        self.passes.as_deref().unwrap_or(self.opt_level.passes())
+    }
+
+    // Compiling a file generates code to write out, otherwise it's for the interpreter
+    pub fn codegen_options(&self) -> CodegenOptions {
+        CodegenOptions {
+            target: self.target.clone(),
+            opt_level: self.opt_level.into(),
+            jit: self.file.is_none(),
+            bounds_checks: self.bounds_checks_p,
+            fuel: self.fuel,
+            timeout: self.timeout.map(Duration::from_millis),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Formats Kaleidoscope source files in place, or stdin to stdout when no files are given
+    Fmt {
+        /// Don't write anything, only list the files that aren't formatted, failing if there are any
+        #[arg(long)]
+        check: bool,
+
+        /// Files to format
+        files: Vec<PathBuf>,
+    },
+
+    /// Starts a language server speaking LSP over stdin and stdout, for editors to show errors, jump to definitions and complete names
+    Lsp,
+
+    /// Classifies every token and comment of a Kaleidoscope file (or stdin, when no file is given) for syntax highlighting
+    Highlight {
+        /// What to print the classified source as
+        #[arg(long, value_enum, default_value = "html")]
+        format: HighlightFormat,
+
+        /// File to highlight
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum HighlightFormat {
+    /// Every token and comment, with where it is and its class
+    Json,
+    /// The source in a <pre>, every token and comment in a <span> with a class to style
+    Html,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Emit {
+    /// The syntax tree as JSON
+    AstJson,
+    /// The syntax tree as an S-expression
+    AstSexp,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OptLevel {
     O0,
     O1,
     O2,
     O3,
+    Os,
+    Oz,
+}
+
+impl OptLevel {
+    // The pipelines clang runs for each level
+    pub fn passes(&self) -> &'static str {
+        match self {
+            OptLevel::O0 => "default<O0>",
+            OptLevel::O1 => "default<O1>",
+            OptLevel::O2 => "default<O2>",
+            OptLevel::O3 => "default<O3>",
+            OptLevel::Os => "default<Os>",
+            OptLevel::Oz => "default<Oz>",
+        }
+    }
 }
 
 impl ValueEnum for OptLevel {
     fn value_variants<'a>() -> &'a [Self] {
-        &[OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3]
+        &[OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3, OptLevel::Os, OptLevel::Oz]
     }
 
     fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
@@ -68,6 +171,8 @@ impl ValueEnum for OptLevel {
             OptLevel::O1 => PossibleValue::new("1").help("Less optimization"),
             OptLevel::O2 => PossibleValue::new("2").help("Default optimization"),
             OptLevel::O3 => PossibleValue::new("3").help("Aggressive optimization"),
+            OptLevel::Os => PossibleValue::new("s").help("Default optimization, favoring smaller code"),
+            OptLevel::Oz => PossibleValue::new("z").help("Default optimization, making code as small as possible"),
         })
     }
 }
@@ -79,17 +184,20 @@ impl Into<OsStr> for OptLevel {
             OptLevel::O1 => "1".into(),
             OptLevel::O2 => "2".into(),
             OptLevel::O3 => "3".into(),
+            OptLevel::Os => "s".into(),
+            OptLevel::Oz => "z".into(),
         }
     }
 }
 
-// Convert to a inkwell optimization level, reflection of an actual LLVM level
+// Convert to a inkwell optimization level, reflection of an actual LLVM level. Code generation
+// has no size levels, like clang, -Os and -Oz only change the passes run on the IR.
 impl Into<inkwell::OptimizationLevel> for OptLevel {
     fn into(self) -> inkwell::OptimizationLevel {
         match self {
             OptLevel::O0 => inkwell::OptimizationLevel::None,
             OptLevel::O1 => inkwell::OptimizationLevel::Less,
-            OptLevel::O2 => inkwell::OptimizationLevel::Default,
+            OptLevel::O2 | OptLevel::Os | OptLevel::Oz => inkwell::OptimizationLevel::Default,
             OptLevel::O3 => inkwell::OptimizationLevel::Aggressive,
         }
     }
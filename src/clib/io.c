@@ -4,7 +4,65 @@
 #define DLLEXPORT
 #endif
 
+#include <math.h>
+#include <stdint.h>
 #include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+#ifndef _WIN32
+#include <setjmp.h>
+#include <signal.h>
+#endif
+
+// Kaleidoscope strings, passed by value, the characters are not null terminated.
+// Must match the { ptr, i64 } layout used by the backend for the str type.
+typedef struct {
+    const char* ptr;
+    int64_t len;
+} kstr;
+
+// Kaleidoscope arrays, the length followed by the elements themselves.
+// Must match the { i64, [0 x double] } layout used by the backend for the [f64] type.
+typedef struct {
+    int64_t len;
+    double data[];
+} karray;
+
+// Everything a guarded call can end with, matched by guarded_call in src/runtime.rs
+enum guarded_outcome {
+    GUARDED_FINISHED,
+    GUARDED_STACK_OVERFLOW,
+    GUARDED_MEMORY_FAULT,
+    GUARDED_ABORTED,
+    GUARDED_ARITHMETIC,
+    GUARDED_TRAPPED,
+    GUARDED_HOST_PANICKED,
+    GUARDED_OUT_OF_FUEL,
+    GUARDED_TIMED_OUT,
+    GUARDED_OUT_OF_MEMORY,
+};
+
+extern DLLEXPORT void kaleidrs_trap(int outcome);
+
+// The double as an int64_t, NaN as zero and anything out of range as the nearest end,
+// where a plain cast would be undefined behavior
+static int64_t to_int64(double d) {
+    if (d != d) {
+        return 0;
+    }
+
+    if (d <= -0x1p63) {
+        return INT64_MIN;
+    }
+
+    if (d >= 0x1p63) {
+        return INT64_MAX;
+    }
+
+    return (int64_t)d;
+}
 
 // putchard - putchar that takes a double as ascii code, prints it with a newline and returns 0.
 extern DLLEXPORT double putchard(double X) {
@@ -17,4 +75,365 @@ extern DLLEXPORT double putchard(double X) {
 extern DLLEXPORT double printd(double d) {
     printf("\"%f\"\n", d);
     return 0;
-}
\ No newline at end of file
+}
+
+// prints, prints the string to stdout followed by a newline
+extern DLLEXPORT double prints(kstr s) {
+    fwrite(s.ptr, 1, s.len, stdout);
+    fputc(10, stdout);
+    return 0;
+}
+
+// writechar, putchard without the newline, to stdout like the other write functions
+extern DLLEXPORT double writechar(double X) {
+    fputc((unsigned char)to_int64(X), stdout);
+    return 0;
+}
+
+// writed, prints the double value to stdout as short as it can be written, without a newline
+extern DLLEXPORT double writed(double d) {
+    printf("%.15g", d);
+    return 0;
+}
+
+// writefixed, prints the double value to stdout with the given number of digits after the point
+extern DLLEXPORT double writefixed(double d, double digits) {
+    // NaN compares false, and gets no digits like a negative number does
+    int precision = !(digits > 0) ? 0 : digits > 30 ? 30 : (int)digits;
+
+    printf("%.*f", precision, d);
+    return 0;
+}
+
+// writes, prints the string to stdout, without a newline
+extern DLLEXPORT double writes(kstr s) {
+    fwrite(s.ptr, 1, s.len, stdout);
+    return 0;
+}
+
+// newline, ends the line the write functions have been writing
+extern DLLEXPORT double newline(void) {
+    fputc(10, stdout);
+    return 0;
+}
+
+// kaleidrs_flush_output, called after every top level expression the JIT runs. C buffers
+// its stdout apart from Rust's, so what the write functions left in the buffer is written
+// out before whatever the REPL prints next.
+extern DLLEXPORT void kaleidrs_flush_output(void) {
+    fflush(stdout);
+}
+
+// readd, reads a number from stdin, NaN when there isn't one (check with x != x)
+extern DLLEXPORT double readd(void) {
+    double d;
+
+    // Whatever was written as a prompt shows up before waiting for input
+    fflush(stdout);
+
+    if (scanf("%lf", &d) != 1) {
+        return NAN;
+    }
+
+    return d;
+}
+
+// clockd, the wall-clock time in seconds since the Unix epoch, with fractions of a second
+extern DLLEXPORT double clockd(void) {
+    struct timespec now;
+    timespec_get(&now, TIME_UTC);
+
+    return (double)now.tv_sec + now.tv_nsec / 1e9;
+}
+
+// Random numbers are generated by splitmix64, the same seed gives the same numbers on
+// every platform, unlike rand
+static uint64_t rand_state = 0x9E3779B97F4A7C15;
+
+// seedrand, starts the random numbers over from seed, a whole number, NaN is 0
+extern DLLEXPORT double seedrand(double seed) {
+    rand_state = (uint64_t)to_int64(seed);
+    return 0;
+}
+
+// randd, a random number from 0 up to but not including 1
+extern DLLEXPORT double randd(void) {
+    uint64_t z = (rand_state += 0x9E3779B97F4A7C15);
+
+    z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9;
+    z = (z ^ (z >> 27)) * 0x94D049BB133111EB;
+    z = z ^ (z >> 31);
+
+    return (z >> 11) * 0x1.0p-53;
+}
+
+// abortmsg, ends the program with a message on stderr, the return type is only there
+// so it can be called wherever a value is expected
+extern DLLEXPORT double abortmsg(kstr message) {
+    fflush(stdout);
+    fputs("abort: ", stderr);
+    fwrite(message.ptr, 1, message.len, stderr);
+    fputc(10, stderr);
+    abort();
+}
+
+// Stops the program, or the guarded call it's running in, when there's no memory for a
+// string or an array of the size asked for, rather than writing past what there is
+static void out_of_memory(const char* what, uint64_t len) {
+    fflush(stdout);
+    fprintf(stderr, "out of memory for %s of length %llu\n", what, (unsigned long long)len);
+    kaleidrs_trap(GUARDED_OUT_OF_MEMORY);
+}
+
+// kstr_concat, called by the backend for "a" + "b", allocates a new string.
+// There is no garbage collector, the memory lives until the program exits.
+extern DLLEXPORT kstr kstr_concat(kstr a, kstr b) {
+    // Neither length is negative, so their sum fits in a uint64_t, if not an int64_t
+    uint64_t len = (uint64_t)a.len + (uint64_t)b.len;
+
+    if (len > INT64_MAX || len > SIZE_MAX) {
+        out_of_memory("a string", len);
+    }
+
+    // malloc(0) may give back NULL
+    char* buf = malloc(len > 0 ? len : 1);
+
+    if (buf == NULL) {
+        out_of_memory("a string", len);
+    }
+
+    memcpy(buf, a.ptr, a.len);
+    memcpy(buf + a.len, b.ptr, b.len);
+
+    return (kstr){ buf, (int64_t)len };
+}
+
+// kstr_compare, called by the backend for ==, !=, < and > on strings,
+// negative, zero or positive like strcmp.
+extern DLLEXPORT int64_t kstr_compare(kstr a, kstr b) {
+    int64_t shortest = a.len < b.len ? a.len : b.len;
+    int cmp = memcmp(a.ptr, b.ptr, shortest);
+
+    if (cmp != 0) {
+        return cmp;
+    }
+
+    return a.len - b.len;
+}
+
+// kaleidrs_division_fault, called by the backend for an integer division by zero, or one
+// that overflows, which LLVM leaves undefined rather than trapping like the hardware
+extern DLLEXPORT void kaleidrs_division_fault(int64_t left, int64_t right) {
+    fflush(stdout);
+    fprintf(stderr, "integer division of %lld by %lld\n", (long long)left, (long long)right);
+    kaleidrs_trap(GUARDED_ARITHMETIC);
+}
+
+// karray_new, called by the backend for array literals, allocates len elements set to fill.
+// Like strings, arrays are never freed.
+extern DLLEXPORT karray* karray_new(int64_t len, double fill) {
+    if (len < 0) {
+        len = 0;
+    }
+
+    // The size in bytes has to fit in a size_t, it would wrap around to a small one
+    if ((uint64_t)len > (SIZE_MAX - sizeof(karray)) / sizeof(double)) {
+        out_of_memory("an array", len);
+    }
+
+    karray* array = calloc(1, sizeof(karray) + (size_t)len * sizeof(double));
+
+    if (array == NULL) {
+        out_of_memory("an array", len);
+    }
+
+    array->len = len;
+
+    for (int64_t i = 0; i < array->len; i++) {
+        array->data[i] = fill;
+    }
+
+    return array;
+}
+
+// karray_out_of_bounds, called by the backend when --bounds-checks catches a bad index
+extern DLLEXPORT void karray_out_of_bounds(int64_t index, int64_t len) {
+    fprintf(stderr, "index %lld out of bounds for array of length %lld\n", (long long)index, (long long)len);
+    abort();
+}
+
+#ifndef _WIN32
+// Big enough for the handler, which runs here, the stack it was on may have no room left
+#define GUARD_STACK_SIZE (64 * 1024)
+
+static const int guarded_signals[] = {SIGSEGV, SIGBUS, SIGILL, SIGTRAP, SIGFPE, SIGABRT};
+#define GUARDED_SIGNAL_COUNT (sizeof(guarded_signals) / sizeof(guarded_signals[0]))
+
+// The handlers in place before kaleidrs_guard_install, Rust's own among them
+static struct sigaction previous_actions[GUARDED_SIGNAL_COUNT];
+
+// Where the guarded call running on this thread jumps back to, NULL when there is none,
+// or when it called out to a Rust function (see kaleidrs_guard_suspend)
+static _Thread_local sigjmp_buf *guard_env;
+static _Thread_local char *guard_stack_top;
+
+// A signal that isn't ours to catch goes to the handler that was there before, as if
+// we'd never been installed. The default for all of them is to end the process, the
+// signal is blocked while it's handled, so it goes off as soon as this returns.
+static void pass_on(int sig, siginfo_t *info, void *context) {
+    struct sigaction *previous = NULL;
+
+    for (size_t i = 0; i < GUARDED_SIGNAL_COUNT; i++) {
+        if (guarded_signals[i] == sig) {
+            previous = &previous_actions[i];
+        }
+    }
+
+    if (previous->sa_flags & SA_SIGINFO) {
+        previous->sa_sigaction(sig, info, context);
+    } else if (previous->sa_handler == SIG_DFL) {
+        sigaction(sig, previous, NULL);
+        raise(sig);
+    } else if (previous->sa_handler != SIG_IGN) {
+        previous->sa_handler(sig);
+    }
+}
+
+// Jumps straight back to kaleidrs_guarded_call, leaving the code that was running behind.
+// A fault just below the stack kaleidrs_guarded_call is on is taken as an overflow.
+static void guard_handler(int sig, siginfo_t *info, void *context) {
+    if (guard_env == NULL) {
+        pass_on(sig, info, context);
+        return;
+    }
+
+    char *addr = (char *)info->si_addr;
+    int outcome = GUARDED_TRAPPED;
+
+    switch (sig) {
+    case SIGSEGV:
+    case SIGBUS:
+        outcome = addr < guard_stack_top && guard_stack_top - addr < (1L << 30)
+            ? GUARDED_STACK_OVERFLOW
+            : GUARDED_MEMORY_FAULT;
+        break;
+    case SIGABRT:
+        outcome = GUARDED_ABORTED;
+        break;
+    case SIGFPE:
+        outcome = GUARDED_ARITHMETIC;
+        break;
+    }
+
+    siglongjmp(*guard_env, outcome);
+}
+
+// The handler runs on a stack of its own, every thread needs one. Rust gives the threads
+// it starts one already, the stack of any other thread is left for as long as it runs.
+static void ensure_signal_stack(void) {
+    stack_t current;
+    sigaltstack(NULL, &current);
+
+    if (current.ss_flags & SS_DISABLE) {
+        stack_t guard_stack;
+
+        guard_stack.ss_sp = malloc(GUARD_STACK_SIZE);
+        guard_stack.ss_size = GUARD_STACK_SIZE;
+        guard_stack.ss_flags = 0;
+        sigaltstack(&guard_stack, NULL);
+    }
+}
+#endif
+
+// kaleidrs_guard_install, puts the handler in place for the whole process, called once
+// before the first guarded call (see src/runtime.rs). Signals on threads that aren't in
+// a guarded call are passed on to the handlers that were there before.
+extern DLLEXPORT void kaleidrs_guard_install(void) {
+#ifndef _WIN32
+    struct sigaction action;
+
+    memset(&action, 0, sizeof(action));
+    action.sa_sigaction = guard_handler;
+    action.sa_flags = SA_SIGINFO | SA_ONSTACK;
+    sigemptyset(&action.sa_mask);
+
+    for (size_t i = 0; i < GUARDED_SIGNAL_COUNT; i++) {
+        sigaction(guarded_signals[i], &action, &previous_actions[i]);
+    }
+#endif
+}
+
+// kaleidrs_guarded_call, called by the REPL to run a top level expression, so a crash in it
+// is reported rather than taking the whole process down. Returns GUARDED_FINISHED with the
+// value of the expression in result, or what stopped it. Guarded calls can be nested, one
+// made from a Rust function the expression called goes back to the outer one once done.
+//
+// Whatever the expression was in the middle of is abandoned, and memory it allocated is
+// leaked. Rust functions it calls are never jumped out of, see kaleidrs_guard_suspend.
+extern DLLEXPORT int kaleidrs_guarded_call(double (*function)(void), double *result) {
+#ifdef _WIN32
+    *result = function();
+    return GUARDED_FINISHED;
+#else
+    sigjmp_buf env;
+    sigjmp_buf *outer_env = guard_env;
+    char *outer_stack_top = guard_stack_top;
+    char stack_marker;
+
+    ensure_signal_stack();
+    guard_stack_top = &stack_marker;
+
+    // Saves the signal mask, the signal being handled is blocked until the jump restores it
+    int outcome = sigsetjmp(env, 1);
+
+    if (outcome == GUARDED_FINISHED) {
+        guard_env = &env;
+        *result = function();
+    }
+
+    guard_env = outer_env;
+    guard_stack_top = outer_stack_top;
+
+    return outcome;
+#endif
+}
+
+// kaleidrs_trap, stops the guarded call running on this thread with the given outcome, as
+// if it had crashed. Outside of one there is nothing to go back to, and the process ends.
+extern DLLEXPORT void kaleidrs_trap(int outcome) {
+#ifndef _WIN32
+    if (guard_env != NULL) {
+        siglongjmp(*guard_env, outcome);
+    }
+#else
+    (void)outcome;
+#endif
+    abort();
+}
+
+// kaleidrs_out_of_fuel, called by the backend's fuel check with the fuel it found, none
+// left. The timeout takes the fuel far below zero, which no fuel limit ever gets to.
+extern DLLEXPORT void kaleidrs_out_of_fuel(int64_t fuel) {
+    kaleidrs_trap(fuel <= INT64_MIN / 2 ? GUARDED_TIMED_OUT : GUARDED_OUT_OF_FUEL);
+}
+
+// kaleidrs_guard_suspend, called before the guarded code calls into Rust, which can't be
+// jumped out of. A crash in there is a crash, as if there were no guard, until the
+// guard returned is given back to kaleidrs_guard_resume.
+extern DLLEXPORT void *kaleidrs_guard_suspend(void) {
+#ifdef _WIN32
+    return NULL;
+#else
+    sigjmp_buf *env = guard_env;
+    guard_env = NULL;
+    return env;
+#endif
+}
+
+extern DLLEXPORT void kaleidrs_guard_resume(void *env) {
+#ifdef _WIN32
+    (void)env;
+#else
+    guard_env = env;
+#endif
+}
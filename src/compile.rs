@@ -4,51 +4,71 @@ use inkwell::targets::FileType;
 
 use crate::backend::llvm_backend::{LLVMCodeGen, LLVMContext};
 use crate::{
-    cli::Cli,
+    cli::{Cli, Emit},
     frontend::{
+        ast::Item,
         lexer::{Lex, Token},
-        parser::{parse_definition, parse_extern, parse_top_level_expr},
+        parser::parse_item,
+        resolve::{report_semantic_errors, Resolver},
+        serialize::{program_from_json_str, program_from_sexp_str, program_to_json, program_to_sexp},
+        simplify::simplify,
+        typecheck::TypeChecker,
     },
 };
 
 pub fn compile_src<'src>(src_code: &'src str, cli: &Cli) -> Result<(), Box<dyn Error + 'src>> {
     let ctx = inkwell::context::Context::create();
-    let llvm_ctx = LLVMContext::new(&ctx, cli);
+    let llvm_ctx = LLVMContext::new(&ctx, &cli.codegen_options());
+    let mut type_checker = TypeChecker::new();
 
-    let mut tokens = src_code.lex().peekable();
+    let mut items = read_items(src_code, cli)?;
 
-    while let Some(token) = tokens.peek() {
-        match token {
-            Token::Extern => match parse_extern(&mut tokens) {
-                Ok(ast) => {
-                    ast.codegen(&llvm_ctx)?;
-                }
-                Err(e) => eprintln!("Error: {}", e),
-            },
-
-            Token::FuncDef => match parse_definition(&mut tokens) {
-                Ok(ast) => {
-                    ast.codegen(&llvm_ctx)?;
-                }
-                Err(e) => eprintln!("Error: {}", e),
-            },
-
-            // Eat semicolons and move on
-            Token::Semicolon => {
-                tokens.next();
+    match cli.emit {
+        Some(Emit::AstJson) => {
+            println!("{}", serde_json::to_string_pretty(&program_to_json(&items))?);
+            return Ok(());
+        }
+
+        Some(Emit::AstSexp) => {
+            print!("{}", program_to_sexp(&items));
+            return Ok(());
+        }
+
+        None => {}
+    }
+
+    for library in &cli.link_libs {
+        llvm_ctx.link_library(library)?;
+    }
+
+    // Every name resolution error in the program is reported before any code is generated
+    if let Err(errors) = Resolver::new().resolve_program(&items) {
+        report_semantic_errors(src_code, &errors);
+        return Err(format!("{} semantic error(s) found", errors.len()).into());
+    }
+
+    for item in items.iter_mut() {
+        match item {
+            Item::Extern(ast) => {
+                type_checker.check_prototype(ast);
+                ast.codegen(&llvm_ctx)?;
             }
 
-            _top_level_expr => match parse_top_level_expr(&mut tokens) {
-                Ok(ast) => {
-                    ast.codegen(&llvm_ctx)?;
-                }
-                Err(e) => eprintln!("Error: {}", e),
-            },
+            Item::Function(ast) => {
+                type_checker.check_function(ast)?;
+                simplify(ast);
+                ast.codegen(&llvm_ctx)?;
+            }
+
+            Item::Global(ast) => {
+                type_checker.check_global(ast)?;
+                ast.codegen(&llvm_ctx)?;
+            }
         }
     }
 
     // Run the optimization passes on IR in module, output to object/assembly file
-    llvm_ctx.run_passes(&cli.passes);
+    llvm_ctx.run_passes(cli.passes())?;
 
     if cli.asm_p {
         llvm_ctx.compile(&cli.output.as_path(), FileType::Assembly);
@@ -58,3 +78,35 @@ pub fn compile_src<'src>(src_code: &'src str, cli: &Cli) -> Result<(), Box<dyn E
 
     Ok(())
 }
+
+// Syntax trees written by --emit (or another tool) are read back instead of parsed
+fn read_items<'src>(src_code: &'src str, cli: &Cli) -> Result<Vec<Item<'src>>, Box<dyn Error + 'src>> {
+    let extension = cli.file.as_ref().and_then(|file| file.extension());
+
+    match extension.and_then(|ext| ext.to_str()) {
+        Some("json") => Ok(program_from_json_str(src_code)?),
+        Some("sexp") => Ok(program_from_sexp_str(src_code)?),
+        _ => Ok(parse_items(src_code)),
+    }
+}
+
+// Parses the whole source file up front, top level expressions become anonymous functions
+fn parse_items(src_code: &str) -> Vec<Item> {
+    let mut tokens = src_code.lex().peekable();
+    let mut items = vec![];
+
+    while let Some(token) = tokens.peek() {
+        // Eat semicolons and move on
+        if let Token::Semicolon = token {
+            tokens.next();
+            continue;
+        }
+
+        match parse_item(&mut tokens) {
+            Ok(item) => items.push(item),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+
+    items
+}
@@ -0,0 +1,69 @@
+use std::fs::{read_to_string, write};
+use std::io::{read_to_string as read_all, stdin};
+use std::path::PathBuf;
+use std::process::exit;
+
+use crate::frontend::format::format_source;
+
+// Driver for `kaleidrs fmt`. Without files, stdin is formatted to stdout, otherwise
+// every file is rewritten in place, unless checking, where nothing is written and
+// the files that would change are listed instead. A file that doesn't parse is left
+// as it is. Exits with 1 if any file failed to format, or wasn't formatted when checking.
+pub fn driver(check: bool, files: &[PathBuf]) {
+    if files.is_empty() {
+        let src = read_all(stdin()).expect("Failed to read from stdin");
+
+        match format_source(&src) {
+            Ok(formatted) if check && formatted != src => {
+                eprintln!("<stdin> is not formatted");
+                exit(1);
+            }
+
+            Ok(_) if check => {}
+
+            Ok(formatted) => print!("{formatted}"),
+
+            Err(e) => {
+                eprintln!("<stdin>: Parser error: {}", e);
+                exit(1);
+            }
+        }
+
+        return;
+    }
+
+    let mut failed = false;
+
+    for file in files {
+        let Ok(src) = read_to_string(file) else {
+            eprintln!("{}: File not found, please make sure it exists!", file.display());
+            failed = true;
+            continue;
+        };
+
+        match format_source(&src) {
+            Ok(formatted) if formatted == src => {}
+
+            Ok(_) if check => {
+                println!("{}", file.display());
+                failed = true;
+            }
+
+            Ok(formatted) => {
+                if let Err(e) = write(file, formatted) {
+                    eprintln!("{}: Failed to write: {}", file.display(), e);
+                    failed = true;
+                }
+            }
+
+            Err(e) => {
+                eprintln!("{}: Parser error: {}", file.display(), e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
@@ -0,0 +1,536 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::iter;
+
+use crate::frontend::{
+    ast::{ASTExpr, Item, Prototype, Span, Type},
+    format::{params_str, prototype_str},
+    lexer::{Lex, Token},
+    parser::{keeping_precedence, parse_item, ParserError},
+    resolve::Resolver,
+    scope::ScopeStack,
+    typecheck::TypeChecker,
+};
+
+// What editor tooling (see the language server) wants to know about a source file:
+// every error in it, and every name defined in it along with every use of that name.
+//
+// Unlike the compiler, which gives up on a file at its first syntax error, the
+// analysis skips ahead to the next semicolon and keeps going, so one typo doesn't
+// hide every other error in the file, or every definition after it.
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub diagnostics: Vec<Diagnostic>,
+    pub symbols: Vec<Symbol>,
+    pub references: Vec<Reference>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Function,
+    Extern,
+    Operator,
+    Global,
+    Parameter,
+    Variable,
+}
+
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub span: Option<Span>, // Operators aren't kept as slices of the source, so we don't know where those are
+    pub detail: String,     // How it was declared, like "def fib(x)"
+    pub arity: Option<usize>,
+}
+
+// A use of a symbol, an index into Analysis::symbols
+#[derive(Debug, PartialEq)]
+pub struct Reference {
+    pub span: Span,
+    pub symbol: usize,
+}
+
+impl Analysis {
+    // The symbol defined or used at offset, the end of a name counts as in it,
+    // that's where the cursor is after typing one
+    pub fn symbol_at(&self, offset: usize) -> Option<usize> {
+        let contains = |span: &Span| span.start <= offset && offset <= span.end;
+
+        self.references
+            .iter()
+            .find(|reference| contains(&reference.span))
+            .map(|reference| reference.symbol)
+            .or_else(|| {
+                self.symbols
+                    .iter()
+                    .position(|symbol| symbol.span.as_ref().is_some_and(contains))
+            })
+    }
+
+    pub fn references_to(&self, symbol: usize) -> impl Iterator<Item = Span> + '_ {
+        self.references
+            .iter()
+            .filter(move |reference| reference.symbol == symbol)
+            .map(|reference| reference.span)
+    }
+}
+
+pub fn analyze(src: &str) -> Analysis {
+    analyze_with(src, |_| None)
+}
+
+// Same as analyze, with every item that gets through the frontend also handed to
+// generate, which can report what goes wrong with it after that (like the backend
+// failing to generate code for it). A source defining operators doesn't change the
+// precedence anything else is parsed with.
+pub fn analyze_with(src: &str, mut generate: impl FnMut(&mut Item) -> Option<String>) -> Analysis {
+    keeping_precedence(|| analyze_items(src, &mut generate))
+}
+
+fn analyze_items(src: &str, generate: &mut impl FnMut(&mut Item) -> Option<String>) -> Analysis {
+    let mut analysis = Analysis::default();
+    let mut items = parse_program(src, &mut analysis.diagnostics);
+
+    // Names first, the type checker rewrites bits of the tree
+    let mut indexer = Indexer::new(src);
+    indexer.index_program(&items);
+    analysis.symbols = indexer.symbols;
+    analysis.references = indexer.references;
+
+    // Then the same checks as the compiler, in the same order
+    let mut resolver = Resolver::new();
+    let mut type_checker = TypeChecker::new();
+
+    for (start, item) in items.iter_mut() {
+        if let Err(errors) = resolver.resolve_item(item) {
+            analysis.diagnostics.extend(errors.into_iter().map(|err| Diagnostic {
+                span: err.span(src).unwrap_or(*start),
+                message: err.to_string(),
+            }));
+
+            continue;
+        }
+
+        let checked = match item {
+            Item::Extern(proto) => {
+                type_checker.check_prototype(proto);
+                Ok(())
+            }
+            Item::Function(func) => type_checker.check_function(func).map(drop),
+            Item::Global(global) => type_checker.check_global(global).map(drop),
+        };
+
+        // Type errors don't say where they are, they go on the start of the item,
+        // and so do the errors after those
+        let failed = match checked {
+            Ok(()) => generate(item),
+            Err(err) => Some(err.to_string()),
+        };
+
+        if let Some(message) = failed {
+            analysis.diagnostics.push(Diagnostic {
+                span: *start,
+                message,
+            });
+        }
+    }
+
+    analysis
+}
+
+// Every item that parsed, along with where it starts. A syntax error is put on the
+// token the parser choked on, the last one it took from the lexer.
+fn parse_program<'src>(src: &'src str, diagnostics: &mut Vec<Diagnostic>) -> Vec<(Span, Item<'src>)> {
+    let mut lexer = src.lex();
+    let tokens = iter::from_fn(|| lexer.next_spanned()).collect::<Vec<_>>();
+
+    let span_of = |idx: usize| Span::of(src, tokens[idx].1).unwrap();
+    let end = Span {
+        start: src.len(),
+        end: src.len(),
+    };
+
+    let taken = Cell::new(0);
+    let mut stream = tokens
+        .iter()
+        .map(|&(token, _)| {
+            taken.set(taken.get() + 1);
+            token
+        })
+        .peekable();
+
+    let mut items = vec![];
+
+    while let Some(token) = stream.peek() {
+        if let Token::Semicolon = token {
+            stream.next();
+            continue;
+        }
+
+        // The token just peeked at starts the item
+        let start = span_of(taken.get() - 1);
+
+        match parse_item(&mut stream) {
+            Ok(item) => items.push((start, item)),
+
+            Err(err) => {
+                let span = match err {
+                    ParserError::UnexpectedEOI => end,
+                    _ => span_of(taken.get() - 1),
+                };
+
+                diagnostics.push(Diagnostic {
+                    span,
+                    message: err.to_string(),
+                });
+
+                // Skip the rest of the broken item
+                while stream.next_if(|t| !matches!(t, Token::Semicolon)).is_some() {}
+            }
+        }
+    }
+
+    items
+}
+
+struct Indexer<'src> {
+    src: &'src str,
+    symbols: Vec<Symbol>,
+    references: Vec<Reference>,
+    functions: HashMap<&'src str, usize>,
+    globals: HashMap<&'src str, usize>,
+    scope: ScopeStack<usize>,
+}
+
+fn annotated(name: &str, ty: Option<Type>) -> String {
+    match ty {
+        Some(ty) => format!("{name}: {ty}"),
+        None => name.to_string(),
+    }
+}
+
+impl<'src> Indexer<'src> {
+    fn new(src: &'src str) -> Self {
+        Self {
+            src,
+            symbols: vec![],
+            references: vec![],
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+            scope: ScopeStack::new(),
+        }
+    }
+
+    fn define(&mut self, name: &str, kind: SymbolKind, detail: String, arity: Option<usize>) -> usize {
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            kind,
+            span: Span::of(self.src, name),
+            detail,
+            arity,
+        });
+
+        self.symbols.len() - 1
+    }
+
+    fn refer(&mut self, name: &str, symbol: usize) {
+        if let Some(span) = Span::of(self.src, name) {
+            self.references.push(Reference { span, symbol });
+        }
+    }
+
+    // Local variables shadow globals, which shadow functions used as values
+    fn refer_variable(&mut self, name: &'src str) {
+        let symbol = self
+            .scope
+            .lookup(name)
+            .or_else(|| self.globals.get(name))
+            .or_else(|| self.functions.get(name))
+            .copied();
+
+        if let Some(symbol) = symbol {
+            self.refer(name, symbol);
+        }
+    }
+
+    fn declare(&mut self, proto: &Prototype<'src>, kind: SymbolKind) {
+        let keyword = match kind {
+            SymbolKind::Extern => "extern",
+            _ => "def",
+        };
+
+        let detail = format!("{keyword} {}", prototype_str(proto));
+        let arity = Some(proto.get_num_params());
+
+        match *proto {
+            Prototype::FunctionProto {
+                name: "__anonymous_expr",
+                ..
+            } => {}
+
+            Prototype::FunctionProto { name, .. } => {
+                let symbol = self.define(name, kind, detail, arity);
+                self.functions.insert(name, symbol);
+            }
+
+            Prototype::OverloadedUnaryOpProto { operator, .. }
+            | Prototype::OverloadedBinaryOpProto { operator, .. } => {
+                self.define(operator.as_str(), SymbolKind::Operator, detail, arity);
+            }
+        }
+    }
+
+    // Every function is declared before any body is indexed, so uses of functions
+    // defined further down the file are found too
+    fn index_program(&mut self, items: &[(Span, Item<'src>)]) {
+        for (_, item) in items {
+            match item {
+                Item::Extern(proto) => self.declare(proto, SymbolKind::Extern),
+                Item::Function(func) => self.declare(&func.proto, SymbolKind::Function),
+                Item::Global(_) => {}
+            }
+        }
+
+        for (_, item) in items {
+            match item {
+                Item::Extern(_) => {}
+
+                Item::Function(func) => {
+                    self.scope.push_scope();
+
+                    let params: Vec<(&str, Option<Type>)> = match &*func.proto {
+                        Prototype::FunctionProto { args, .. } => args.clone(),
+                        proto => proto.get_param_names().into_iter().map(|name| (name, None)).collect(),
+                    };
+
+                    for (param, ty) in params {
+                        let symbol = self.define(param, SymbolKind::Parameter, annotated(param, ty), None);
+                        self.scope.define(param, symbol);
+                    }
+
+                    self.index_expr(&func.body);
+                    self.scope.pop_scope();
+                }
+
+                Item::Global(global) => {
+                    if let Some(init) = &global.init {
+                        self.index_expr(init);
+                    }
+
+                    let detail = format!("global {}", annotated(global.name, global.var_type));
+                    let symbol = self.define(global.name, SymbolKind::Global, detail, None);
+                    self.globals.insert(global.name, symbol);
+                }
+            }
+        }
+    }
+
+    fn index_exprs(&mut self, exprs: &[Box<ASTExpr<'src>>]) {
+        for expr in exprs {
+            self.index_expr(expr);
+        }
+    }
+
+    fn index_expr(&mut self, expr: &ASTExpr<'src>) {
+        use ASTExpr::*;
+
+        match expr {
+            NumberExpr(_) | IntegerExpr(_) | BoolExpr(_) | StringExpr(_) | BreakExpr | ContinueExpr => {}
+
+            VariableExpr(name) => self.refer_variable(name),
+
+            ArrayExpr(exprs) | BlockExpr(exprs) => self.index_exprs(exprs),
+
+            // A call of a variable holding a function, or a function by name
+            CallExpr { callee, args } => {
+                self.refer_variable(callee);
+                self.index_exprs(args);
+            }
+
+            ApplyExpr { callee, args, .. } => {
+                self.index_expr(callee);
+                self.index_exprs(args);
+            }
+
+            LenExpr(operand) | UnaryExpr { operand, .. } => self.index_expr(operand),
+
+            BinaryExpr { left, right, .. }
+            | ArrayFillExpr {
+                value: left,
+                count: right,
+            }
+            | IndexExpr {
+                array: left,
+                index: right,
+            }
+            | WhileExpr {
+                cond: left,
+                body: right,
+            } => {
+                self.index_expr(left);
+                self.index_expr(right);
+            }
+
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.index_expr(cond);
+                self.index_expr(then_branch);
+                self.index_expr(else_branch);
+            }
+
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                self.index_expr(start);
+                self.scope.push_scope();
+
+                let detail = format!("for {}", annotated(varname, *var_type));
+                let symbol = self.define(varname, SymbolKind::Variable, detail, None);
+                self.scope.define(varname, symbol);
+
+                self.index_expr(end);
+                self.index_expr(step);
+                self.index_expr(body);
+                self.scope.pop_scope();
+            }
+
+            VarExpr { var_names, body } => {
+                self.scope.push_scope();
+
+                for (name, ty, init) in var_names {
+                    if let Some(init) = init {
+                        self.index_expr(init);
+                    }
+
+                    let symbol = self.define(name, SymbolKind::Variable, format!("var {}", annotated(name, *ty)), None);
+                    self.scope.define(name, symbol);
+                }
+
+                self.index_expr(body);
+                self.scope.pop_scope();
+            }
+
+            LambdaExpr { params, body, .. } => self.index_lambda(params, body),
+
+            // The function's own name is visible in its body, so it may recurse
+            LocalFnExpr { name, lambda, body } => {
+                let LambdaExpr {
+                    params,
+                    ret_type,
+                    body: fn_body,
+                    ..
+                } = &**lambda
+                else {
+                    unreachable!("Local functions are always lambdas");
+                };
+
+                let detail = match ret_type.filter(|ty| *ty != Type::F64) {
+                    Some(ty) => format!("def {name}({}) -> {ty}", params_str(params)),
+                    None => format!("def {name}({})", params_str(params)),
+                };
+
+                let symbol = self.define(name, SymbolKind::Function, detail, Some(params.len()));
+
+                self.scope.push_scope();
+                self.scope.define(name, symbol);
+                self.index_lambda(params, fn_body);
+                self.index_expr(body);
+                self.scope.pop_scope();
+            }
+        }
+    }
+
+    fn index_lambda(&mut self, params: &[(&'src str, Option<Type>)], body: &ASTExpr<'src>) {
+        self.scope.push_scope();
+
+        for (param, ty) in params {
+            let symbol = self.define(param, SymbolKind::Parameter, annotated(param, *ty), None);
+            self.scope.define(param, symbol);
+        }
+
+        self.index_expr(body);
+        self.scope.pop_scope();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Ops, parser::OP_PRECEDENCE};
+
+    #[test]
+    fn syntax_errors_dont_stop_the_analysis() {
+        let src = "def broken(x) x + ;
+                   def fine(x) x * 2;
+                   fine(missing);";
+
+        let analysis = analyze(src);
+
+        let errors = analysis
+            .diagnostics
+            .iter()
+            .map(|diag| (diag.span.line_col(src), diag.message.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            errors,
+            vec![
+                ((1, 19), "Unexpected token: Semicolon"),
+                ((3, 25), "Unknown variable name missing"),
+            ]
+        );
+
+        // The definition after the error was still found
+        let fine = analysis.symbols.iter().find(|symbol| symbol.name == "fine").unwrap();
+        assert_eq!(fine.detail, "def fine(x)");
+        assert_eq!(fine.arity, Some(1));
+    }
+
+    #[test]
+    fn uses_are_linked_to_their_definitions() {
+        let src = "def f(x) var x = x + 1 in x * g(x);
+                   def g(y) y;";
+
+        let analysis = analyze(src);
+        let offset = |nth: usize, name: &str| src.match_indices(name).nth(nth).unwrap().0;
+
+        // Uses of x in the body of the var see the variable, not the parameter
+        let param = analysis.symbol_at(offset(0, "x")).unwrap();
+        let var = analysis.symbol_at(offset(1, "x")).unwrap();
+
+        assert_eq!(analysis.symbols[param].kind, SymbolKind::Parameter);
+        assert_eq!(analysis.symbols[var].kind, SymbolKind::Variable);
+
+        assert_eq!(analysis.symbol_at(offset(2, "x")), Some(param));
+        assert_eq!(analysis.symbol_at(offset(3, "x")), Some(var));
+        assert_eq!(analysis.symbol_at(offset(4, "x")), Some(var));
+
+        // Functions defined further down are found too
+        let g = analysis.symbol_at(offset(0, "g")).unwrap();
+        assert_eq!(analysis.symbols[g].span, Span::of(src, &src[offset(1, "g")..offset(1, "g") + 1]));
+        assert_eq!(analysis.references_to(g).count(), 1);
+    }
+
+    #[test]
+    fn operators_defined_in_a_source_stay_in_it() {
+        let analysis = analyze("def binary^ 70 (a b) a;\n1 ^ 2 * 3;");
+
+        assert!(analysis.diagnostics.is_empty(), "{:?}", analysis.diagnostics);
+        assert_eq!(OP_PRECEDENCE.read().unwrap().get(&Ops::Xor), Some(&5));
+    }
+}
@@ -1,5 +1,86 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Mutex;
+
 use crate::frontend::lexer::Ops;
 
+// The static types a Kaleidoscope value can have. The tutorial language
+// only had doubles, so whenever a program omits an annotation we fall back
+// to F64, which keeps every program written for the original untyped
+// language compiling as before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    F64,
+    I64,
+    Bool,
+    Str,   // A pointer and a length, see the string runtime in src/clib/io.c
+    Array, // Heap allocated vector of doubles, written [f64] in annotations
+    Fn(&'static FnSig), // Function values, written fn(f64, i64) -> bool in annotations
+}
+
+// The parameter and return types of a function value. Signatures are interned,
+// see Type::function, which is what lets Type stay Copy like the other types.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct FnSig {
+    pub params: Vec<Type>,
+    pub ret: Type,
+}
+
+lazy_static! {
+    static ref FN_SIGNATURES: Mutex<HashSet<&'static FnSig>> = Mutex::new(HashSet::new());
+}
+
+impl Type {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Type::F64 => "f64",
+            Type::I64 => "i64",
+            Type::Bool => "bool",
+            Type::Str => "str",
+            Type::Array => "[f64]",
+            Type::Fn(_) => "fn",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "f64" => Some(Type::F64),
+            "i64" => Some(Type::I64),
+            "bool" => Some(Type::Bool),
+            "str" => Some(Type::Str),
+            _ => None,
+        }
+    }
+
+    // The type of a function value, every distinct signature is only ever
+    // allocated once, and lives for the rest of the program.
+    pub fn function(params: Vec<Type>, ret: Type) -> Type {
+        let sig = FnSig { params, ret };
+        let mut signatures = FN_SIGNATURES.lock().unwrap();
+
+        match signatures.get(&sig) {
+            Some(interned) => Type::Fn(interned),
+            None => {
+                let interned: &'static FnSig = Box::leak(Box::new(sig));
+                signatures.insert(interned);
+                Type::Fn(interned)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Fn(sig) => {
+                let params = sig.params.iter().map(Type::to_string).collect::<Vec<_>>();
+                write!(f, "fn({}) -> {}", params.join(", "), sig.ret)
+            }
+            named => write!(f, "{}", named.as_str()),
+        }
+    }
+}
+
 // NOTE TO LEARNERS/DEVELOPERS:
 // Previously, the AST followed the tutorial by the letter,
 // and created a AST composed of dynamically dispatched expression
@@ -20,6 +101,19 @@ use crate::frontend::lexer::Ops;
 #[derive(Debug, Clone, PartialEq)]
 pub enum ASTExpr<'src> {
     NumberExpr(f64),
+    IntegerExpr(i64), // Never produced by the parser, number literals are typed as integers by the type checker
+    BoolExpr(bool),
+    StringExpr(&'src str), // Escapes are left as written, the backend takes care of them
+    ArrayExpr(Vec<Box<ASTExpr<'src>>>),
+    ArrayFillExpr {
+        value: Box<ASTExpr<'src>>,
+        count: Box<ASTExpr<'src>>,
+    },
+    IndexExpr {
+        array: Box<ASTExpr<'src>>,
+        index: Box<ASTExpr<'src>>,
+    },
+    LenExpr(Box<ASTExpr<'src>>), // Builtin len(), works on arrays and strings
     VariableExpr(&'src str),
     UnaryExpr {
         op: Ops,
@@ -34,6 +128,32 @@ pub enum ASTExpr<'src> {
         callee: &'src str,
         args: Vec<Box<ASTExpr<'src>>>,
     },
+    // Calls a function value, rather than a function by name. The type checker
+    // turns calls of variables holding functions into these, and fills in
+    // callee_type, the signature the backend needs to make the call.
+    ApplyExpr {
+        callee: Box<ASTExpr<'src>>,
+        args: Vec<Box<ASTExpr<'src>>>,
+        callee_type: Option<Type>,
+    },
+    // Anonymous functions, written \x, y -> x + y. The type checker fills in the
+    // return type, and the variables of enclosing scopes the body refers to,
+    // which are copied into the closure when the lambda is evaluated.
+    // Local functions are lambdas too, ones that can call themselves by self_name.
+    LambdaExpr {
+        params: Vec<(&'src str, Option<Type>)>,
+        ret_type: Option<Type>,
+        captures: Vec<&'src str>,
+        self_name: Option<&'src str>,
+        body: Box<ASTExpr<'src>>,
+    },
+    // def name(params) lambda-body in body, a function only visible in body
+    // (and its own body, so it may recurse). The lambda is always a LambdaExpr.
+    LocalFnExpr {
+        name: &'src str,
+        lambda: Box<ASTExpr<'src>>,
+        body: Box<ASTExpr<'src>>,
+    },
     IfExpr {
         cond: Box<ASTExpr<'src>>,
         then_branch: Box<ASTExpr<'src>>,
@@ -41,23 +161,49 @@ pub enum ASTExpr<'src> {
     },
     ForLoopExpr {
         varname: &'src str,
+        var_type: Option<Type>,
         start: Box<ASTExpr<'src>>,
         end: Box<ASTExpr<'src>>,
         step: Box<ASTExpr<'src>>,
         body: Box<ASTExpr<'src>>,
     },
+    WhileExpr {
+        cond: Box<ASTExpr<'src>>,
+        body: Box<ASTExpr<'src>>,
+    },
+    BlockExpr(Vec<Box<ASTExpr<'src>>>), // Evaluated in order, the value of the last one is the value of the block
+    BreakExpr,    // Only valid inside a loop body, the parser makes sure of it
+    ContinueExpr, // Same as above
     VarExpr {
-        var_names: Vec<(&'src str, Option<Box<ASTExpr<'src>>>)>, // var_names is a combination of variable name and (possible) initializer
+        var_names: Vec<VarBinding<'src>>,
         body: Box<ASTExpr<'src>>,
     },
 }
 
+// A binding introduced by a var expression, combination of variable name,
+// (possible) type annotation and (possible) initializer
+pub type VarBinding<'src> = (&'src str, Option<Type>, Option<Box<ASTExpr<'src>>>);
+
+impl<'src> ASTExpr<'src> {
+    // Break and continue jump away, control never comes back to the expression
+    // that contained them, so they never really produce a value. Neither does
+    // a block ending in one.
+    pub fn is_loop_control(&self) -> bool {
+        match self {
+            ASTExpr::BreakExpr | ASTExpr::ContinueExpr => true,
+            ASTExpr::BlockExpr(exprs) => exprs.last().is_some_and(|last| last.is_loop_control()),
+            _ => false,
+        }
+    }
+}
+
 // Prototype, mimics that off the tutorial C++ class
 #[derive(Debug, PartialEq)]
 pub enum Prototype<'src> {
     FunctionProto {
         name: &'src str,
-        args: Vec<&'src str>,
+        args: Vec<(&'src str, Option<Type>)>,
+        ret_type: Option<Type>,
     },
     OverloadedUnaryOpProto {
         operator: Ops,
@@ -83,6 +229,37 @@ impl<'src> Prototype<'src> {
         }
     }
 
+    pub fn get_param_names(&self) -> Vec<&'src str> {
+        match self {
+            FunctionProto { args, .. } => args.iter().map(|(name, _)| *name).collect(),
+
+            OverloadedUnaryOpProto { arg, .. } => vec![*arg],
+
+            OverloadedBinaryOpProto { args: (lhs, rhs), .. } => vec![*lhs, *rhs],
+        }
+    }
+
+    // Overloaded operators only ever work on doubles, as do functions
+    // whose parameters were left unannotated
+    pub fn get_param_types(&self) -> Vec<Type> {
+        match self {
+            FunctionProto { args, .. } => args
+                .iter()
+                .map(|(_, ty)| ty.unwrap_or(Type::F64))
+                .collect(),
+
+            _operator => vec![Type::F64; self.get_num_params()],
+        }
+    }
+
+    pub fn get_return_type(&self) -> Type {
+        match self {
+            FunctionProto { ret_type, .. } => ret_type.unwrap_or(Type::F64),
+
+            _operator => Type::F64,
+        }
+    }
+
     pub fn get_num_params(&self) -> usize {
         match self {
             FunctionProto { args, .. } => args.len(),
@@ -100,3 +277,468 @@ pub struct Function<'src> {
     pub proto: Box<Prototype<'src>>,
     pub body: Box<ASTExpr<'src>>,
 }
+
+// A top level item of a source file, in the order they were written
+#[derive(Debug, PartialEq)]
+pub enum Item<'src> {
+    Extern(Box<Prototype<'src>>),
+    Function(Box<Function<'src>>),
+    Global(Box<Global<'src>>),
+}
+
+// A global variable declared at the top level, not part of the tutorial. Visible
+// to (and assignable from) every function, unless shadowed by a local variable.
+// The type checker fills in var_type when it was left out.
+#[derive(Debug, PartialEq)]
+pub struct Global<'src> {
+    pub name: &'src str,
+    pub var_type: Option<Type>,
+    pub init: Option<Box<ASTExpr<'src>>>,
+}
+
+// Walking the tree. Most passes only care about a handful of the expressions, a
+// Visitor overrides the visit_ methods of those and leaves the rest to the
+// defaults, which visit every child of a node, in the order they are evaluated.
+// An overridden method calls the matching walk_ function to keep going deeper.
+//
+//     struct CountCalls(usize);
+//
+//     impl<'src> Visitor<'src> for CountCalls {
+//         fn visit_expr(&mut self, expr: &ASTExpr<'src>) {
+//             if let ASTExpr::CallExpr { .. } = expr {
+//                 self.0 += 1;
+//             }
+//
+//             walk_expr(self, expr);
+//         }
+//     }
+pub trait Visitor<'src> {
+    fn visit_function(&mut self, func: &Function<'src>) {
+        walk_function(self, func);
+    }
+
+    // Prototypes hold no expressions, nothing to walk into
+    fn visit_prototype(&mut self, _proto: &Prototype<'src>) {}
+
+    fn visit_expr(&mut self, expr: &ASTExpr<'src>) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_function<'src, V: Visitor<'src> + ?Sized>(visitor: &mut V, func: &Function<'src>) {
+    visitor.visit_prototype(&func.proto);
+    visitor.visit_expr(&func.body);
+}
+
+pub fn walk_expr<'src, V: Visitor<'src> + ?Sized>(visitor: &mut V, expr: &ASTExpr<'src>) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | IntegerExpr(_) | BoolExpr(_) | StringExpr(_) | VariableExpr(_)
+        | BreakExpr | ContinueExpr => {}
+
+        ArrayExpr(exprs) | BlockExpr(exprs) | CallExpr { args: exprs, .. } => {
+            exprs.iter().for_each(|expr| visitor.visit_expr(expr));
+        }
+
+        LenExpr(operand) | UnaryExpr { operand, .. } | LambdaExpr { body: operand, .. } => {
+            visitor.visit_expr(operand)
+        }
+
+        ArrayFillExpr {
+            value: left,
+            count: right,
+        }
+        | IndexExpr {
+            array: left,
+            index: right,
+        }
+        | BinaryExpr { left, right, .. }
+        | WhileExpr {
+            cond: left,
+            body: right,
+        }
+        | LocalFnExpr {
+            lambda: left,
+            body: right,
+            ..
+        } => {
+            visitor.visit_expr(left);
+            visitor.visit_expr(right);
+        }
+
+        ApplyExpr { callee, args, .. } => {
+            visitor.visit_expr(callee);
+            args.iter().for_each(|arg| visitor.visit_expr(arg));
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            visitor.visit_expr(cond);
+            visitor.visit_expr(then_branch);
+            visitor.visit_expr(else_branch);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            visitor.visit_expr(start);
+            visitor.visit_expr(body);
+            visitor.visit_expr(step);
+            visitor.visit_expr(end);
+        }
+
+        VarExpr { var_names, body } => {
+            for (_, _, init) in var_names {
+                if let Some(init) = init {
+                    visitor.visit_expr(init);
+                }
+            }
+
+            visitor.visit_expr(body);
+        }
+    }
+}
+
+// Same as Visitor, but for passes that change the tree in place
+pub trait MutVisitor<'src> {
+    fn visit_function_mut(&mut self, func: &mut Function<'src>) {
+        walk_function_mut(self, func);
+    }
+
+    fn visit_prototype_mut(&mut self, _proto: &mut Prototype<'src>) {}
+
+    fn visit_expr_mut(&mut self, expr: &mut ASTExpr<'src>) {
+        walk_expr_mut(self, expr);
+    }
+}
+
+pub fn walk_function_mut<'src, V: MutVisitor<'src> + ?Sized>(visitor: &mut V, func: &mut Function<'src>) {
+    visitor.visit_prototype_mut(&mut func.proto);
+    visitor.visit_expr_mut(&mut func.body);
+}
+
+pub fn walk_expr_mut<'src, V: MutVisitor<'src> + ?Sized>(visitor: &mut V, expr: &mut ASTExpr<'src>) {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(_) | IntegerExpr(_) | BoolExpr(_) | StringExpr(_) | VariableExpr(_)
+        | BreakExpr | ContinueExpr => {}
+
+        ArrayExpr(exprs) | BlockExpr(exprs) | CallExpr { args: exprs, .. } => {
+            exprs.iter_mut().for_each(|expr| visitor.visit_expr_mut(expr));
+        }
+
+        LenExpr(operand) | UnaryExpr { operand, .. } | LambdaExpr { body: operand, .. } => {
+            visitor.visit_expr_mut(operand)
+        }
+
+        ArrayFillExpr {
+            value: left,
+            count: right,
+        }
+        | IndexExpr {
+            array: left,
+            index: right,
+        }
+        | BinaryExpr { left, right, .. }
+        | WhileExpr {
+            cond: left,
+            body: right,
+        }
+        | LocalFnExpr {
+            lambda: left,
+            body: right,
+            ..
+        } => {
+            visitor.visit_expr_mut(left);
+            visitor.visit_expr_mut(right);
+        }
+
+        ApplyExpr { callee, args, .. } => {
+            visitor.visit_expr_mut(callee);
+            args.iter_mut().for_each(|arg| visitor.visit_expr_mut(arg));
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            visitor.visit_expr_mut(cond);
+            visitor.visit_expr_mut(then_branch);
+            visitor.visit_expr_mut(else_branch);
+        }
+
+        ForLoopExpr {
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            visitor.visit_expr_mut(start);
+            visitor.visit_expr_mut(body);
+            visitor.visit_expr_mut(step);
+            visitor.visit_expr_mut(end);
+        }
+
+        VarExpr { var_names, body } => {
+            for (_, _, init) in var_names {
+                if let Some(init) = init {
+                    visitor.visit_expr_mut(init);
+                }
+            }
+
+            visitor.visit_expr_mut(body);
+        }
+    }
+}
+
+// A Folder takes the tree apart and builds a new one, handy for rewrites that
+// replace a node with a different kind of node. The defaults fold the children
+// of a node and put it back together as it was.
+pub trait Folder<'src> {
+    fn fold_function(&mut self, func: Function<'src>) -> Function<'src> {
+        Function {
+            proto: Box::new(self.fold_prototype(*func.proto)),
+            body: self.fold_expr(func.body),
+        }
+    }
+
+    fn fold_prototype(&mut self, proto: Prototype<'src>) -> Prototype<'src> {
+        proto
+    }
+
+    fn fold_expr(&mut self, expr: Box<ASTExpr<'src>>) -> Box<ASTExpr<'src>> {
+        fold_expr_children(self, expr)
+    }
+}
+
+pub fn fold_expr_children<'src, F: Folder<'src> + ?Sized>(
+    folder: &mut F,
+    expr: Box<ASTExpr<'src>>,
+) -> Box<ASTExpr<'src>> {
+    use ASTExpr::*;
+
+    let mut fold_all = |exprs: Vec<Box<ASTExpr<'src>>>| {
+        exprs
+            .into_iter()
+            .map(|expr| folder.fold_expr(expr))
+            .collect::<Vec<_>>()
+    };
+
+    let folded = match *expr {
+        leaf @ (NumberExpr(_) | IntegerExpr(_) | BoolExpr(_) | StringExpr(_) | VariableExpr(_)
+        | BreakExpr | ContinueExpr) => leaf,
+
+        ArrayExpr(exprs) => ArrayExpr(fold_all(exprs)),
+
+        BlockExpr(exprs) => BlockExpr(fold_all(exprs)),
+
+        CallExpr { callee, args } => CallExpr {
+            callee,
+            args: fold_all(args),
+        },
+
+        ApplyExpr {
+            callee,
+            args,
+            callee_type,
+        } => ApplyExpr {
+            callee: folder.fold_expr(callee),
+            args: args.into_iter().map(|arg| folder.fold_expr(arg)).collect(),
+            callee_type,
+        },
+
+        LenExpr(operand) => LenExpr(folder.fold_expr(operand)),
+
+        UnaryExpr { op, operand } => UnaryExpr {
+            op,
+            operand: folder.fold_expr(operand),
+        },
+
+        BinaryExpr { op, left, right } => BinaryExpr {
+            op,
+            left: folder.fold_expr(left),
+            right: folder.fold_expr(right),
+        },
+
+        ArrayFillExpr { value, count } => ArrayFillExpr {
+            value: folder.fold_expr(value),
+            count: folder.fold_expr(count),
+        },
+
+        IndexExpr { array, index } => IndexExpr {
+            array: folder.fold_expr(array),
+            index: folder.fold_expr(index),
+        },
+
+        WhileExpr { cond, body } => WhileExpr {
+            cond: folder.fold_expr(cond),
+            body: folder.fold_expr(body),
+        },
+
+        LambdaExpr {
+            params,
+            ret_type,
+            captures,
+            self_name,
+            body,
+        } => LambdaExpr {
+            params,
+            ret_type,
+            captures,
+            self_name,
+            body: folder.fold_expr(body),
+        },
+
+        LocalFnExpr { name, lambda, body } => LocalFnExpr {
+            name,
+            lambda: folder.fold_expr(lambda),
+            body: folder.fold_expr(body),
+        },
+
+        IfExpr {
+            cond,
+            then_branch,
+            else_branch,
+        } => IfExpr {
+            cond: folder.fold_expr(cond),
+            then_branch: folder.fold_expr(then_branch),
+            else_branch: folder.fold_expr(else_branch),
+        },
+
+        ForLoopExpr {
+            varname,
+            var_type,
+            start,
+            end,
+            step,
+            body,
+        } => {
+            let start = folder.fold_expr(start);
+            let body = folder.fold_expr(body);
+            let step = folder.fold_expr(step);
+            let end = folder.fold_expr(end);
+
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            }
+        }
+
+        VarExpr { var_names, body } => VarExpr {
+            var_names: var_names
+                .into_iter()
+                .map(|(name, ty, init)| (name, ty, init.map(|init| folder.fold_expr(init))))
+                .collect(),
+            body: folder.fold_expr(body),
+        },
+    };
+
+    Box::new(folded)
+}
+
+// A range of bytes in the source code. The tree doesn't keep track of where things
+// were written, but every name in it is a slice of the source it was parsed from,
+// so the position of a name can be worked out from its address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // None when the slice did not come from src, like the names the parser makes up
+    pub fn of(src: &str, slice: &str) -> Option<Span> {
+        let start = (slice.as_ptr() as usize).checked_sub(src.as_ptr() as usize)?;
+        let end = start + slice.len();
+
+        (end <= src.len()).then_some(Span { start, end })
+    }
+
+    // Line and column the span starts at, both counting from 1
+    pub fn line_col(&self, src: &str) -> (usize, usize) {
+        let before = &src[..self.start];
+        let line = before.matches('\n').count() + 1;
+        let col = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+
+        (line, col)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, parser::parse_definition};
+
+    #[test]
+    fn visitors_reach_every_expression() {
+        struct CallNames<'src>(Vec<&'src str>);
+
+        impl<'src> Visitor<'src> for CallNames<'src> {
+            fn visit_expr(&mut self, expr: &ASTExpr<'src>) {
+                if let ASTExpr::CallExpr { callee, .. } = expr {
+                    self.0.push(callee);
+                }
+
+                walk_expr(self, expr);
+            }
+        }
+
+        let func = parse_definition(
+            &mut "def f(x) var y = g(x) in for i = 0, i < h(y) in \\z -> k(z);".lex().peekable(),
+        )
+        .unwrap();
+
+        let mut names = CallNames(vec![]);
+        names.visit_function(&func);
+
+        assert_eq!(names.0, vec!["g", "k", "h"]);
+    }
+
+    #[test]
+    fn folders_rebuild_the_tree() {
+        // Replaces every variable named x with the number 1
+        struct ReplaceX;
+
+        impl<'src> Folder<'src> for ReplaceX {
+            fn fold_expr(&mut self, expr: Box<ASTExpr<'src>>) -> Box<ASTExpr<'src>> {
+                match *expr {
+                    ASTExpr::VariableExpr("x") => Box::new(ASTExpr::NumberExpr(1.0)),
+                    _ => fold_expr_children(self, expr),
+                }
+            }
+        }
+
+        let func = parse_definition(&mut "def f(x) x + g(x, y);".lex().peekable()).unwrap();
+        let folded = ReplaceX.fold_function(*func);
+
+        assert_eq!(
+            folded.body,
+            Box::new(ASTExpr::BinaryExpr {
+                op: Ops::Plus,
+                left: Box::new(ASTExpr::NumberExpr(1.0)),
+                right: Box::new(ASTExpr::CallExpr {
+                    callee: &"g",
+                    args: vec![
+                        Box::new(ASTExpr::NumberExpr(1.0)),
+                        Box::new(ASTExpr::VariableExpr(&"y"))
+                    ],
+                }),
+            })
+        );
+    }
+}
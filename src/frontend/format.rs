@@ -0,0 +1,858 @@
+use crate::frontend::{
+    ast::{walk_expr, ASTExpr, Function, Global, Item, Prototype, Span, Type, Visitor},
+    lexer::{comments, Lex, Ops, Token},
+    parser::{parse_item, ParserError, OP_PRECEDENCE},
+};
+
+// Lines are kept this short where possible, indentation goes up two spaces at a time
+const WIDTH: usize = 80;
+const INDENT: usize = 2;
+
+// Pretty prints Kaleidoscope source code. An expression that fits on the rest of its
+// line is written on it, otherwise it is broken up: the branches of an if, the bodies
+// of loops, var and functions go on lines of their own, indented one level deeper,
+// blocks always put each of their expressions on a line of its own.
+//
+// The tree doesn't keep comments, or where anything was written for that matter, but
+// names in the tree are slices of the source, so they tell us roughly where each
+// expression was. A comment is written on a line of its own, before the first item,
+// or expression starting a line, that was written after it.
+pub fn format_source(src: &str) -> Result<String, ParserError<'_>> {
+    let mut formatter = Formatter::new(src);
+    let mut tokens = src.lex().peekable();
+    let mut out = String::new();
+    let mut last_item: Option<Item> = None;
+
+    while let Some(token) = tokens.peek() {
+        if let Token::Semicolon = token {
+            tokens.next();
+            continue;
+        }
+
+        // Each item is formatted as soon as it is parsed, while the precedence of the
+        // user defined operators are the ones it was parsed with
+        let item = parse_item(&mut tokens)?;
+
+        out += &formatter.item_after(last_item.as_ref(), &item);
+        last_item = Some(item);
+    }
+
+    let trailing = formatter.comment_lines(None, 0);
+
+    if !trailing.is_empty() && !out.is_empty() {
+        out.push('\n');
+    }
+
+    out += &trailing;
+
+    Ok(out)
+}
+
+// Items that weren't parsed from any source, like the definitions the REPL keeps
+pub fn format_items(items: &[Item]) -> String {
+    let mut formatter = Formatter::new("");
+
+    items
+        .iter()
+        .enumerate()
+        .map(|(idx, item)| formatter.item_after(idx.checked_sub(1).map(|prev| &items[prev]), item))
+        .collect()
+}
+
+// Where a subexpression is, decides whether it needs parentheses
+#[derive(Clone, Copy)]
+enum Position {
+    Left(i32),  // Left operand of a binary operator with the given precedence
+    Right(i32), // Right operand of one
+    Operand,    // Operand of a unary operator
+    Postfix,    // What is called or indexed
+}
+
+fn precedence(op: Ops) -> i32 {
+    OP_PRECEDENCE.read().unwrap().get(&op).copied().unwrap_or(0)
+}
+
+// Binary operators associate to the left, so an operator of the same precedence
+// on the right needs parentheses, one on the left doesn't. Expressions that extend
+// as far to the right as they can, like if and var, always need them as operands.
+fn needs_parens(expr: &ASTExpr, position: Position) -> bool {
+    use ASTExpr::*;
+
+    match expr {
+        BinaryExpr { op, .. } => match position {
+            Position::Left(outer) => precedence(*op) < outer,
+            Position::Right(outer) => precedence(*op) <= outer,
+            Position::Operand | Position::Postfix => true,
+        },
+
+        UnaryExpr { .. } => matches!(position, Position::Postfix),
+
+        IfExpr { .. }
+        | ForLoopExpr { .. }
+        | WhileExpr { .. }
+        | VarExpr { .. }
+        | LambdaExpr { .. }
+        | LocalFnExpr { .. } => true,
+
+        _ => false,
+    }
+}
+
+// The parser fills in a step of 1.0 when a for loop doesn't have one
+fn is_default_step(step: &ASTExpr) -> bool {
+    matches!(step, ASTExpr::NumberExpr(num) if *num == 1.0)
+}
+
+// Blocks, and loops, vars or lambdas whose bodies are blocks, continue on the line
+// they start on, the block closes at the indentation of that line
+fn opens_block(expr: &ASTExpr) -> bool {
+    use ASTExpr::*;
+
+    match expr {
+        BlockExpr(_) => true,
+        ForLoopExpr { body, .. } | WhileExpr { body, .. } | VarExpr { body, .. } | LambdaExpr { body, .. } => {
+            opens_block(body)
+        }
+        _ => false,
+    }
+}
+
+fn indentation(indent: usize) -> String {
+    " ".repeat(indent)
+}
+
+// The column text ends at, when it starts at col
+fn end_col(col: usize, text: &str) -> usize {
+    match text.rfind('\n') {
+        Some(newline) => text.len() - newline - 1,
+        None => col + text.len(),
+    }
+}
+
+pub fn params_str(params: &[(&str, Option<Type>)]) -> String {
+    params
+        .iter()
+        .map(|(name, annotation)| match annotation {
+            Some(ty) => format!("{name}: {ty}"),
+            None => name.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn prototype_str(proto: &Prototype) -> String {
+    match proto {
+        Prototype::FunctionProto {
+            name,
+            args,
+            ret_type,
+        } => match ret_type {
+            Some(ty) => format!("{name}({}) -> {ty}", params_str(args)),
+            None => format!("{name}({})", params_str(args)),
+        },
+
+        Prototype::OverloadedUnaryOpProto { operator, arg } => {
+            format!("unary{}({arg})", operator.as_str())
+        }
+
+        Prototype::OverloadedBinaryOpProto {
+            operator,
+            args: (lhs, rhs),
+            precedence,
+        } => format!("binary{} {precedence} ({lhs} {rhs})", operator.as_str()),
+    }
+}
+
+struct Formatter<'src> {
+    src: &'src str,
+    comments: Vec<(usize, &'src str)>,
+    next_comment: usize,
+}
+
+impl<'src> Formatter<'src> {
+    fn new(src: &'src str) -> Self {
+        let comments = comments(src)
+            .into_iter()
+            .filter_map(|comment| Span::of(src, comment).map(|span| (span.start, comment)))
+            .collect();
+
+        Self {
+            src,
+            comments,
+            next_comment: 0,
+        }
+    }
+
+    // The comments written before pos (or all that are left), one per line
+    fn comment_lines(&mut self, pos: Option<usize>, indent: usize) -> String {
+        let mut lines = String::new();
+
+        while let Some((offset, comment)) = self.comments.get(self.next_comment) {
+            if pos.is_some_and(|pos| *offset > pos) {
+                break;
+            }
+
+            lines += &format!("{}{comment}\n", indentation(indent));
+            self.next_comment += 1;
+        }
+
+        lines
+    }
+
+    // Where the first and last names in expr were written
+    fn positions(&self, expr: &ASTExpr) -> Option<(usize, usize)> {
+        struct Names<'a> {
+            src: &'a str,
+            first: Option<usize>,
+            last: Option<usize>,
+        }
+
+        impl Names<'_> {
+            fn add(&mut self, name: &str) {
+                if let Some(span) = Span::of(self.src, name) {
+                    self.first = Some(self.first.map_or(span.start, |first| first.min(span.start)));
+                    self.last = Some(self.last.map_or(span.start, |last| last.max(span.start)));
+                }
+            }
+        }
+
+        impl<'src> Visitor<'src> for Names<'_> {
+            fn visit_expr(&mut self, expr: &ASTExpr<'src>) {
+                use ASTExpr::*;
+
+                match expr {
+                    VariableExpr(name) | StringExpr(name) | CallExpr { callee: name, .. } => {
+                        self.add(name)
+                    }
+                    ForLoopExpr { varname, .. } => self.add(varname),
+                    VarExpr { var_names, .. } => var_names.iter().for_each(|(name, ..)| self.add(name)),
+                    LambdaExpr { params, .. } => params.iter().for_each(|(name, _)| self.add(name)),
+                    LocalFnExpr { name, .. } => self.add(name),
+                    _ => {}
+                }
+
+                walk_expr(self, expr);
+            }
+        }
+
+        let mut names = Names {
+            src: self.src,
+            first: None,
+            last: None,
+        };
+
+        names.visit_expr(expr);
+
+        names.first.zip(names.last)
+    }
+
+    // Whether a comment that hasn't been written yet was written inside of expr
+    fn has_comments_within(&self, expr: &ASTExpr) -> bool {
+        match (self.comments.get(self.next_comment), self.positions(expr)) {
+            (Some((offset, _)), Some((_, last))) => *offset < last,
+            _ => false,
+        }
+    }
+
+    // Consecutive externs or globals are kept together, everything else is spaced out
+    fn item_after(&mut self, last_item: Option<&Item>, item: &Item) -> String {
+        let separator = match (last_item, item) {
+            (None, _) => "",
+            (Some(Item::Extern(_)), Item::Extern(_)) | (Some(Item::Global(_)), Item::Global(_)) => "",
+            _ => "\n",
+        };
+
+        format!("{separator}{}\n", self.item(item))
+    }
+
+    fn item(&mut self, item: &Item) -> String {
+        // Where the item was written, the first name in it that came from the source
+        let start = match item {
+            Item::Extern(proto) => self.prototype_start(proto),
+            Item::Function(func) => self.prototype_start(&func.proto),
+            Item::Global(global) => Span::of(self.src, global.name).map(|span| span.start),
+        };
+
+        let mut out = String::new();
+
+        match item {
+            Item::Extern(proto) => {
+                out += &self.comment_lines(start, 0);
+                out += &format!("extern {};", prototype_str(proto));
+            }
+
+            Item::Global(global) => {
+                out += &self.comment_lines(start, 0);
+                out += &self.global(global);
+            }
+
+            Item::Function(func) => out += &self.function(func),
+        }
+
+        out
+    }
+
+    fn global(&mut self, global: &Global) -> String {
+        let mut out = format!("global {}", global.name);
+
+        if let Some(ty) = global.var_type {
+            out += &format!(": {ty}");
+        }
+
+        if let Some(init) = &global.init {
+            let col = out.len() + 3;
+            out += &format!(" = {}", self.expr(init, 0, col));
+        }
+
+        out + ";"
+    }
+
+    fn prototype_start(&self, proto: &Prototype) -> Option<usize> {
+        let name = match proto {
+            Prototype::FunctionProto { name, .. } => *name,
+            Prototype::OverloadedUnaryOpProto { arg, .. } => *arg,
+            Prototype::OverloadedBinaryOpProto { args: (lhs, _), .. } => *lhs,
+        };
+
+        Span::of(self.src, name).map(|span| span.start)
+    }
+
+    fn function(&mut self, func: &Function) -> String {
+        // Top level expressions are written as they are
+        if let Prototype::FunctionProto {
+            name: "__anonymous_expr",
+            ..
+        } = *func.proto
+        {
+            // Like a line, one with no names in it leaves the comments for the next item
+            let comments = match self.positions(&func.body) {
+                Some((first, _)) => self.comment_lines(Some(first), 0),
+                None => String::new(),
+            };
+
+            return comments + &self.expr(&func.body, 0, 0) + ";";
+        }
+
+        let start = self.prototype_start(&func.proto);
+        let header = format!("def {}", prototype_str(&func.proto));
+        let col = header.len();
+
+        self.comment_lines(start, 0) + &header + &self.body(&func.body, 0, col) + ";"
+    }
+
+    // The body of a function, loop, var or lambda, following a header ending at col.
+    // Blocks start on the same line as the header, anything else that fits does too.
+    fn body(&mut self, body: &ASTExpr, indent: usize, col: usize) -> String {
+        if opens_block(body) {
+            return format!(" {}", self.expr(body, indent, col + 1));
+        }
+
+        if !self.has_comments_within(body) {
+            if let Some(flat) = self.flat(body) {
+                if col + 1 + flat.len() <= WIDTH {
+                    return format!(" {flat}");
+                }
+            }
+        }
+
+        format!("\n{}", self.line(body, indent + INDENT))
+    }
+
+    // An expression starting a line of its own, after the comments written before it
+    fn line(&mut self, expr: &ASTExpr, indent: usize) -> String {
+        let first = self.positions(expr).map(|(first, _)| first);
+
+        // Comments can only be placed before the expression, if it has no names in it
+        // they wait for the next one that does
+        let comments = match first {
+            Some(_) => self.comment_lines(first, indent),
+            None => String::new(),
+        };
+
+        comments + &indentation(indent) + &self.expr(expr, indent, indent)
+    }
+
+    fn expr(&mut self, expr: &ASTExpr, indent: usize, col: usize) -> String {
+        if !self.has_comments_within(expr) {
+            if let Some(flat) = self.flat(expr) {
+                if col + flat.len() <= WIDTH {
+                    return flat;
+                }
+            }
+        }
+
+        self.broken(expr, indent, col)
+    }
+
+    fn wrapped(&mut self, expr: &ASTExpr, position: Position, indent: usize, col: usize) -> String {
+        if needs_parens(expr, position) {
+            format!("({})", self.expr(expr, indent, col + 1))
+        } else {
+            self.expr(expr, indent, col)
+        }
+    }
+
+    // Comma separated expressions, like the arguments of a call
+    fn list(&mut self, exprs: &[Box<ASTExpr>], indent: usize, mut col: usize) -> String {
+        let mut out = String::new();
+
+        for (idx, expr) in exprs.iter().enumerate() {
+            if idx > 0 {
+                out += ", ";
+                col += 2;
+            }
+
+            let text = self.expr(expr, indent, col);
+            col = end_col(col, &text);
+            out += &text;
+        }
+
+        out
+    }
+
+    // The header of a var, listing the variables and their initializers
+    fn bindings(&mut self, expr: &ASTExpr, indent: usize, col: usize) -> String {
+        let ASTExpr::VarExpr { var_names, .. } = expr else {
+            unreachable!("Only var expressions have bindings");
+        };
+
+        let mut out = String::from("var ");
+
+        for (idx, (name, annotation, init)) in var_names.iter().enumerate() {
+            if idx > 0 {
+                out += ", ";
+            }
+
+            out += name;
+
+            if let Some(ty) = annotation {
+                out += &format!(": {ty}");
+            }
+
+            if let Some(init) = init {
+                out += " = ";
+                let init_col = end_col(col, &out);
+                out += &self.expr(init, indent, init_col);
+            }
+        }
+
+        out + " in"
+    }
+
+    // Lays out an expression across multiple lines, only called when it doesn't fit
+    // on one, or it has to be broken up (it holds a block or a comment)
+    fn broken(&mut self, expr: &ASTExpr, indent: usize, col: usize) -> String {
+        use ASTExpr::*;
+
+        match expr {
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let mut out = format!("if {} then\n", self.expr(cond, indent, col + 3));
+                out += &self.line(then_branch, indent + INDENT);
+                out += &format!("\n{}else", indentation(indent));
+
+                // Chains of else ifs stay at the same level
+                match **else_branch {
+                    IfExpr { .. } => out + " " + &self.expr(else_branch, indent, indent + 5),
+                    _ => out + "\n" + &self.line(else_branch, indent + INDENT),
+                }
+            }
+
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let mut out = format!("for {varname}");
+
+                if let Some(ty) = var_type {
+                    out += &format!(": {ty}");
+                }
+
+                out += " = ";
+                out += &self.expr(start, indent, end_col(col, &out));
+                out += ", ";
+                out += &self.expr(end, indent, end_col(col, &out));
+
+                if !is_default_step(step) {
+                    out += ", ";
+                    out += &self.expr(step, indent, end_col(col, &out));
+                }
+
+                out += " in";
+                let header_end = end_col(col, &out);
+
+                out + &self.body(body, indent, header_end)
+            }
+
+            WhileExpr { cond, body } => {
+                let out = format!("while {} do", self.expr(cond, indent, col + 6));
+                let header_end = end_col(col, &out);
+
+                out + &self.body(body, indent, header_end)
+            }
+
+            VarExpr { body, .. } => {
+                let out = self.bindings(expr, indent, col);
+                let header_end = end_col(col, &out);
+
+                out + &self.body(body, indent, header_end)
+            }
+
+            LambdaExpr { params, body, .. } => {
+                let out = format!("\\{} ->", params_str(params));
+                let header_end = end_col(col, &out);
+
+                out + &self.body(body, indent, header_end)
+            }
+
+            LocalFnExpr { name, lambda, body } => {
+                let LambdaExpr {
+                    params,
+                    ret_type,
+                    body: fn_body,
+                    ..
+                } = &**lambda
+                else {
+                    unreachable!("Local functions are always lambdas");
+                };
+
+                let mut out = format!("def {name}({})", params_str(params));
+
+                if let Some(ty) = ret_type.filter(|ty| *ty != Type::F64) {
+                    out += &format!(" -> {ty}");
+                }
+
+                let header_end = end_col(col, &out);
+                out += &self.body(fn_body, indent, header_end);
+                out += &format!("\n{}in", indentation(indent));
+
+                out + &self.body(body, indent, indent + 2)
+            }
+
+            BlockExpr(exprs) => {
+                let mut out = String::from("{\n");
+
+                for (idx, expr) in exprs.iter().enumerate() {
+                    out += &self.line(expr, indent + INDENT);
+
+                    if idx + 1 < exprs.len() {
+                        out.push(';');
+                    }
+
+                    out.push('\n');
+                }
+
+                out + &indentation(indent) + "}"
+            }
+
+            BinaryExpr { op, left, right } => {
+                let prec = precedence(*op);
+
+                let mut out = self.wrapped(left, Position::Left(prec), indent, col);
+                out += &format!(" {} ", op.as_str());
+                let right_col = end_col(col, &out);
+
+                out + &self.wrapped(right, Position::Right(prec), indent, right_col)
+            }
+
+            UnaryExpr { op, operand } => {
+                op.as_str().to_string() + &self.wrapped(operand, Position::Operand, indent, col + 1)
+            }
+
+            CallExpr { callee, args } => {
+                let args_col = col + callee.len() + 1;
+                format!("{callee}({})", self.list(args, indent, args_col))
+            }
+
+            ApplyExpr { callee, args, .. } => {
+                let callee = self.wrapped(callee, Position::Postfix, indent, col);
+                let args_col = end_col(col, &callee) + 1;
+
+                format!("{callee}({})", self.list(args, indent, args_col))
+            }
+
+            ArrayExpr(exprs) => format!("[{}]", self.list(exprs, indent, col + 1)),
+
+            ArrayFillExpr { value, count } => {
+                let value = self.expr(value, indent, col + 1);
+                let count_col = end_col(col + 1, &value) + 2;
+
+                format!("[{value}; {}]", self.expr(count, indent, count_col))
+            }
+
+            IndexExpr { array, index } => {
+                let array = self.wrapped(array, Position::Postfix, indent, col);
+                let index_col = end_col(col, &array) + 1;
+
+                format!("{array}[{}]", self.expr(index, indent, index_col))
+            }
+
+            LenExpr(operand) => format!("len({})", self.expr(operand, indent, col + 4)),
+
+            // Everything else always fits on a line
+            leaf => self.flat(leaf).unwrap(),
+        }
+    }
+
+    // The expression written on a single line, None if it can't be (it has a block)
+    fn flat(&self, expr: &ASTExpr) -> Option<String> {
+        use ASTExpr::*;
+
+        let wrapped = |expr: &ASTExpr, position| {
+            let text = self.flat(expr)?;
+
+            Some(if needs_parens(expr, position) {
+                format!("({text})")
+            } else {
+                text
+            })
+        };
+
+        let list = |exprs: &[Box<ASTExpr>]| {
+            exprs
+                .iter()
+                .map(|expr| self.flat(expr))
+                .collect::<Option<Vec<_>>>()
+                .map(|texts| texts.join(", "))
+        };
+
+        let text = match expr {
+            NumberExpr(num) => num.to_string(),
+            IntegerExpr(int) => int.to_string(),
+            BoolExpr(b) => b.to_string(),
+            StringExpr(text) => format!("\"{text}\""),
+            VariableExpr(name) => name.to_string(),
+            BreakExpr => "break".to_string(),
+            ContinueExpr => "continue".to_string(),
+
+            ArrayExpr(exprs) => format!("[{}]", list(exprs)?),
+            ArrayFillExpr { value, count } => format!("[{}; {}]", self.flat(value)?, self.flat(count)?),
+            IndexExpr { array, index } => {
+                format!("{}[{}]", wrapped(array, Position::Postfix)?, self.flat(index)?)
+            }
+            LenExpr(operand) => format!("len({})", self.flat(operand)?),
+
+            UnaryExpr { op, operand } => {
+                format!("{}{}", op.as_str(), wrapped(operand, Position::Operand)?)
+            }
+
+            BinaryExpr { op, left, right } => {
+                let prec = precedence(*op);
+
+                format!(
+                    "{} {} {}",
+                    wrapped(left, Position::Left(prec))?,
+                    op.as_str(),
+                    wrapped(right, Position::Right(prec))?
+                )
+            }
+
+            CallExpr { callee, args } => format!("{callee}({})", list(args)?),
+            ApplyExpr { callee, args, .. } => {
+                format!("{}({})", wrapped(callee, Position::Postfix)?, list(args)?)
+            }
+
+            LambdaExpr { params, body, .. } => {
+                format!("\\{} -> {}", params_str(params), self.flat(body)?)
+            }
+
+            LocalFnExpr { name, lambda, body } => {
+                let LambdaExpr {
+                    params,
+                    ret_type,
+                    body: fn_body,
+                    ..
+                } = &**lambda
+                else {
+                    unreachable!("Local functions are always lambdas");
+                };
+
+                let ret = match ret_type.filter(|ty| *ty != Type::F64) {
+                    Some(ty) => format!(" -> {ty}"),
+                    None => String::new(),
+                };
+
+                format!(
+                    "def {name}({}){ret} {} in {}",
+                    params_str(params),
+                    self.flat(fn_body)?,
+                    self.flat(body)?
+                )
+            }
+
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => format!(
+                "if {} then {} else {}",
+                self.flat(cond)?,
+                self.flat(then_branch)?,
+                self.flat(else_branch)?
+            ),
+
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let annotation = var_type.map_or(String::new(), |ty| format!(": {ty}"));
+                let step = match is_default_step(step) {
+                    true => String::new(),
+                    false => format!(", {}", self.flat(step)?),
+                };
+
+                format!(
+                    "for {varname}{annotation} = {}, {}{step} in {}",
+                    self.flat(start)?,
+                    self.flat(end)?,
+                    self.flat(body)?
+                )
+            }
+
+            WhileExpr { cond, body } => {
+                format!("while {} do {}", self.flat(cond)?, self.flat(body)?)
+            }
+
+            VarExpr { var_names, body } => {
+                let bindings = var_names
+                    .iter()
+                    .map(|(name, annotation, init)| {
+                        let mut binding = name.to_string();
+
+                        if let Some(ty) = annotation {
+                            binding += &format!(": {ty}");
+                        }
+
+                        if let Some(init) = init {
+                            binding += &format!(" = {}", self.flat(init)?);
+                        }
+
+                        Some(binding)
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+
+                format!("var {} in {}", bindings.join(", "), self.flat(body)?)
+            }
+
+            BlockExpr(_) => return None,
+        };
+
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_layout_consistently() {
+        let src = "def fib(x)
+  if (x < 3) then
+    1
+  else
+    fib(x-1)+fib(x-2);
+
+extern   putchard(c)  ;
+extern printd(x);
+def fibi(x) var a = 1, b = 1, c in { for i = 3, i < x in { c = a + b; a = b; b = c }; b };
+fibi(10)*(2-1);
+";
+
+        let formatted = "def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2);
+
+extern putchard(c);
+extern printd(x);
+
+def fibi(x) var a = 1, b = 1, c in {
+  for i = 3, i < x in {
+    c = a + b;
+    a = b;
+    b = c
+  };
+  b
+};
+
+fibi(10) * (2 - 1);
+";
+
+        assert_eq!(format_source(src).unwrap(), formatted);
+
+        // Formatting twice changes nothing
+        assert_eq!(format_source(formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn keeps_comments_and_breaks_long_lines() {
+        let src = "# Counts down
+def countdown(n)
+  # from n
+  while n > 0 do n = n - 1; # the end
+";
+
+        let formatted = "# Counts down
+def countdown(n)
+  # from n
+  while n > 0 do n = n - 1;
+
+# the end
+";
+
+        assert_eq!(format_source(src).unwrap(), formatted);
+
+        let long = "def long(alpha beta gamma)
+            if alpha < beta then alpha * gamma + beta * gamma * alpha else beta * beta * gamma - alpha;";
+
+        assert_eq!(
+            format_source(long).unwrap(),
+            "def long(alpha, beta, gamma)
+  if alpha < beta then
+    alpha * gamma + beta * gamma * alpha
+  else
+    beta * beta * gamma - alpha;
+"
+        );
+    }
+
+    #[test]
+    fn comments_wait_for_an_item_with_names() {
+        let src = "# four
+4;
+# square
+def sq(x) x * x;
+# after
+";
+
+        let formatted = "4;
+
+# four
+# square
+def sq(x) x * x;
+
+# after
+";
+
+        assert_eq!(format_source(src).unwrap(), formatted);
+    }
+
+    #[test]
+    fn parenthesizes_by_precedence() {
+        let src = "def f(a b c) (a - (b - c)) * -(a + b) + (a - b) - c;";
+
+        assert_eq!(
+            format_source(src).unwrap(),
+            "def f(a, b, c) (a - (b - c)) * -(a + b) + (a - b) - c;\n"
+        );
+    }
+}
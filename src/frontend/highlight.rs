@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::iter;
+
+use serde_json::{json, Value};
+
+use crate::frontend::{
+    analysis::{analyze, Analysis, SymbolKind},
+    ast::{Span, Type},
+    lexer::{comments, Lex, Token},
+};
+
+// Highlighting by what each piece of source means, rather than what it looks like,
+// for editors (see the language server) and for the docs. Lexing alone can't tell a
+// function from a variable, or an operator the language has from one the program
+// defines with `def binary`, so names are looked up in the analysis of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Keyword,
+    Number,
+    String,
+    Function,
+    Parameter,
+    Variable,
+    Global,
+    Type,
+    Operator,
+    UserOperator,
+    Punctuation,
+    Comment,
+}
+
+impl Class {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Class::Keyword => "keyword",
+            Class::Number => "number",
+            Class::String => "string",
+            Class::Function => "function",
+            Class::Parameter => "parameter",
+            Class::Variable => "variable",
+            Class::Global => "global",
+            Class::Type => "type",
+            Class::Operator => "operator",
+            Class::UserOperator => "user-operator",
+            Class::Punctuation => "punctuation",
+            Class::Comment => "comment",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Highlight {
+    pub span: Span,
+    pub class: Class,
+}
+
+// Every token and comment in src, in the order they were written. Whitespace, and
+// anything the lexer doesn't know, is left out.
+pub fn classify(src: &str) -> Vec<Highlight> {
+    classify_analyzed(src, &analyze(src))
+}
+
+// Same as classify, for when src has been analyzed already
+pub fn classify_analyzed(src: &str, analysis: &Analysis) -> Vec<Highlight> {
+    let user_operators = analysis
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.kind == SymbolKind::Operator)
+        .map(|symbol| symbol.name.as_str())
+        .collect::<HashSet<_>>();
+
+    // The symbol a name defines or refers to, if the analysis found one
+    let symbol_kind = |span: Span| {
+        analysis
+            .references
+            .iter()
+            .find(|reference| reference.span == span)
+            .map(|reference| reference.symbol)
+            .or_else(|| analysis.symbols.iter().position(|symbol| symbol.span == Some(span)))
+            .map(|symbol| analysis.symbols[symbol].kind)
+    };
+
+    let mut lexer = src.lex();
+    let tokens = iter::from_fn(|| lexer.next_spanned()).collect::<Vec<_>>();
+
+    let mut highlights = vec![];
+
+    for (idx, &(token, slice)) in tokens.iter().enumerate() {
+        let Some(span) = Span::of(src, slice) else {
+            continue;
+        };
+
+        let class = match token {
+            Token::FuncDef
+            | Token::Extern
+            | Token::If
+            | Token::Then
+            | Token::Else
+            | Token::For
+            | Token::In
+            | Token::UnaryOverload
+            | Token::BinaryOverload
+            | Token::Var
+            | Token::Bool(_)
+            | Token::While
+            | Token::Do
+            | Token::Break
+            | Token::Continue
+            | Token::Global => Class::Keyword,
+
+            Token::Number(_) => Class::Number,
+            Token::Str(_) => Class::String,
+
+            Token::Identifier(name) => match symbol_kind(span) {
+                Some(SymbolKind::Function | SymbolKind::Extern) => Class::Function,
+                Some(SymbolKind::Parameter) => Class::Parameter,
+                Some(SymbolKind::Variable) => Class::Variable,
+                Some(SymbolKind::Global) => Class::Global,
+                Some(SymbolKind::Operator) => Class::UserOperator,
+
+                // Not defined anywhere in src, a type, a builtin like len, or a mistake
+                None if name == "fn" || Type::from_name(name).is_some() => Class::Type,
+                None if matches!(tokens.get(idx + 1), Some((Token::OpenParen, _))) => Class::Function,
+                None => Class::Variable,
+            },
+
+            Token::Operator(op) if user_operators.contains(op.as_str()) => Class::UserOperator,
+            Token::Operator(_) => Class::Operator,
+
+            Token::OpenParen
+            | Token::ClosedParen
+            | Token::Comma
+            | Token::Semicolon
+            | Token::Arrow
+            | Token::OpenBracket
+            | Token::ClosedBracket
+            | Token::OpenBrace
+            | Token::ClosedBrace
+            | Token::Backslash => Class::Punctuation,
+
+            Token::Unknown(_) => continue,
+        };
+
+        highlights.push(Highlight { span, class });
+    }
+
+    let comment_spans = comments(src).into_iter().filter_map(|comment| Span::of(src, comment));
+    highlights.extend(comment_spans.map(|span| Highlight {
+        span,
+        class: Class::Comment,
+    }));
+
+    highlights.sort_by_key(|highlight| highlight.span.start);
+    highlights
+}
+
+// [{"start": 0, "end": 3, "line": 1, "column": 1, "class": "keyword", "text": "def"}, ...]
+// start and end are byte offsets, line and column count characters from 1
+pub fn to_json(src: &str, highlights: &[Highlight]) -> Value {
+    let highlights = highlights
+        .iter()
+        .map(|highlight| {
+            let Span { start, end } = highlight.span;
+            let (line, column) = highlight.span.line_col(src);
+
+            json!({
+                "start": start,
+                "end": end,
+                "line": line,
+                "column": column,
+                "class": highlight.class.as_str(),
+                "text": &src[start..end],
+            })
+        })
+        .collect();
+
+    Value::Array(highlights)
+}
+
+// The source in a <pre>, every highlight wrapped in a <span class="k-..."> for a
+// stylesheet to color, and everything in between left as it was written
+pub fn to_html(src: &str, highlights: &[Highlight]) -> String {
+    let mut html = String::from("<pre class=\"kaleidoscope\"><code>");
+    let mut written = 0;
+
+    for highlight in highlights {
+        let Span { start, end } = highlight.span;
+
+        html += &escape_html(&src[written..start]);
+        html += &format!(
+            "<span class=\"k-{}\">{}</span>",
+            highlight.class.as_str(),
+            escape_html(&src[start..end])
+        );
+
+        written = end;
+    }
+
+    html += &escape_html(&src[written..]);
+    html + "</code></pre>\n"
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classes(src: &str) -> Vec<(&str, &str)> {
+        classify(src)
+            .into_iter()
+            .map(|highlight| (&src[highlight.span.start..highlight.span.end], highlight.class.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn names_are_classified_by_what_they_are() {
+        let src = "# Logical or\n\
+                   def binary| 5 (l r) if l then 1 else r;\n\
+                   def f(x: f64) var y = x | 0 in len([y]) + g(y);";
+
+        assert_eq!(
+            classes(src),
+            vec![
+                ("# Logical or", "comment"),
+                ("def", "keyword"),
+                ("binary", "keyword"),
+                ("|", "user-operator"),
+                ("5", "number"),
+                ("(", "punctuation"),
+                ("l", "parameter"),
+                ("r", "parameter"),
+                (")", "punctuation"),
+                ("if", "keyword"),
+                ("l", "parameter"),
+                ("then", "keyword"),
+                ("1", "number"),
+                ("else", "keyword"),
+                ("r", "parameter"),
+                (";", "punctuation"),
+                ("def", "keyword"),
+                ("f", "function"),
+                ("(", "punctuation"),
+                ("x", "parameter"),
+                (":", "operator"),
+                ("f64", "type"),
+                (")", "punctuation"),
+                ("var", "keyword"),
+                ("y", "variable"),
+                ("=", "operator"),
+                ("x", "parameter"),
+                ("|", "user-operator"),
+                ("0", "number"),
+                ("in", "keyword"),
+                ("len", "function"),
+                ("(", "punctuation"),
+                ("[", "punctuation"),
+                ("y", "variable"),
+                ("]", "punctuation"),
+                (")", "punctuation"),
+                ("+", "operator"),
+                ("g", "function"),
+                ("(", "punctuation"),
+                ("y", "variable"),
+                (")", "punctuation"),
+                (";", "punctuation"),
+            ]
+        );
+    }
+
+    #[test]
+    fn html_keeps_the_source_as_written() {
+        let src = "global s = \"<b>\";  # a & b";
+        let html = to_html(src, &classify(src));
+
+        assert_eq!(
+            html,
+            "<pre class=\"kaleidoscope\"><code>\
+             <span class=\"k-keyword\">global</span> <span class=\"k-global\">s</span> \
+             <span class=\"k-operator\">=</span> <span class=\"k-string\">&quot;&lt;b&gt;&quot;</span>\
+             <span class=\"k-punctuation\">;</span>  <span class=\"k-comment\"># a &amp; b</span>\
+             </code></pre>\n"
+        );
+    }
+}
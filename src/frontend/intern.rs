@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::frontend::ast::{ASTExpr, Function, Global, Item, Prototype, VarBinding};
+
+// Every name in the tree borrows from the source it was parsed from, which keeps
+// parsing free of copies, but means a tree can't outlive its source. The REPL reads
+// each line into the same buffer, so nothing it parsed survives to the next line.
+//
+// Interning a tree copies every name into the symbol table, where each distinct name
+// is allocated once and lives for the rest of the program (like function signatures,
+// see Type::function), giving back the same tree, borrowing from nothing. The copy
+// no longer points into the source, so Span::of can't find where its names were.
+lazy_static! {
+    static ref SYMBOLS: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+pub fn intern(name: &str) -> &'static str {
+    let mut symbols = SYMBOLS.lock().unwrap();
+
+    match symbols.get(name) {
+        Some(symbol) => symbol,
+        None => {
+            let symbol: &'static str = Box::leak(name.to_string().into_boxed_str());
+            symbols.insert(symbol);
+            symbol
+        }
+    }
+}
+
+// Trees that outlive their source
+pub type OwnedExpr = ASTExpr<'static>;
+pub type OwnedPrototype = Prototype<'static>;
+pub type OwnedFunction = Function<'static>;
+pub type OwnedItem = Item<'static>;
+
+impl Function<'_> {
+    pub fn interned(&self) -> OwnedFunction {
+        Function {
+            proto: Box::new(self.proto.interned()),
+            body: Box::new(self.body.interned()),
+        }
+    }
+}
+
+impl Global<'_> {
+    pub fn interned(&self) -> Global<'static> {
+        Global {
+            name: intern(self.name),
+            var_type: self.var_type,
+            init: interned_opt(&self.init),
+        }
+    }
+}
+
+impl Prototype<'_> {
+    pub fn interned(&self) -> OwnedPrototype {
+        match self {
+            Prototype::FunctionProto {
+                name,
+                args,
+                ret_type,
+            } => Prototype::FunctionProto {
+                name: intern(name),
+                args: args.iter().map(|(name, ty)| (intern(name), *ty)).collect(),
+                ret_type: *ret_type,
+            },
+
+            Prototype::OverloadedUnaryOpProto { operator, arg } => Prototype::OverloadedUnaryOpProto {
+                operator: *operator,
+                arg: intern(arg),
+            },
+
+            Prototype::OverloadedBinaryOpProto {
+                operator,
+                args: (lhs, rhs),
+                precedence,
+            } => Prototype::OverloadedBinaryOpProto {
+                operator: *operator,
+                args: (intern(lhs), intern(rhs)),
+                precedence: *precedence,
+            },
+        }
+    }
+}
+
+fn interned_all(exprs: &[Box<ASTExpr>]) -> Vec<Box<OwnedExpr>> {
+    exprs.iter().map(|expr| Box::new(expr.interned())).collect()
+}
+
+fn interned_opt(expr: &Option<Box<ASTExpr>>) -> Option<Box<OwnedExpr>> {
+    expr.as_ref().map(|expr| Box::new(expr.interned()))
+}
+
+impl ASTExpr<'_> {
+    pub fn interned(&self) -> OwnedExpr {
+        use ASTExpr::*;
+
+        let boxed = |expr: &ASTExpr| Box::new(expr.interned());
+
+        match self {
+            NumberExpr(num) => NumberExpr(*num),
+            IntegerExpr(int) => IntegerExpr(*int),
+            BoolExpr(b) => BoolExpr(*b),
+            StringExpr(text) => StringExpr(intern(text)),
+            VariableExpr(name) => VariableExpr(intern(name)),
+            BreakExpr => BreakExpr,
+            ContinueExpr => ContinueExpr,
+
+            ArrayExpr(exprs) => ArrayExpr(interned_all(exprs)),
+            BlockExpr(exprs) => BlockExpr(interned_all(exprs)),
+
+            ArrayFillExpr { value, count } => ArrayFillExpr {
+                value: boxed(value),
+                count: boxed(count),
+            },
+
+            IndexExpr { array, index } => IndexExpr {
+                array: boxed(array),
+                index: boxed(index),
+            },
+
+            LenExpr(operand) => LenExpr(boxed(operand)),
+
+            UnaryExpr { op, operand } => UnaryExpr {
+                op: *op,
+                operand: boxed(operand),
+            },
+
+            BinaryExpr { op, left, right } => BinaryExpr {
+                op: *op,
+                left: boxed(left),
+                right: boxed(right),
+            },
+
+            CallExpr { callee, args } => CallExpr {
+                callee: intern(callee),
+                args: interned_all(args),
+            },
+
+            ApplyExpr {
+                callee,
+                args,
+                callee_type,
+            } => ApplyExpr {
+                callee: boxed(callee),
+                args: interned_all(args),
+                callee_type: *callee_type,
+            },
+
+            LambdaExpr {
+                params,
+                ret_type,
+                captures,
+                self_name,
+                body,
+            } => LambdaExpr {
+                params: params.iter().map(|(name, ty)| (intern(name), *ty)).collect(),
+                ret_type: *ret_type,
+                captures: captures.iter().map(|name| intern(name)).collect(),
+                self_name: self_name.map(intern),
+                body: boxed(body),
+            },
+
+            LocalFnExpr { name, lambda, body } => LocalFnExpr {
+                name: intern(name),
+                lambda: boxed(lambda),
+                body: boxed(body),
+            },
+
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => IfExpr {
+                cond: boxed(cond),
+                then_branch: boxed(then_branch),
+                else_branch: boxed(else_branch),
+            },
+
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            } => ForLoopExpr {
+                varname: intern(varname),
+                var_type: *var_type,
+                start: boxed(start),
+                end: boxed(end),
+                step: boxed(step),
+                body: boxed(body),
+            },
+
+            WhileExpr { cond, body } => WhileExpr {
+                cond: boxed(cond),
+                body: boxed(body),
+            },
+
+            VarExpr { var_names, body } => VarExpr {
+                var_names: var_names
+                    .iter()
+                    .map(|(name, ty, init)| -> VarBinding<'static> { (intern(name), *ty, interned_opt(init)) })
+                    .collect(),
+                body: boxed(body),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{
+        lexer::Lex,
+        parser::{parse_definition, parse_global},
+    };
+
+    #[test]
+    fn interned_trees_outlive_their_source() {
+        let items: Vec<OwnedItem> = {
+            let src = String::from(
+                r#"def f(x: i64) var k = 2 in for i = 0, i < x in (\y -> y * k)(i);
+                   global name = "kaleidoscope";"#,
+            );
+
+            let mut tokens = src.lex().peekable();
+            let func = parse_definition(&mut tokens).unwrap();
+            let _semicolon = tokens.next();
+            let global = parse_global(&mut tokens).unwrap();
+
+            let owned = vec![
+                Item::Function(Box::new(func.interned())),
+                Item::Global(Box::new(global.interned())),
+            ];
+
+            // Same tree, the names just live somewhere else
+            assert_eq!(owned, vec![Item::Function(func), Item::Global(global)]);
+            owned
+        };
+
+        let Item::Global(global) = &items[1] else {
+            panic!("Expected a global");
+        };
+
+        assert_eq!(global.name, "name");
+
+        // Every copy of a name is the same symbol
+        assert!(std::ptr::eq(intern("name"), global.name));
+    }
+}
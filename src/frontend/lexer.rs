@@ -1,5 +1,3 @@
-use std::str::SplitWhitespace;
-
 // Our tokens for the Kaleidoscope language, in the original
 // tutorial, delimiters like commas, parenthesis, semicolons
 // were not in the enum, but where inferred to be understood
@@ -28,6 +26,19 @@ pub enum Token<'src> {
     UnaryOverload = 15,
     BinaryOverload = 16,
     Var = 17,
+    Arrow = 18,
+    Bool(bool) = 19,
+    Str(&'src str) = 20, // Contents between the quotes, escapes are left as written in source
+    OpenBracket = 21,
+    ClosedBracket = 22,
+    While = 23,
+    Do = 24,
+    Break = 25,
+    Continue = 26,
+    OpenBrace = 27,
+    ClosedBrace = 28,
+    Global = 29,
+    Backslash = 30,
     Unknown(&'src str) = 255,
 }
 
@@ -50,7 +61,8 @@ pub enum Ops {
     // Assignment
     Assign = 8,
 
-    // These operators are left undefined, but can be overloaded by developers
+    // Logical/bitwise on booleans and integers, left undefined for doubles,
+    // where they can be overloaded by developers
     Or = 9,      // "|"
     And = 10,    // "&"
     Xor = 11,    // "^"
@@ -79,6 +91,15 @@ impl Ops {
             Assign => &"=",
         }
     }
+
+    // The operator written as symbol, the reverse of as_str
+    pub fn from_symbol(symbol: &str) -> Option<Ops> {
+        use Ops::*;
+
+        [Plus, Minus, Mult, Div, Eq, Neq, Lt, Gt, Assign, Or, And, Xor, Negate, Colon]
+            .into_iter()
+            .find(|op| op.as_str() == symbol)
+    }
 }
 
 // For strings with no whitespace, need to be able to find out
@@ -87,9 +108,8 @@ impl Ops {
 impl<'src> Token<'src> {
     fn is_single_char_token(c: char) -> bool {
         match c {
-            '+' | '-' | '*' | '/' | ';' | ',' | '(' | ')' | '<' | '>' | '|' | '&' | '^' | ':' | '!' => {
-                true
-            }
+            '+' | '-' | '*' | '/' | ';' | ',' | '(' | ')' | '[' | ']' | '{' | '}' | '<' | '>' | '|'
+            | '&' | '^' | ':' | '!' | '\\' => true,
 
             _ => false,
         }
@@ -111,9 +131,16 @@ fn tokenize(string: &str) -> Token {
         "else" => Else,
         "for" => For,
         "in" => In,
+        "while" => While,
+        "do" => Do,
+        "break" => Break,
+        "continue" => Continue,
         "unary" => UnaryOverload,
         "binary" => BinaryOverload,
         "var" => Var,
+        "global" => Global,
+        "true" => Bool(true),
+        "false" => Bool(false),
 
         // Operators
         "+" => Operator(Ops::Plus),
@@ -131,14 +158,37 @@ fn tokenize(string: &str) -> Token {
         ":" => Operator(Ops::Colon),
         "=" => Operator(Ops::Assign),
 
+        // Return type annotation, also separates the parameters of a lambda from its body
+        "->" => Arrow,
+
+        // Starts a lambda
+        "\\" => Backslash,
+
         // Parenthesis
         "(" => OpenParen,
         ")" => ClosedParen,
 
+        // Brackets, for arrays
+        "[" => OpenBracket,
+        "]" => ClosedBracket,
+
+        // Braces, for blocks
+        "{" => OpenBrace,
+        "}" => ClosedBrace,
+
         //Delimiters
         "," => Comma,
         ";" => Semicolon,
 
+        // String literals, which the Chunks iterator always hands us whole
+        text if text.starts_with('"') => {
+            if text.len() > 1 && text.ends_with('"') {
+                Str(&text[1..text.len() - 1])
+            } else {
+                Unknown(text)
+            }
+        }
+
         // Everything else
         text => {
             if let Ok(num) = text.parse::<f64>() {
@@ -154,8 +204,111 @@ fn tokenize(string: &str) -> Token {
     }
 }
 
+// Splits source code into slices on whitespace, much like SplitWhitespace
+// does, except a string literal always ends up in a slice of its own, kept
+// whole along with any spaces inside of it. Slices like 'print("hi")'
+// come out as 'print(', '"hi"' and ')'. Comments, from a # to the end of
+// the line like in the tutorial, are skipped over.
+#[derive(Debug, Clone)]
+pub struct Chunks<'src> {
+    rest: &'src str,
+}
+
+impl<'src> Iterator for Chunks<'src> {
+    type Item = &'src str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (piece, rest) = next_piece(self.rest)?;
+            self.rest = rest;
+
+            if !piece.starts_with('#') {
+                return Some(piece);
+            }
+        }
+    }
+}
+
+// Every comment in the source, the lexer throws them away, but the formatter
+// puts them back where they were
+pub fn comments(src: &str) -> Vec<&str> {
+    let mut comments = vec![];
+    let mut rest = src;
+
+    while let Some((piece, after)) = next_piece(rest) {
+        if piece.starts_with('#') {
+            comments.push(piece.trim_end());
+        }
+
+        rest = after;
+    }
+
+    comments
+}
+
+// The next chunk or comment of src, and whatever follows it
+fn next_piece(src: &str) -> Option<(&str, &str)> {
+    let src = src.trim_start();
+
+    if src.is_empty() {
+        return None;
+    }
+
+    let end = if src.starts_with('#') {
+        src.find('\n').unwrap_or(src.len())
+    } else if src.starts_with('"') {
+        // Find the closing quote, skipping over escaped characters. An unterminated
+        // string takes up the rest of the input, the tokenizer will reject it.
+        let mut escaped = false;
+
+        src.char_indices()
+            .skip(1)
+            .find(|&(_, c)| match (escaped, c) {
+                (true, _) => {
+                    escaped = false;
+                    false
+                }
+                (false, '\\') => {
+                    escaped = true;
+                    false
+                }
+                (false, c) => c == '"',
+            })
+            .map_or(src.len(), |(idx, _)| idx + 1)
+    } else {
+        src.find(|c: char| c.is_whitespace() || c == '"' || c == '#')
+            .unwrap_or(src.len())
+    };
+
+    Some(src.split_at(end))
+}
+
+// Replace the escape sequences found in a string literal with the characters
+// they stand for, the backend calls this before emitting the string constant.
+pub fn unescape(raw: &str) -> String {
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            Some(other) => unescaped.push(other), // covers \\ and \"
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
 // Our iterator adapter for producing Kaleidoscope tokens,
-// the only iterator "I" we really use here is SplitWhitespace, but
+// the only iterator "I" we really use here is Chunks, but
 // so it is a bit needless to make this generic, but just following
 // typical iterator adapter nature.
 //
@@ -175,8 +328,24 @@ where
     type Item = Token<'src>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.next_spanned().map(|(token, _slice)| token)
+    }
+}
+
+impl<'src, I> Tokens<'src, I>
+where
+    I: Iterator<Item = &'src str>,
+{
+    // The next token, along with the slice of source it was lexed from, which tells
+    // tools like the language server where in the source the token was
+    pub fn next_spanned(&mut self) -> Option<(Token<'src>, &'src str)> {
         let mut slice = self.leftover_slice.take().or_else(|| self.iter.next())?;
 
+        // String literals are never broken apart, they may contain anything
+        if slice.starts_with('"') {
+            return Some((tokenize(slice), slice));
+        }
+
         if slice.len() > 1 {
             if let Some(pos) = slice.find(Token::is_single_char_token) {
                 if pos != 0 {
@@ -184,14 +353,20 @@ where
                     slice = immed;
                     self.leftover_slice.replace(rest);
                 } else {
-                    let (immed, rest) = slice.split_at(1);
-                    slice = immed;
-                    self.leftover_slice.replace(rest);
+                    // The return type arrow is the only token made of two single char
+                    // tokens, make sure we don't break it apart into minus and greater than
+                    let token_len = if slice.starts_with("->") { 2 } else { 1 };
+
+                    if token_len < slice.len() {
+                        let (immed, rest) = slice.split_at(token_len);
+                        slice = immed;
+                        self.leftover_slice.replace(rest);
+                    }
                 }
             }
         }
 
-        Some(tokenize(slice))
+        Some((tokenize(slice), slice))
     }
 }
 
@@ -201,12 +376,12 @@ where
 // let source_code = read_source_code();
 // let tokens: Vec<Token> = source_code.lex().collect()
 pub trait Lex {
-    fn lex(&self) -> Tokens<SplitWhitespace>;
+    fn lex(&self) -> Tokens<Chunks>;
 }
 
 impl Lex for str {
-    fn lex(&self) -> Tokens<SplitWhitespace> {
-        Tokens::new(self.split_whitespace())
+    fn lex(&self) -> Tokens<Chunks> {
+        Tokens::new(Chunks { rest: self })
     }
 }
 
@@ -375,4 +550,189 @@ mod tests {
             vec![FuncDef, Identifier(&"noParamsCall"), OpenParen, ClosedParen,]
         );
     }
+
+    #[test]
+    fn lexing_type_annotations() {
+        let input = " def f(x: i64, flag: bool)->bool flag == true ";
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                FuncDef,
+                Identifier(&"f"),
+                OpenParen,
+                Identifier(&"x"),
+                Operator(Colon),
+                Identifier(&"i64"),
+                Comma,
+                Identifier(&"flag"),
+                Operator(Colon),
+                Identifier(&"bool"),
+                ClosedParen,
+                Arrow,
+                Identifier(&"bool"),
+                Identifier(&"flag"),
+                Operator(Eq),
+                Bool(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_strings() {
+        let input = r#" prints("hello, world (again)") + "say \"hi\""+"" "#;
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                Identifier(&"prints"),
+                OpenParen,
+                Str(&"hello, world (again)"),
+                ClosedParen,
+                Operator(Plus),
+                Str(&r#"say \"hi\""#),
+                Operator(Plus),
+                Str(&""),
+            ]
+        );
+
+        assert_eq!(unescape(r#"say \"hi\"\n"#), "say \"hi\"\n");
+        assert_eq!(" \"never closed ".lex().collect::<Vec<Token>>(), vec![Unknown(&"\"never closed ")]);
+    }
+
+    #[test]
+    fn lexing_arrays() {
+        let input = " var a: [f64] = [1, 2.5] in a[0] = len(a) ";
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                Var,
+                Identifier(&"a"),
+                Operator(Colon),
+                OpenBracket,
+                Identifier(&"f64"),
+                ClosedBracket,
+                Operator(Assign),
+                OpenBracket,
+                Number(1.0),
+                Comma,
+                Number(2.5),
+                ClosedBracket,
+                In,
+                Identifier(&"a"),
+                OpenBracket,
+                Number(0.0),
+                ClosedBracket,
+                Operator(Assign),
+                Identifier(&"len"),
+                OpenParen,
+                Identifier(&"a"),
+                ClosedParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_while_loops() {
+        let input = " while i < 10 do if done then break else continue ";
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                While,
+                Identifier(&"i"),
+                Operator(Lt),
+                Number(10.0),
+                Do,
+                If,
+                Identifier(&"done"),
+                Then,
+                Break,
+                Else,
+                Continue,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_blocks() {
+        let input = " {x = 1; printd(x);} ";
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                OpenBrace,
+                Identifier(&"x"),
+                Operator(Assign),
+                Number(1.0),
+                Semicolon,
+                Identifier(&"printd"),
+                OpenParen,
+                Identifier(&"x"),
+                ClosedParen,
+                Semicolon,
+                ClosedBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_lambdas() {
+        let input = r" \x, y: i64 -> x*y; f: fn(f64)->f64 ";
+        let tokens = input.lex();
+
+        assert_eq!(
+            tokens.collect::<Vec<Token>>(),
+            vec![
+                Backslash,
+                Identifier(&"x"),
+                Comma,
+                Identifier(&"y"),
+                Operator(Colon),
+                Identifier(&"i64"),
+                Arrow,
+                Identifier(&"x"),
+                Operator(Mult),
+                Identifier(&"y"),
+                Semicolon,
+                Identifier(&"f"),
+                Operator(Colon),
+                Identifier(&"fn"),
+                OpenParen,
+                Identifier(&"f64"),
+                ClosedParen,
+                Arrow,
+                Identifier(&"f64"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_comments() {
+        let input = "# Doubles it\ndef dub(x) x*2;# not \"a string\"\n\"# not a comment\"";
+
+        assert_eq!(
+            input.lex().collect::<Vec<Token>>(),
+            vec![
+                FuncDef,
+                Identifier(&"dub"),
+                OpenParen,
+                Identifier(&"x"),
+                ClosedParen,
+                Identifier(&"x"),
+                Operator(Mult),
+                Number(2.0),
+                Semicolon,
+                Str(&"# not a comment"),
+            ]
+        );
+
+        assert_eq!(comments(input), vec!["# Doubles it", "# not \"a string\""]);
+    }
 }
@@ -1,3 +1,13 @@
+pub mod analysis;
 pub mod ast;
+pub mod format;
+pub mod highlight;
+pub mod intern;
 pub mod lexer;
 pub mod parser;
+pub mod prelude;
+pub mod resolve;
+pub mod scope;
+pub mod serialize;
+pub mod simplify;
+pub mod typecheck;
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::iter::Peekable;
 
@@ -25,10 +26,50 @@ lazy_static! {
         map.insert(Ops::Neq, 50);
         map.insert(Ops::Gt, 50);
         map.insert(Ops::Lt, 50);
+        map.insert(Ops::Or, 5);
+        map.insert(Ops::Xor, 5);
+        map.insert(Ops::And, 6);
         map.into()
     };
 }
 
+// Runs f and puts the precedences back the way they were before it, whatever
+// operators f parses the definitions of (and even if it panics). For tools parsing
+// sources that aren't the program being run, which shouldn't change how it parses.
+pub fn keeping_precedence<T>(f: impl FnOnce() -> T) -> T {
+    struct Restore(HashMap<Ops, i32>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if let Ok(mut precedence_map) = OP_PRECEDENCE.write() {
+                *precedence_map = std::mem::take(&mut self.0);
+            }
+        }
+    }
+
+    let _restore = Restore(OP_PRECEDENCE.read().unwrap().clone());
+
+    f()
+}
+
+// How many loop bodies deep the parser currently is, break and continue
+// only make sense inside of one. Kept per thread since tests run in parallel.
+thread_local! {
+    static LOOP_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Parses a loop body, keeping track of the fact that we are inside a loop.
+// The depth is restored whether or not the body parsed successfully.
+fn parse_loop_body<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    LOOP_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    let body = parse_expression(tokens);
+    LOOP_DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+    body
+}
+
 // Few errors here to character what went wrong during the
 // parsing process.
 #[derive(Error, PartialEq, Debug)]
@@ -47,6 +88,12 @@ pub enum ParserError<'src> {
 
     #[error("Binary operator signatures require two arguments & positive number for precedence")]
     BadOverloadedBinaryOp,
+
+    #[error("Unknown type {0}, expected one of f64/i64/bool/str/fn")]
+    UnknownType(&'src str),
+
+    #[error("'{0}' used outside of a loop")]
+    OutsideOfLoop(&'static str),
 }
 
 /// external ::= 'extern' prototype
@@ -58,8 +105,83 @@ pub fn parse_extern<'src>(
     parse_prototype(tokens)
 }
 
+/// type
+///   ::= 'f64' | 'i64' | 'bool' | 'str' | '[' 'f64' ']'
+///   ::= 'fn' '(' (type ','?)* ')' '->' type
+pub fn parse_type<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> Result<Type, ParserError<'src>> {
+    match tokens.next() {
+        Some(Token::Identifier("fn")) => {
+            tokens
+                .next_if(|t| matches!(t, Token::OpenParen))
+                .ok_or(ParserError::ExpectedToken(&"("))?;
+
+            let mut params = vec![];
+
+            while tokens.next_if(|t| matches!(t, Token::ClosedParen)).is_none() {
+                params.push(parse_type(tokens)?);
+                let _ = tokens.next_if(|t| matches!(t, Token::Comma));
+            }
+
+            tokens
+                .next_if(|t| matches!(t, Token::Arrow))
+                .ok_or(ParserError::ExpectedToken(&"->"))?;
+
+            Ok(Type::function(params, parse_type(tokens)?))
+        }
+
+        Some(Token::Identifier(name)) => Type::from_name(name).ok_or(ParserError::UnknownType(name)),
+
+        // Arrays only ever hold doubles
+        Some(Token::OpenBracket) => {
+            let Some(Token::Identifier("f64")) = tokens.next() else {
+                return Err(ParserError::ExpectedToken(&"f64"));
+            };
+
+            tokens
+                .next_if(|t| matches!(t, Token::ClosedBracket))
+                .ok_or(ParserError::ExpectedToken(&"]"))?;
+
+            Ok(Type::Array)
+        }
+
+        Some(unexpected) => Err(ParserError::UnexpectedToken(unexpected)),
+        None => Err(ParserError::UnexpectedEOI),
+    }
+}
+
+/// annotation ::= (':' type)?
+fn parse_type_annotation<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> Result<Option<Type>, ParserError<'src>> {
+    if let Some(Token::Operator(Ops::Colon)) = tokens.peek() {
+        let _colon = tokens.next();
+        parse_type(tokens).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// params ::= (id annotation ','?)*
+fn parse_params<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> Result<Vec<(&'src str, Option<Type>)>, ParserError<'src>> {
+    let mut params = vec![];
+
+    // Parameters may be separated by whitespace like the tutorial, or by commas,
+    // each one optionally followed by a type annotation
+    while let Some(Token::Identifier(s)) = tokens.peek().copied() {
+        let _ = tokens.next();
+        params.push((s, parse_type_annotation(tokens)?));
+        let _ = tokens.next_if(|t| matches!(t, Token::Comma));
+    }
+
+    Ok(params)
+}
+
 /// prototype
-///   ::= id '(' id* ')'
+///   ::= id '(' (id annotation ','?)* ')' ('->' type)?
 pub fn parse_prototype<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
 ) -> Result<Box<Prototype<'src>>, ParserError<'src>> {
@@ -69,18 +191,22 @@ pub fn parse_prototype<'src>(
                 .next_if(|t| matches!(t, Token::OpenParen))
                 .ok_or(ParserError::ExpectedToken(&"("))?;
 
-            let mut args = vec![];
-
-            while let Some(Token::Identifier(s)) = tokens.peek() {
-                args.push(*s);
-                let _ = tokens.next();
-            }
+            let args = parse_params(tokens)?;
 
             let _ = tokens
                 .next_if(|t| matches!(t, Token::ClosedParen))
                 .ok_or(ParserError::ExpectedToken(&")"))?;
 
-            Ok(Box::new(Prototype::FunctionProto { name, args }))
+            let ret_type = match tokens.next_if(|t| matches!(t, Token::Arrow)) {
+                Some(_arrow) => Some(parse_type(tokens)?),
+                None => None,
+            };
+
+            Ok(Box::new(Prototype::FunctionProto {
+                name,
+                args,
+                ret_type,
+            }))
         }
 
         Some(Token::UnaryOverload) => {
@@ -163,6 +289,31 @@ pub fn parse_definition<'src>(
     Ok(Box::new(Function { proto, body }))
 }
 
+/// global ::= 'global' identifier annotation ('=' expression)?
+pub fn parse_global<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> Result<Box<Global<'src>>, ParserError<'src>> {
+    // swallow the global keyword
+    let _global = tokens.next();
+
+    let Some(Token::Identifier(name)) = tokens.next() else {
+        return Err(ParserError::ExpectedToken("<identifier>"));
+    };
+
+    let var_type = parse_type_annotation(tokens)?;
+
+    let init = match tokens.next_if(|t| matches!(t, Token::Operator(Ops::Assign))) {
+        Some(_assign) => Some(parse_expression(tokens)?),
+        None => None,
+    };
+
+    Ok(Box::new(Global {
+        name,
+        var_type,
+        init,
+    }))
+}
+
 /// toplevelexpr ::= expression
 pub fn parse_top_level_expr<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
@@ -172,20 +323,41 @@ pub fn parse_top_level_expr<'src>(
     let proto = Box::new(Prototype::FunctionProto {
         name: &"__anonymous_expr",
         args: vec![],
+        ret_type: None,
     });
 
     Ok(Box::new(Function { proto, body: expr }))
 }
 
+/// item ::= extern | definition | global | toplevelexpr
+pub fn parse_item<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> Result<Item<'src>, ParserError<'src>> {
+    match tokens.peek() {
+        Some(Token::Extern) => parse_extern(tokens).map(Item::Extern),
+        Some(Token::FuncDef) => parse_definition(tokens).map(Item::Function),
+        Some(Token::Global) => parse_global(tokens).map(Item::Global),
+        _ => parse_top_level_expr(tokens).map(Item::Function),
+    }
+}
+
 // Small alias for fallible returns of parsing expressions
 type ExprParseResult<'src> = Result<Box<ASTExpr<'src>>, ParserError<'src>>;
 
 /// primary
 ///   ::= identifierexpr
 ///   ::= numberexpr
+///   ::= boolexpr
+///   ::= stringexpr
+///   ::= arrayexpr
 ///   ::= parenexpr
+///   ::= blockexpr
+///   ::= lambdaexpr
 ///   ::= ifexpr
 ///   ::= forloopexpr
+///   ::= whileexpr
+///   ::= breakexpr
+///   ::= continueexpr
 ///   ::= varexpr
 fn parse_primary<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
@@ -195,22 +367,38 @@ fn parse_primary<'src>(
 
         Some(Token::Number(_)) => parse_number_expr(tokens),
 
+        Some(Token::Bool(_)) => parse_bool_expr(tokens),
+
+        Some(Token::Str(_)) => parse_string_expr(tokens),
+
+        Some(Token::OpenBracket) => parse_array_expr(tokens),
+
         Some(Token::OpenParen) => parse_paren_expr(tokens),
 
+        Some(Token::OpenBrace) => parse_block_expr(tokens),
+
+        Some(Token::Backslash) => parse_lambda_expr(tokens),
+
         Some(Token::If) => parse_if_expr(tokens),
 
         Some(Token::For) => parse_for_loop_expression(tokens),
 
+        Some(Token::While) => parse_while_expression(tokens),
+
+        Some(Token::Break | Token::Continue) => parse_loop_control(tokens),
+
         Some(Token::Var) => parse_var_expression(tokens),
 
+        Some(Token::FuncDef) => parse_local_fn_expression(tokens),
+
         Some(unexpected) => Err(ParserError::UnexpectedToken(*unexpected)),
 
         None => Err(ParserError::UnexpectedEOI),
     }
 }
 
-/// varexpr ::= 'var' identifier ('=' expression)?
-//              (',' identifier ('=' expression)?)* 'in' expression
+/// varexpr ::= 'var' identifier annotation ('=' expression)?
+//              (',' identifier annotation ('=' expression)?)* 'in' expression
 fn parse_var_expression<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
@@ -225,15 +413,17 @@ fn parse_var_expression<'src>(
             return Err(ParserError::ExpectedToken("<identifier>"));
         };
 
+        let ty = parse_type_annotation(tokens)?;
+
         // If there is an assignment operator following, it has an initializer,
         // parse it and add it along with name, otherwise there is no initializer
         if let Some(Token::Operator(Ops::Assign)) = tokens.peek() {
             let _assign = tokens.next();
             let init = parse_expression(tokens)?;
 
-            var_names.push((name, Some(init)));
+            var_names.push((name, ty, Some(init)));
         } else {
-            var_names.push((name, None))
+            var_names.push((name, ty, None))
         }
 
         // If we have a comma following, we loop, otherwise, we break out of loop
@@ -252,8 +442,61 @@ fn parse_var_expression<'src>(
     Ok(Box::new(ASTExpr::VarExpr { var_names, body }))
 }
 
+/// localfnexpr ::= 'def' id '(' (id annotation ','?)* ')' ('->' type)? expression
+//                   'in' expression
+fn parse_local_fn_expression<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    // Swallow the def keyword
+    let _ = tokens.next();
+
+    let Some(Token::Identifier(name)) = tokens.next() else {
+        return Err(ParserError::ExpectedToken("<identifier>"));
+    };
+
+    tokens
+        .next_if(|t| matches!(t, Token::OpenParen))
+        .ok_or(ParserError::ExpectedToken(&"("))?;
+
+    let params = parse_params(tokens)?;
+
+    tokens
+        .next_if(|t| matches!(t, Token::ClosedParen))
+        .ok_or(ParserError::ExpectedToken(&")"))?;
+
+    // The return type has to be known before the body is checked, since the
+    // body may call the function, so like top level functions it defaults to f64
+    let ret_type = match tokens.next_if(|t| matches!(t, Token::Arrow)) {
+        Some(_arrow) => parse_type(tokens)?,
+        None => Type::F64,
+    };
+
+    // Same as lambdas, loops around the definition are out of reach of its body
+    let outer_depth = LOOP_DEPTH.with(|depth| depth.replace(0));
+    let fn_body = parse_expression(tokens);
+    LOOP_DEPTH.with(|depth| depth.set(outer_depth));
+
+    tokens
+        .next_if(|t| matches!(t, Token::In))
+        .ok_or(ParserError::ExpectedToken(&"in"))?;
+
+    let body = parse_expression(tokens)?;
+
+    Ok(Box::new(ASTExpr::LocalFnExpr {
+        name,
+        lambda: Box::new(ASTExpr::LambdaExpr {
+            params,
+            ret_type: Some(ret_type),
+            captures: vec![],
+            self_name: Some(name),
+            body: fn_body?,
+        }),
+        body,
+    }))
+}
+
 /// unary
-///   ::= primary
+///   ::= postfix
 ///   ::= '!' unary
 fn parse_unary<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
@@ -263,11 +506,121 @@ fn parse_unary<'src>(
 
         Ok(Box::new(ASTExpr::UnaryExpr { op, operand }))
     } else {
-        parse_primary(tokens)
+        parse_postfix(tokens)
+    }
+}
+
+/// postfix ::= primary ('[' expression ']' | '(' (expression ','?)* ')')*
+fn parse_postfix<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    let mut expr = parse_primary(tokens)?;
+
+    loop {
+        match tokens.peek() {
+            Some(Token::OpenBracket) => {
+                let _open_bracket = tokens.next();
+                let index = parse_expression(tokens)?;
+
+                tokens
+                    .next_if(|t| matches!(t, Token::ClosedBracket))
+                    .ok_or(ParserError::ExpectedToken(&"]"))?;
+
+                expr = Box::new(ASTExpr::IndexExpr { array: expr, index });
+            }
+
+            // Calling the result of an expression, like make_adder(1)(2), only
+            // calls by name are parsed as CallExpr, see parse_identifier_expr
+            Some(Token::OpenParen) => {
+                let _open_paren = tokens.next();
+                let args = parse_call_args(tokens)?;
+
+                expr = Box::new(ASTExpr::ApplyExpr {
+                    callee: expr,
+                    args,
+                    callee_type: None,
+                });
+            }
+
+            _ => return Ok(expr),
+        }
     }
 }
 
-/// forexpr ::= 'for' identifier '=' expression ',' expression (',' expr)? 'in' expression
+/// lambdaexpr ::= '\\' params '->' expression
+fn parse_lambda_expr<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    // Swallow the backslash
+    let _ = tokens.next();
+
+    let params = parse_params(tokens)?;
+
+    tokens
+        .next_if(|t| matches!(t, Token::Arrow))
+        .ok_or(ParserError::ExpectedToken(&"->"))?;
+
+    // The body of a lambda is a function of its own, a loop around the lambda
+    // can't be broken out of from inside of it
+    let outer_depth = LOOP_DEPTH.with(|depth| depth.replace(0));
+    let body = parse_expression(tokens);
+    LOOP_DEPTH.with(|depth| depth.set(outer_depth));
+
+    Ok(Box::new(ASTExpr::LambdaExpr {
+        params,
+        ret_type: None,
+        captures: vec![],
+        self_name: None,
+        body: body?,
+    }))
+}
+
+/// arrayexpr
+///   ::= '[' (expression (',' expression)*)? ']'
+///   ::= '[' expression ';' expression ']'
+fn parse_array_expr<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    // Swallow the open bracket
+    let _ = tokens.next();
+
+    let mut elements = vec![];
+
+    if let Some(_closed_bracket) = tokens.next_if(|t| matches!(t, Token::ClosedBracket)) {
+        return Ok(Box::new(ASTExpr::ArrayExpr(elements)));
+    }
+
+    let first = parse_expression(tokens)?;
+
+    // The [value; count] form, an array of count copies of value
+    if let Some(_semicolon) = tokens.next_if(|t| matches!(t, Token::Semicolon)) {
+        let count = parse_expression(tokens)?;
+
+        tokens
+            .next_if(|t| matches!(t, Token::ClosedBracket))
+            .ok_or(ParserError::ExpectedToken(&"]"))?;
+
+        return Ok(Box::new(ASTExpr::ArrayFillExpr {
+            value: first,
+            count,
+        }));
+    }
+
+    elements.push(first);
+
+    loop {
+        match tokens.next() {
+            Some(Token::Comma) => elements.push(parse_expression(tokens)?),
+            Some(Token::ClosedBracket) => break,
+            Some(unexpected) => return Err(ParserError::UnexpectedToken(unexpected)),
+            None => return Err(ParserError::UnexpectedEOI),
+        }
+    }
+
+    Ok(Box::new(ASTExpr::ArrayExpr(elements)))
+}
+
+/// forexpr ::= 'for' identifier annotation '=' expression ',' expression (',' expr)? 'in' expression
 fn parse_for_loop_expression<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
 ) -> ExprParseResult<'src> {
@@ -279,6 +632,8 @@ fn parse_for_loop_expression<'src>(
         return Err(ParserError::ExpectedToken(&"variable"));
     };
 
+    let var_type = parse_type_annotation(tokens)?;
+
     let Some(Token::Operator(Ops::Assign)) = tokens.next() else {
         return Err(ParserError::ExpectedToken(&"="));
     };
@@ -304,10 +659,11 @@ fn parse_for_loop_expression<'src>(
         return Err(ParserError::ExpectedToken(&"in"));
     };
 
-    let body = parse_expression(tokens)?;
+    let body = parse_loop_body(tokens)?;
 
     Ok(Box::new(ASTExpr::ForLoopExpr {
         varname,
+        var_type,
         start,
         end,
         step,
@@ -315,6 +671,70 @@ fn parse_for_loop_expression<'src>(
     }))
 }
 
+/// blockexpr ::= '{' expression (';' expression)* ';'? '}'
+fn parse_block_expr<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    // Swallow the open brace
+    let _ = tokens.next();
+
+    let mut exprs = vec![parse_expression(tokens)?];
+
+    loop {
+        match tokens.next() {
+            // A semicolon either separates expressions, or trails the last one
+            Some(Token::Semicolon) => {
+                if let Some(_closed_brace) = tokens.next_if(|t| matches!(t, Token::ClosedBrace)) {
+                    break;
+                }
+
+                exprs.push(parse_expression(tokens)?);
+            }
+            Some(Token::ClosedBrace) => break,
+            Some(unexpected) => return Err(ParserError::UnexpectedToken(unexpected)),
+            None => return Err(ParserError::UnexpectedEOI),
+        }
+    }
+
+    Ok(Box::new(ASTExpr::BlockExpr(exprs)))
+}
+
+/// whileexpr ::= 'while' expression 'do' expression
+fn parse_while_expression<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    let Some(Token::While) = tokens.next() else {
+        return Err(ParserError::ExpectedToken(&"while"));
+    };
+
+    let cond = parse_expression(tokens)?;
+
+    let Some(Token::Do) = tokens.next() else {
+        return Err(ParserError::ExpectedToken(&"do"));
+    };
+
+    let body = parse_loop_body(tokens)?;
+
+    Ok(Box::new(ASTExpr::WhileExpr { cond, body }))
+}
+
+/// breakexpr ::= 'break'
+/// continueexpr ::= 'continue'
+fn parse_loop_control<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    let in_loop = LOOP_DEPTH.with(|depth| depth.get() > 0);
+
+    match tokens.next() {
+        Some(Token::Break) if in_loop => Ok(Box::new(ASTExpr::BreakExpr)),
+        Some(Token::Continue) if in_loop => Ok(Box::new(ASTExpr::ContinueExpr)),
+        Some(Token::Break) => Err(ParserError::OutsideOfLoop(&"break")),
+        Some(Token::Continue) => Err(ParserError::OutsideOfLoop(&"continue")),
+        Some(unexpected) => Err(ParserError::UnexpectedToken(unexpected)),
+        None => Err(ParserError::UnexpectedEOI),
+    }
+}
+
 /// ifexpr ::= 'if' expression 'then' expression 'else' expression
 fn parse_if_expr<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
@@ -355,6 +775,28 @@ fn parse_number_expr<'src>(
     }
 }
 
+/// boolexpr ::= 'true' | 'false'
+fn parse_bool_expr<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    if let Some(Token::Bool(value)) = tokens.next() {
+        Ok(Box::new(ASTExpr::BoolExpr(value)))
+    } else {
+        panic!("Expected next token to be boolean for parse_bool_expr!")
+    }
+}
+
+/// stringexpr ::= '"' character* '"'
+fn parse_string_expr<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> ExprParseResult<'src> {
+    if let Some(Token::Str(contents)) = tokens.next() {
+        Ok(Box::new(ASTExpr::StringExpr(contents)))
+    } else {
+        panic!("Expected next token to be string for parse_string_expr!")
+    }
+}
+
 /// identifierexpr
 ///   ::= identifier
 ///   ::= identifier '(' expression* ')'
@@ -370,23 +812,13 @@ fn parse_identifier_expr<'src>(
     if let Some(Token::OpenParen) = tokens.peek() {
         let _open_paren = tokens.next();
 
-        let mut args = vec![];
+        let mut args = parse_call_args(tokens)?;
 
-        loop {
-            if let Some(Token::ClosedParen) = tokens.peek() {
-                break;
-            }
-
-            parse_expression(tokens).map(|arg_expr| args.push(arg_expr))?;
-
-            if let Some(Token::Comma) = tokens.peek() {
-                tokens.next();
-                continue;
-            }
+        // len() is builtin, rather than a function living in the module
+        if name == "len" && args.len() == 1 {
+            return Ok(Box::new(ASTExpr::LenExpr(args.remove(0))));
         }
 
-        let _closed_paren = tokens.next();
-
         Ok(Box::new(ASTExpr::CallExpr { callee: name, args }))
     } else {
         // Variable Expression
@@ -394,6 +826,30 @@ fn parse_identifier_expr<'src>(
     }
 }
 
+/// callargs ::= (expression ','?)* ')'
+fn parse_call_args<'src>(
+    tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
+) -> Result<Vec<Box<ASTExpr<'src>>>, ParserError<'src>> {
+    let mut args = vec![];
+
+    loop {
+        if let Some(Token::ClosedParen) = tokens.peek() {
+            break;
+        }
+
+        parse_expression(tokens).map(|arg_expr| args.push(arg_expr))?;
+
+        if let Some(Token::Comma) = tokens.peek() {
+            tokens.next();
+            continue;
+        }
+    }
+
+    let _closed_paren = tokens.next();
+
+    Ok(args)
+}
+
 /// parenexpr ::= '(' expression ')'
 fn parse_paren_expr<'src>(
     tokens: &mut Peekable<impl Iterator<Item = Token<'src>>>,
@@ -426,11 +882,16 @@ fn parse_expression<'src>(
 }
 
 // Small helper method to fetch the precedence of operator
-// from hash table. If the token is not an operator,
-// default to -1. Tutorial names this GetTokPrecedence
+// from hash table. If the token is not an operator, or is an
+// operator nobody defined yet, default to -1. Tutorial names this GetTokPrecedence
 fn get_token_precedence(token: Token) -> i32 {
     if let Token::Operator(operator) = token {
-        OP_PRECEDENCE.read().unwrap()[&operator]
+        OP_PRECEDENCE
+            .read()
+            .unwrap()
+            .get(&operator)
+            .copied()
+            .unwrap_or(-1)
     } else {
         -1
     }
@@ -635,7 +1096,8 @@ mod tests {
             Ok(Box::new(Function {
                 proto: Box::new(Prototype::FunctionProto {
                     name: &"func1",
-                    args: vec![&"x", &"y"]
+                    args: vec![(&"x", None), (&"y", None)],
+                    ret_type: None,
                 }),
                 body: Box::new(BinaryExpr {
                     op: Mult,
@@ -653,7 +1115,8 @@ mod tests {
             Ok(Box::new(Function {
                 proto: Box::new(Prototype::FunctionProto {
                     name: &"alwaysReturnOne",
-                    args: vec![]
+                    args: vec![],
+                    ret_type: None,
                 }),
                 body: Box::new(NumberExpr(1.0)),
             }))
@@ -669,7 +1132,8 @@ mod tests {
             Ok(Box::new(Function {
                 proto: Box::new(Prototype::FunctionProto {
                     name: &"func2",
-                    args: vec![&"base", &"mid", &"upper"]
+                    args: vec![(&"base", None), (&"mid", None), (&"upper", None)],
+                    ret_type: None,
                 }),
                 body: Box::new(BinaryExpr {
                     op: Plus,
@@ -706,4 +1170,303 @@ mod tests {
             }))
         );
     }
+
+    #[test]
+    fn parsing_type_annotations() {
+        let mut tokens = "def typed(x: i64, y: f64, z) -> bool x == 0;"
+            .lex()
+            .peekable();
+        let func_ast = parse_definition(&mut tokens);
+
+        assert_eq!(
+            func_ast,
+            Ok(Box::new(Function {
+                proto: Box::new(Prototype::FunctionProto {
+                    name: &"typed",
+                    args: vec![(&"x", Some(Type::I64)), (&"y", Some(Type::F64)), (&"z", None)],
+                    ret_type: Some(Type::Bool),
+                }),
+                body: Box::new(BinaryExpr {
+                    op: Eq,
+                    left: Box::new(VariableExpr(&"x")),
+                    right: Box::new(NumberExpr(0.0)),
+                })
+            }))
+        );
+
+        tokens = " var i: i64 = 1, done: bool in done; ".lex().peekable();
+        let var_expr = parse_expression(&mut tokens);
+
+        assert_eq!(
+            var_expr,
+            Ok(Box::new(VarExpr {
+                var_names: vec![
+                    (&"i", Some(Type::I64), Some(Box::new(NumberExpr(1.0)))),
+                    (&"done", Some(Type::Bool), None),
+                ],
+                body: Box::new(VariableExpr(&"done")),
+            }))
+        );
+
+        tokens = "def greet(name: str) -> str \"hello \" + name;".lex().peekable();
+
+        assert_eq!(
+            parse_definition(&mut tokens),
+            Ok(Box::new(Function {
+                proto: Box::new(Prototype::FunctionProto {
+                    name: &"greet",
+                    args: vec![(&"name", Some(Type::Str))],
+                    ret_type: Some(Type::Str),
+                }),
+                body: Box::new(BinaryExpr {
+                    op: Plus,
+                    left: Box::new(StringExpr(&"hello ")),
+                    right: Box::new(VariableExpr(&"name")),
+                })
+            }))
+        );
+
+        tokens = "def bad(x: string) x;".lex().peekable();
+
+        assert_eq!(
+            parse_definition(&mut tokens),
+            Err(ParserError::UnknownType(&"string"))
+        );
+    }
+
+    #[test]
+    fn parsing_arrays() {
+        let mut tokens = " [1, x, 3] ".lex().peekable();
+
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(ArrayExpr(vec![
+                Box::new(NumberExpr(1.0)),
+                Box::new(VariableExpr(&"x")),
+                Box::new(NumberExpr(3.0)),
+            ])))
+        );
+
+        tokens = " [0; n] ".lex().peekable();
+
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(ArrayFillExpr {
+                value: Box::new(NumberExpr(0.0)),
+                count: Box::new(VariableExpr(&"n")),
+            }))
+        );
+
+        // Indexing binds tighter than any operator, and may be assigned to
+        tokens = " a[i + 1] = len(a) * 2; ".lex().peekable();
+
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Ok(Box::new(BinaryExpr {
+                op: Assign,
+                left: Box::new(IndexExpr {
+                    array: Box::new(VariableExpr(&"a")),
+                    index: Box::new(BinaryExpr {
+                        op: Plus,
+                        left: Box::new(VariableExpr(&"i")),
+                        right: Box::new(NumberExpr(1.0)),
+                    }),
+                }),
+                right: Box::new(BinaryExpr {
+                    op: Mult,
+                    left: Box::new(LenExpr(Box::new(VariableExpr(&"a")))),
+                    right: Box::new(NumberExpr(2.0)),
+                }),
+            }))
+        );
+
+        tokens = "def sum(xs: [f64]) xs[0];".lex().peekable();
+
+        assert!(matches!(
+            *parse_definition(&mut tokens).unwrap().proto,
+            Prototype::FunctionProto { ref args, .. } if args == &vec![("xs", Some(Type::Array))]
+        ));
+    }
+
+    #[test]
+    fn parsing_while_loops() {
+        let mut tokens = " while i < 10 do if i == 5 then break else i = i + 1; ".lex().peekable();
+
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(WhileExpr {
+                cond: Box::new(BinaryExpr {
+                    op: Lt,
+                    left: Box::new(VariableExpr(&"i")),
+                    right: Box::new(NumberExpr(10.0)),
+                }),
+                body: Box::new(IfExpr {
+                    cond: Box::new(BinaryExpr {
+                        op: Eq,
+                        left: Box::new(VariableExpr(&"i")),
+                        right: Box::new(NumberExpr(5.0)),
+                    }),
+                    then_branch: Box::new(BreakExpr),
+                    else_branch: Box::new(BinaryExpr {
+                        op: Assign,
+                        left: Box::new(VariableExpr(&"i")),
+                        right: Box::new(BinaryExpr {
+                            op: Plus,
+                            left: Box::new(VariableExpr(&"i")),
+                            right: Box::new(NumberExpr(1.0)),
+                        }),
+                    }),
+                }),
+            }))
+        );
+
+        tokens = " for i = 0, i < 10 in continue; ".lex().peekable();
+        assert!(matches!(
+            *parse_primary(&mut tokens).unwrap(),
+            ForLoopExpr { ref body, .. } if **body == ContinueExpr
+        ));
+
+        // Outside of a loop body, or once the loop is over, neither is allowed
+        tokens = " if x then break else 0; ".lex().peekable();
+        assert_eq!(parse_primary(&mut tokens), Err(ParserError::OutsideOfLoop(&"break")));
+
+        tokens = " (while x do 0) + continue; ".lex().peekable();
+        assert_eq!(parse_expression(&mut tokens), Err(ParserError::OutsideOfLoop(&"continue")));
+    }
+
+    #[test]
+    fn parsing_blocks() {
+        let mut tokens = " { x = 1; x + 2; } ".lex().peekable();
+
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(BlockExpr(vec![
+                Box::new(BinaryExpr {
+                    op: Assign,
+                    left: Box::new(VariableExpr(&"x")),
+                    right: Box::new(NumberExpr(1.0)),
+                }),
+                Box::new(BinaryExpr {
+                    op: Plus,
+                    left: Box::new(VariableExpr(&"x")),
+                    right: Box::new(NumberExpr(2.0)),
+                }),
+            ])))
+        );
+
+        // The trailing semicolon is optional, but a block can't be empty
+        tokens = " {x} ".lex().peekable();
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(BlockExpr(vec![Box::new(VariableExpr(&"x"))])))
+        );
+
+        tokens = " {} ".lex().peekable();
+        assert_eq!(parse_primary(&mut tokens), Err(ParserError::UnexpectedToken(Token::ClosedBrace)));
+
+        tokens = " { x y } ".lex().peekable();
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Err(ParserError::UnexpectedToken(Token::Identifier(&"y")))
+        );
+    }
+
+    #[test]
+    fn parsing_globals() {
+        let mut tokens = " global counter: i64 = 0; ".lex().peekable();
+
+        assert_eq!(
+            parse_global(&mut tokens),
+            Ok(Box::new(Global {
+                name: &"counter",
+                var_type: Some(Type::I64),
+                init: Some(Box::new(NumberExpr(0.0))),
+            }))
+        );
+
+        tokens = " global x; ".lex().peekable();
+
+        assert_eq!(
+            parse_global(&mut tokens),
+            Ok(Box::new(Global {
+                name: &"x",
+                var_type: None,
+                init: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn parsing_local_functions() {
+        let mut tokens = "def sq(x) x * x in sq(2);".lex().peekable();
+
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(LocalFnExpr {
+                name: &"sq",
+                lambda: Box::new(LambdaExpr {
+                    params: vec![("x", None)],
+                    ret_type: Some(Type::F64),
+                    captures: vec![],
+                    self_name: Some("sq"),
+                    body: Box::new(BinaryExpr {
+                        op: Mult,
+                        left: Box::new(VariableExpr(&"x")),
+                        right: Box::new(VariableExpr(&"x")),
+                    }),
+                }),
+                body: Box::new(CallExpr {
+                    callee: &"sq",
+                    args: vec![Box::new(NumberExpr(2.0))],
+                }),
+            }))
+        );
+    }
+
+    #[test]
+    fn parsing_lambdas() {
+        let mut tokens = r" \x, y: i64 -> x * y; ".lex().peekable();
+
+        assert_eq!(
+            parse_primary(&mut tokens),
+            Ok(Box::new(LambdaExpr {
+                params: vec![("x", None), ("y", Some(Type::I64))],
+                ret_type: None,
+                captures: vec![],
+                self_name: None,
+                body: Box::new(BinaryExpr {
+                    op: Mult,
+                    left: Box::new(VariableExpr(&"x")),
+                    right: Box::new(VariableExpr(&"y")),
+                }),
+            }))
+        );
+
+        // Results of calls can be called again
+        tokens = " make_adder(1)(2); ".lex().peekable();
+
+        assert_eq!(
+            parse_expression(&mut tokens),
+            Ok(Box::new(ApplyExpr {
+                callee: Box::new(CallExpr {
+                    callee: &"make_adder",
+                    args: vec![Box::new(NumberExpr(1.0))],
+                }),
+                args: vec![Box::new(NumberExpr(2.0))],
+                callee_type: None,
+            }))
+        );
+
+        tokens = "def map(xs: [f64], f: fn(f64) -> f64) -> [f64] xs;".lex().peekable();
+
+        assert!(matches!(
+            *parse_definition(&mut tokens).unwrap().proto,
+            Prototype::FunctionProto { ref args, .. }
+                if args[1] == ("f", Some(Type::function(vec![Type::F64], Type::F64)))
+        ));
+
+        // Loops around a lambda can't be broken out of from its body
+        tokens = r" while x do \y -> break; ".lex().peekable();
+        assert_eq!(parse_primary(&mut tokens), Err(ParserError::OutsideOfLoop(&"break")));
+    }
 }
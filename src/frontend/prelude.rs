@@ -0,0 +1,53 @@
+use crate::frontend::{
+    ast::Prototype,
+    lexer::{Lex, Token},
+    parser::parse_extern,
+};
+
+// Functions every program can call without declaring them, the C math library the
+// tutorial has you declare with extern yourself. The resolver, type checker and backend
+// each declare these when they're created, and the runtime links the math library in,
+// so the JIT always finds them. Declaring one again with extern is fine, as long as
+// it's declared the same way.
+//
+// Calls to sqrt, fabs, floor and ceil become LLVM intrinsics rather than calls, which
+// are single instructions on most machines, see CallExpr in the backend.
+pub const PRELUDE: &str = "
+    extern sin(x); extern cos(x); extern tan(x);
+    extern asin(x); extern acos(x); extern atan(x); extern atan2(y x);
+    extern sinh(x); extern cosh(x); extern tanh(x);
+    extern exp(x); extern log(x); extern log2(x); extern log10(x); extern pow(x y);
+    extern sqrt(x); extern cbrt(x); extern hypot(x y);
+    extern floor(x); extern ceil(x); extern round(x); extern trunc(x);
+    extern fabs(x); extern fmod(x y); extern fmin(x y); extern fmax(x y);
+";
+
+pub fn prelude() -> Vec<Box<Prototype<'static>>> {
+    let mut tokens = PRELUDE.lex().peekable();
+    let mut protos = vec![];
+
+    while let Some(token) = tokens.peek() {
+        if let Token::Semicolon = token {
+            tokens.next();
+            continue;
+        }
+
+        protos.push(parse_extern(&mut tokens).expect("The prelude always parses"));
+    }
+
+    protos
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frontend::{lexer::Lex, parser::parse_definition, resolve::Resolver, typecheck::TypeChecker};
+
+    #[test]
+    fn the_prelude_needs_no_declarations() {
+        let src = "def polar(x y) atan2(y, x) + sqrt(pow(x, 2) + pow(y, 2)) * floor(fabs(x));";
+        let mut func = parse_definition(&mut src.lex().peekable()).unwrap();
+
+        assert_eq!(Resolver::new().resolve_function(&func), Ok(()));
+        assert!(TypeChecker::new().check_function(&mut func).is_ok());
+    }
+}
@@ -0,0 +1,487 @@
+use std::collections::{HashMap, HashSet};
+
+use thiserror::Error;
+
+use crate::frontend::{
+    ast::{ASTExpr, Function, Global, Item, Prototype, Span},
+    lexer::Ops,
+    prelude::prelude,
+    scope::ScopeStack,
+};
+
+// Mistakes in a program that can be found by looking at names alone, before
+// we know anything about types: variables and functions that don't exist, calls
+// with the wrong number of arguments, and operators nobody defined.
+#[derive(Error, PartialEq, Debug)]
+pub enum SemanticError<'src> {
+    #[error("Unknown variable name {0}")]
+    UnknownVariable(&'src str),
+
+    #[error("Undefined function {0}")]
+    UndefinedFunction(&'src str),
+
+    #[error("Incorrect number of arguments passed to {func_name}, expected {expected} but found {found}")]
+    IncorrectNumberOfArgs {
+        func_name: &'src str,
+        expected: usize,
+        found: usize,
+    },
+
+    #[error("Undefined operator used: {op:?} in {func_name}")]
+    UndefinedOperator { op: Ops, func_name: &'src str },
+
+    #[error("Variable {0} used in a var initializer before its definition")]
+    UsedBeforeDefinition(&'src str),
+}
+
+impl<'src> SemanticError<'src> {
+    // Where in src the error is, operators aren't kept as slices of the source,
+    // so for those we point at the name of the function they were used in
+    pub fn span(&self, src: &str) -> Option<Span> {
+        use SemanticError::*;
+
+        match self {
+            UnknownVariable(name) | UndefinedFunction(name) | UsedBeforeDefinition(name) => {
+                Span::of(src, name)
+            }
+
+            IncorrectNumberOfArgs { func_name, .. } | UndefinedOperator { func_name, .. } => {
+                Span::of(src, func_name)
+            }
+        }
+    }
+
+    // The error prefixed with the line and column it was found at, if we know them
+    pub fn render(&self, src: &str) -> String {
+        match self.span(src) {
+            Some(span) => {
+                let (line, col) = span.line_col(src);
+                format!("{line}:{col}: {self}")
+            }
+
+            None => self.to_string(),
+        }
+    }
+}
+
+// The resolver walks each item before the type checker and the backend see it,
+// collecting every error it comes across rather than stopping at the first one.
+// It only remembers the names defined by the items it accepted so far, so like
+// the rest of the compiler, functions have to be defined (or declared) before use.
+#[derive(Debug, Default, Clone)]
+pub struct Resolver {
+    functions: HashMap<String, usize>,
+    globals: HashSet<String>,
+}
+
+type Errors<'src> = Vec<SemanticError<'src>>;
+
+impl Resolver {
+    // Knows the functions of the prelude from the start
+    pub fn new() -> Self {
+        let mut resolver = Self::default();
+
+        for proto in prelude() {
+            resolver.declare(&proto);
+        }
+
+        resolver
+    }
+
+    pub fn resolve_item<'src>(&mut self, item: &Item<'src>) -> Result<(), Errors<'src>> {
+        match item {
+            Item::Extern(proto) => {
+                self.declare(proto);
+                Ok(())
+            }
+
+            Item::Function(func) => self.resolve_function(func),
+
+            Item::Global(global) => self.resolve_global(global),
+        }
+    }
+
+    // Resolves a whole program, reporting the errors of every item at once. A function
+    // is declared even when its body has errors, so calls to it from the functions
+    // after it aren't reported as well.
+    pub fn resolve_program<'src>(&mut self, items: &[Item<'src>]) -> Result<(), Errors<'src>> {
+        let mut errors = vec![];
+
+        for item in items {
+            if let Err(item_errors) = self.resolve_item(item) {
+                if let Item::Function(func) = item {
+                    self.declare(&func.proto);
+                }
+
+                errors.extend(item_errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn declare(&mut self, proto: &Prototype) {
+        self.functions.insert(proto.get_name(), proto.get_num_params());
+    }
+
+    // The function is declared up front so it can call itself, if the body turns out to
+    // have errors whatever was declared under its name before is put back
+    pub fn resolve_function<'src>(&mut self, func: &Function<'src>) -> Result<(), Errors<'src>> {
+        let previous = self
+            .functions
+            .insert(func.proto.get_name(), func.proto.get_num_params());
+
+        let func_name = match *func.proto {
+            Prototype::FunctionProto { name, .. } => name,
+            _ => "<operator>",
+        };
+
+        let mut scope = ScopeStack::new();
+
+        for param in func.proto.get_param_names() {
+            scope.define(param, ());
+        }
+
+        let mut errors = vec![];
+        self.resolve_expr(&func.body, func_name, &mut scope, &mut errors);
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        match previous {
+            Some(num_params) => self.functions.insert(func.proto.get_name(), num_params),
+            None => self.functions.remove(&func.proto.get_name()),
+        };
+
+        Err(errors)
+    }
+
+    pub fn resolve_global<'src>(&mut self, global: &Global<'src>) -> Result<(), Errors<'src>> {
+        let mut errors = vec![];
+
+        if let Some(init) = &global.init {
+            self.resolve_expr(init, global.name, &mut ScopeStack::new(), &mut errors);
+        }
+
+        if errors.is_empty() {
+            self.globals.insert(global.name.to_string());
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn is_variable(&self, name: &str, scope: &ScopeStack<()>) -> bool {
+        scope.contains(name) || self.globals.contains(name)
+    }
+
+    fn resolve_expr<'src>(
+        &self,
+        expr: &ASTExpr<'src>,
+        func_name: &'src str,
+        scope: &mut ScopeStack<()>,
+        errors: &mut Errors<'src>,
+    ) {
+        use ASTExpr::*;
+
+        match expr {
+            NumberExpr(_) | IntegerExpr(_) | BoolExpr(_) | StringExpr(_) | BreakExpr | ContinueExpr => {}
+
+            // Named functions can be used as values too
+            VariableExpr(name) => {
+                if !self.is_variable(name, scope) && !self.functions.contains_key(*name) {
+                    errors.push(SemanticError::UnknownVariable(name));
+                }
+            }
+
+            ArrayExpr(exprs) | BlockExpr(exprs) => {
+                for expr in exprs {
+                    self.resolve_expr(expr, func_name, scope, errors);
+                }
+            }
+
+            // Calling a variable holding a function, how many arguments it takes
+            // depends on its type, which is up to the type checker
+            CallExpr { callee, args } => {
+                if !self.is_variable(callee, scope) {
+                    match self.functions.get(*callee) {
+                        Some(&expected) if expected != args.len() => {
+                            errors.push(SemanticError::IncorrectNumberOfArgs {
+                                func_name: callee,
+                                expected,
+                                found: args.len(),
+                            })
+                        }
+
+                        Some(_) => {}
+
+                        None => errors.push(SemanticError::UndefinedFunction(callee)),
+                    }
+                }
+
+                for arg in args {
+                    self.resolve_expr(arg, func_name, scope, errors);
+                }
+            }
+
+            ApplyExpr { callee, args, .. } => {
+                self.resolve_expr(callee, func_name, scope, errors);
+
+                for arg in args {
+                    self.resolve_expr(arg, func_name, scope, errors);
+                }
+            }
+
+            LenExpr(operand) => self.resolve_expr(operand, func_name, scope, errors),
+
+            // Negation and unary minus are built in, for some types at least
+            UnaryExpr { op, operand } => {
+                if !matches!(op, Ops::Negate | Ops::Minus)
+                    && !self.functions.contains_key(&format!("unary{}", op.as_str()))
+                {
+                    errors.push(SemanticError::UndefinedOperator { op: *op, func_name });
+                }
+
+                self.resolve_expr(operand, func_name, scope, errors);
+            }
+
+            // Only the target of an assignment has to be a variable, not a function
+            BinaryExpr {
+                op: Ops::Assign,
+                left,
+                right,
+            } => {
+                match **left {
+                    VariableExpr(name) if !self.is_variable(name, scope) => {
+                        errors.push(SemanticError::UnknownVariable(name))
+                    }
+
+                    VariableExpr(_) => {}
+
+                    ref target => self.resolve_expr(target, func_name, scope, errors),
+                }
+
+                self.resolve_expr(right, func_name, scope, errors);
+            }
+
+            BinaryExpr { op, left, right } => {
+                if matches!(op, Ops::Colon | Ops::Negate)
+                    && !self.functions.contains_key(&format!("binary{}", op.as_str()))
+                {
+                    errors.push(SemanticError::UndefinedOperator { op: *op, func_name });
+                }
+
+                self.resolve_expr(left, func_name, scope, errors);
+                self.resolve_expr(right, func_name, scope, errors);
+            }
+
+            ArrayFillExpr {
+                value: left,
+                count: right,
+            }
+            | IndexExpr {
+                array: left,
+                index: right,
+            }
+            | WhileExpr {
+                cond: left,
+                body: right,
+            } => {
+                self.resolve_expr(left, func_name, scope, errors);
+                self.resolve_expr(right, func_name, scope, errors);
+            }
+
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expr(cond, func_name, scope, errors);
+                self.resolve_expr(then_branch, func_name, scope, errors);
+                self.resolve_expr(else_branch, func_name, scope, errors);
+            }
+
+            ForLoopExpr {
+                varname,
+                start,
+                end,
+                step,
+                body,
+                ..
+            } => {
+                self.resolve_expr(start, func_name, scope, errors);
+
+                scope.push_scope();
+                scope.define(varname, ());
+
+                self.resolve_expr(end, func_name, scope, errors);
+                self.resolve_expr(step, func_name, scope, errors);
+                self.resolve_expr(body, func_name, scope, errors);
+
+                scope.pop_scope();
+            }
+
+            VarExpr { var_names, body } => {
+                scope.push_scope();
+
+                for (idx, (name, _, init)) in var_names.iter().enumerate() {
+                    if let Some(init) = init {
+                        let mut init_errors = vec![];
+                        self.resolve_expr(init, func_name, scope, &mut init_errors);
+
+                        // Same as the type checker, naming this variable or a later one
+                        // from the same var is a use before definition
+                        errors.extend(init_errors.into_iter().map(|err| match err {
+                            SemanticError::UnknownVariable(used)
+                                if var_names[idx..].iter().any(|(later, ..)| *later == used) =>
+                            {
+                                SemanticError::UsedBeforeDefinition(used)
+                            }
+                            err => err,
+                        }));
+                    }
+
+                    scope.define(name, ());
+                }
+
+                self.resolve_expr(body, func_name, scope, errors);
+
+                scope.pop_scope();
+            }
+
+            LambdaExpr {
+                params,
+                self_name,
+                body,
+                ..
+            } => {
+                scope.push_scope();
+
+                if let Some(name) = self_name {
+                    scope.define(name, ());
+                }
+
+                scope.push_scope();
+
+                for (param, _) in params {
+                    scope.define(param, ());
+                }
+
+                self.resolve_expr(body, func_name, scope, errors);
+
+                scope.pop_scope();
+                scope.pop_scope();
+            }
+
+            LocalFnExpr { name, lambda, body } => {
+                self.resolve_expr(lambda, func_name, scope, errors);
+
+                scope.push_scope();
+                scope.define(name, ());
+
+                self.resolve_expr(body, func_name, scope, errors);
+
+                scope.pop_scope();
+            }
+        }
+    }
+}
+
+// Every error the resolver found, with the line and column they were found at
+pub fn report_semantic_errors(src: &str, errors: &[SemanticError]) {
+    for err in errors {
+        eprintln!("Semantic error: {}", err.render(src));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, parser::*};
+
+    fn resolve_all<'src>(resolver: &mut Resolver, src: &'src str) -> Result<(), Errors<'src>> {
+        let mut tokens = src.lex().peekable();
+        let mut items = vec![];
+
+        while tokens.peek().is_some() {
+            items.push(Item::Function(parse_definition(&mut tokens).unwrap()));
+            let _semicolon = tokens.next();
+        }
+
+        resolver.resolve_program(&items)
+    }
+
+    #[test]
+    fn reports_every_error_at_once() {
+        let mut resolver = Resolver::new();
+
+        let src = "def add(a b) a + b;
+                   def broken(x) add(x) + y + missing(x);
+                   def fine(x) broken(x) + add(x, x);";
+
+        let errors = resolve_all(&mut resolver, src).unwrap_err();
+
+        assert_eq!(
+            errors,
+            vec![
+                SemanticError::IncorrectNumberOfArgs {
+                    func_name: &"add",
+                    expected: 2,
+                    found: 1
+                },
+                SemanticError::UnknownVariable(&"y"),
+                SemanticError::UndefinedFunction(&"missing"),
+            ]
+        );
+
+        // Errors point back into the source
+        assert_eq!(errors[1].render(src), "2:43: Unknown variable name y");
+    }
+
+    #[test]
+    fn failed_definitions_leave_nothing_behind() {
+        let mut resolver = Resolver::new();
+
+        let mut tokens = "def f(x) x; def f(x y) z;".lex().peekable();
+        let f = parse_definition(&mut tokens).unwrap();
+        let _semicolon = tokens.next();
+        let broken_f = parse_definition(&mut tokens).unwrap();
+
+        assert!(resolver.resolve_function(&f).is_ok());
+        assert!(resolver.resolve_function(&broken_f).is_err());
+        assert_eq!(resolver.functions.get("f"), Some(&1));
+
+        tokens = "global g = h;".lex().peekable();
+        assert!(resolver.resolve_global(&parse_global(&mut tokens).unwrap()).is_err());
+        assert!(!resolver.globals.contains("g"));
+    }
+
+    #[test]
+    fn names_are_resolved_through_scopes() {
+        let mut resolver = Resolver::new();
+
+        assert!(resolve_all(
+            &mut resolver,
+            r"def f(n) var k = 2 in for i = 0, i < n in (\x -> x * k)(i);
+              def g(x) def go(y) if y < 1 then 0 else go(y - 1) in go(x);",
+        )
+        .is_ok());
+
+        assert_eq!(
+            resolve_all(&mut resolver, "def h(x) (for i = 0, i < x in i) + i;"),
+            Err(vec![SemanticError::UnknownVariable(&"i")])
+        );
+        assert_eq!(
+            resolve_all(&mut resolver, "def j(x) ^x;"),
+            Err(vec![SemanticError::UndefinedOperator {
+                op: Ops::Xor,
+                func_name: &"j"
+            }])
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+
+// Lexical scoping, shared by the type checker and the backend. Every construct
+// that introduces names (a function's parameters, var, for loops, lambdas and
+// local functions) pushes a scope of its own, and pops it once its body is done,
+// so the names it shadowed come back into view without any bookkeeping.
+//
+// Each binding is given its own slot, numbered in the order they were defined
+// within the function, even when two bindings share a name. Whatever is bound
+// (a type, a stack pointer) lives in that slot, the scopes only map names to slots.
+pub type Slot = usize;
+
+#[derive(Debug, Clone)]
+pub struct ScopeStack<T> {
+    scopes: Vec<HashMap<String, Slot>>,
+    slots: Vec<T>,
+}
+
+impl<T> Default for ScopeStack<T> {
+    fn default() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+            slots: vec![],
+        }
+    }
+}
+
+impl<T> ScopeStack<T> {
+    // A stack with a single, outermost scope, where a function's parameters go
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    // The outermost scope is never popped, the slots stay around, a later
+    // binding is simply given a new one.
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    // Binds the name in the innermost scope, a second binding of the same name in the
+    // same scope replaces the first (like var x = 1, x = 2 in ...)
+    pub fn define(&mut self, name: &str, value: T) -> Slot {
+        let slot = self.slots.len();
+        self.slots.push(value);
+
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), slot);
+
+        slot
+    }
+
+    // Finds the slot the name refers to, starting from the innermost scope
+    pub fn resolve(&self, name: &str) -> Option<Slot> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&T> {
+        self.resolve(name).map(|slot| &self.slots[slot])
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.resolve(name).is_some()
+    }
+
+    // Forget everything, ready for the next function
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inner_scopes_shadow_outer_ones() {
+        let mut scopes = ScopeStack::new();
+
+        let outer = scopes.define("x", "param");
+
+        scopes.push_scope();
+        let inner = scopes.define("x", "var");
+
+        assert_ne!(outer, inner);
+        assert_eq!(scopes.resolve("x"), Some(inner));
+        assert_eq!(scopes.lookup("x"), Some(&"var"));
+
+        scopes.pop_scope();
+
+        assert_eq!(scopes.resolve("x"), Some(outer));
+        assert_eq!(scopes.lookup("x"), Some(&"param"));
+        assert!(!scopes.contains("y"));
+
+        // Popping the outermost scope leaves the parameters in place
+        scopes.pop_scope();
+        assert!(scopes.contains("x"));
+    }
+}
@@ -0,0 +1,903 @@
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::frontend::{
+    ast::{ASTExpr, Function, Global, Item, Prototype, Type},
+    intern::intern,
+    lexer::{Lex, Ops},
+    parser::parse_type,
+};
+
+// The tree as data other tools can read and write, see --emit. The JSON schema is
+// written out by hand below rather than derived from the Rust types, so renaming a
+// variant or reordering fields in ast.rs doesn't break anyone. Every node is an
+// object with a "kind", optional fields are null (or left out when reading), types
+// are written like annotations ("i64", "[f64]", "fn(f64) -> bool"), and operators
+// like in source ("+", "=="). Strings are kept as they were written in the source,
+// escapes and all, so "a\\nb" in the tree is the literal "a\nb" in Kaleidoscope. JSON has
+// no NaN or infinity, numbers that aren't finite are written as "nan", "inf" or "-inf".
+// The tree is written as parsed, the closure captures and call signatures the type
+// checker fills in later are left out, it works them out again for a tree read back.
+// A program looks like
+//
+// {"kind": "program", "version": 1, "items": [
+//   {"kind": "function",
+//    "prototype": {"kind": "function", "name": "inc", "params": [{"name": "x", "type": null}], "return_type": null},
+//    "body": {"kind": "binary", "op": "+", "left": {"kind": "variable", "name": "x"}, "right": {"kind": "number", "value": 1.0}}},
+//   {"kind": "expression", "body": {"kind": "call", "callee": "inc", "args": [{"kind": "number", "value": 2.0}]}}
+// ]}
+//
+// The S-expression form is the same data, objects become lists headed by their kind
+// followed by keyword/value pairs (null fields are left out), arrays use brackets:
+//
+// (program :version 1 :items [
+//   (function :prototype (function :name "inc" :params [(:name "x")]) :body (binary :op "+" ...))
+// ])
+//
+// Bumped whenever the schema changes in a way older readers can't handle
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Invalid S-expression at byte {0}: {1}")]
+    Sexp(usize, &'static str),
+
+    #[error("Unsupported schema version {0}, expected {SCHEMA_VERSION}")]
+    UnsupportedVersion(Value),
+
+    #[error("Missing field {0}")]
+    MissingField(&'static str),
+
+    #[error("Field {0} has the wrong type")]
+    WrongType(&'static str),
+
+    #[error("Unknown kind of node {0}")]
+    UnknownKind(String),
+
+    #[error("Unknown operator {0}")]
+    UnknownOperator(String),
+
+    #[error("Unknown type {0}")]
+    UnknownType(String),
+}
+
+// Writing the tree
+
+pub fn program_to_json(items: &[Item]) -> Value {
+    json!({
+        "kind": "program",
+        "version": SCHEMA_VERSION,
+        "items": items.iter().map(item_to_json).collect::<Vec<_>>(),
+    })
+}
+
+// One item per line, the whole program on one line wouldn't be much use to anyone
+pub fn program_to_sexp(items: &[Item]) -> String {
+    let items = items
+        .iter()
+        .map(|item| format!("  {}\n", to_sexp(&item_to_json(item))))
+        .collect::<String>();
+
+    format!("(program :version {SCHEMA_VERSION} :items [\n{items}])\n")
+}
+
+pub fn item_to_json(item: &Item) -> Value {
+    match item {
+        Item::Extern(proto) => json!({"kind": "extern", "prototype": prototype_to_json(proto)}),
+
+        Item::Function(func) => function_to_json(func),
+
+        Item::Global(global) => json!({
+            "kind": "global",
+            "name": global.name,
+            "type": type_to_json(global.var_type),
+            "init": global.init.as_deref().map_or(Value::Null, expr_to_json),
+        }),
+    }
+}
+
+// Top level expressions are only functions to the backend
+pub fn function_to_json(func: &Function) -> Value {
+    match *func.proto {
+        Prototype::FunctionProto {
+            name: "__anonymous_expr",
+            ..
+        } => json!({"kind": "expression", "body": expr_to_json(&func.body)}),
+
+        _ => json!({
+            "kind": "function",
+            "prototype": prototype_to_json(&func.proto),
+            "body": expr_to_json(&func.body),
+        }),
+    }
+}
+
+pub fn prototype_to_json(proto: &Prototype) -> Value {
+    match proto {
+        Prototype::FunctionProto {
+            name,
+            args,
+            ret_type,
+        } => json!({
+            "kind": "function",
+            "name": name,
+            "params": params_to_json(args),
+            "return_type": type_to_json(*ret_type),
+        }),
+
+        Prototype::OverloadedUnaryOpProto { operator, arg } => json!({
+            "kind": "unary_operator",
+            "operator": operator.as_str(),
+            "param": arg,
+        }),
+
+        Prototype::OverloadedBinaryOpProto {
+            operator,
+            args: (lhs, rhs),
+            precedence,
+        } => json!({
+            "kind": "binary_operator",
+            "operator": operator.as_str(),
+            "params": [lhs, rhs],
+            "precedence": precedence,
+        }),
+    }
+}
+
+fn type_to_json(ty: Option<Type>) -> Value {
+    ty.map_or(Value::Null, |ty| ty.to_string().into())
+}
+
+fn params_to_json(params: &[(&str, Option<Type>)]) -> Value {
+    params
+        .iter()
+        .map(|(name, ty)| json!({"name": name, "type": type_to_json(*ty)}))
+        .collect()
+}
+
+fn exprs_to_json(exprs: &[Box<ASTExpr>]) -> Value {
+    exprs.iter().map(|expr| expr_to_json(expr)).collect()
+}
+
+pub fn expr_to_json(expr: &ASTExpr) -> Value {
+    use ASTExpr::*;
+
+    match expr {
+        NumberExpr(num) => json!({"kind": "number", "value": number_to_json(*num)}),
+        IntegerExpr(int) => json!({"kind": "integer", "value": int}),
+        BoolExpr(b) => json!({"kind": "bool", "value": b}),
+        StringExpr(text) => json!({"kind": "string", "value": text}),
+        VariableExpr(name) => json!({"kind": "variable", "name": name}),
+        BreakExpr => json!({"kind": "break"}),
+        ContinueExpr => json!({"kind": "continue"}),
+
+        ArrayExpr(exprs) => json!({"kind": "array", "elements": exprs_to_json(exprs)}),
+        BlockExpr(exprs) => json!({"kind": "block", "exprs": exprs_to_json(exprs)}),
+
+        ArrayFillExpr { value, count } => json!({
+            "kind": "array_fill",
+            "value": expr_to_json(value),
+            "count": expr_to_json(count),
+        }),
+
+        IndexExpr { array, index } => json!({
+            "kind": "index",
+            "array": expr_to_json(array),
+            "index": expr_to_json(index),
+        }),
+
+        LenExpr(operand) => json!({"kind": "len", "operand": expr_to_json(operand)}),
+
+        UnaryExpr { op, operand } => json!({
+            "kind": "unary",
+            "op": op.as_str(),
+            "operand": expr_to_json(operand),
+        }),
+
+        BinaryExpr { op, left, right } => json!({
+            "kind": "binary",
+            "op": op.as_str(),
+            "left": expr_to_json(left),
+            "right": expr_to_json(right),
+        }),
+
+        CallExpr { callee, args } => json!({
+            "kind": "call",
+            "callee": callee,
+            "args": exprs_to_json(args),
+        }),
+
+        ApplyExpr { callee, args, .. } => json!({
+            "kind": "apply",
+            "callee": expr_to_json(callee),
+            "args": exprs_to_json(args),
+        }),
+
+        LambdaExpr {
+            params,
+            ret_type,
+            body,
+            ..
+        } => json!({
+            "kind": "lambda",
+            "params": params_to_json(params),
+            "return_type": type_to_json(*ret_type),
+            "body": expr_to_json(body),
+        }),
+
+        LocalFnExpr { name, lambda, body } => json!({
+            "kind": "local_fn",
+            "name": name,
+            "lambda": expr_to_json(lambda),
+            "body": expr_to_json(body),
+        }),
+
+        IfExpr {
+            cond,
+            then_branch,
+            else_branch,
+        } => json!({
+            "kind": "if",
+            "cond": expr_to_json(cond),
+            "then": expr_to_json(then_branch),
+            "else": expr_to_json(else_branch),
+        }),
+
+        ForLoopExpr {
+            varname,
+            var_type,
+            start,
+            end,
+            step,
+            body,
+        } => json!({
+            "kind": "for",
+            "var": varname,
+            "type": type_to_json(*var_type),
+            "start": expr_to_json(start),
+            "end": expr_to_json(end),
+            "step": expr_to_json(step),
+            "body": expr_to_json(body),
+        }),
+
+        WhileExpr { cond, body } => json!({
+            "kind": "while",
+            "cond": expr_to_json(cond),
+            "body": expr_to_json(body),
+        }),
+
+        VarExpr { var_names, body } => json!({
+            "kind": "var",
+            "bindings": var_names
+                .iter()
+                .map(|(name, ty, init)| json!({
+                    "name": name,
+                    "type": type_to_json(*ty),
+                    "init": init.as_deref().map_or(Value::Null, expr_to_json),
+                }))
+                .collect::<Vec<_>>(),
+            "body": expr_to_json(body),
+        }),
+    }
+}
+
+fn number_to_json(num: f64) -> Value {
+    match num {
+        _ if num.is_nan() => "nan".into(),
+        f64::INFINITY => "inf".into(),
+        f64::NEG_INFINITY => "-inf".into(),
+        _ => num.into(),
+    }
+}
+
+// Reading the tree back
+
+pub fn program_from_json_str(src: &str) -> Result<Vec<Item<'static>>, DecodeError> {
+    program_from_json(&serde_json::from_str(src)?)
+}
+
+pub fn program_from_sexp_str(src: &str) -> Result<Vec<Item<'static>>, DecodeError> {
+    program_from_json(&from_sexp(src)?)
+}
+
+pub fn program_from_json(value: &Value) -> Result<Vec<Item<'static>>, DecodeError> {
+    let program = Fields::of(value, "program")?;
+
+    match program.kind()? {
+        "program" => {}
+        other => return Err(DecodeError::UnknownKind(other.to_string())),
+    }
+
+    let version = program.get("version");
+
+    if version.as_u64() != Some(SCHEMA_VERSION) {
+        return Err(DecodeError::UnsupportedVersion(version.clone()));
+    }
+
+    program.list("items")?.iter().map(item_from_json).collect()
+}
+
+pub fn item_from_json(value: &Value) -> Result<Item<'static>, DecodeError> {
+    let item = Fields::of(value, "item")?;
+
+    Ok(match item.kind()? {
+        "extern" => Item::Extern(prototype_from_json(item.get("prototype"))?),
+
+        "function" => Item::Function(Box::new(Function {
+            proto: prototype_from_json(item.get("prototype"))?,
+            body: item.expr("body")?,
+        })),
+
+        "expression" => Item::Function(Box::new(Function {
+            proto: Box::new(Prototype::FunctionProto {
+                name: &"__anonymous_expr",
+                args: vec![],
+                ret_type: None,
+            }),
+            body: item.expr("body")?,
+        })),
+
+        "global" => Item::Global(Box::new(Global {
+            name: item.name("name")?,
+            var_type: item.opt_type("type")?,
+            init: item.opt_expr("init")?,
+        })),
+
+        other => return Err(DecodeError::UnknownKind(other.to_string())),
+    })
+}
+
+pub fn prototype_from_json(value: &Value) -> Result<Box<Prototype<'static>>, DecodeError> {
+    let proto = Fields::of(value, "prototype")?;
+
+    let proto = match proto.kind()? {
+        "function" => Prototype::FunctionProto {
+            name: proto.name("name")?,
+            args: proto.params("params")?,
+            ret_type: proto.opt_type("return_type")?,
+        },
+
+        "unary_operator" => Prototype::OverloadedUnaryOpProto {
+            operator: proto.op("operator")?,
+            arg: proto.name("param")?,
+        },
+
+        "binary_operator" => {
+            let [lhs, rhs] = proto.list("params")? else {
+                return Err(DecodeError::WrongType("params"));
+            };
+
+            Prototype::OverloadedBinaryOpProto {
+                operator: proto.op("operator")?,
+                args: (
+                    lhs.as_str().map(intern).ok_or(DecodeError::WrongType("params"))?,
+                    rhs.as_str().map(intern).ok_or(DecodeError::WrongType("params"))?,
+                ),
+                precedence: proto
+                    .get("precedence")
+                    .as_i64()
+                    .and_then(|prec| i32::try_from(prec).ok())
+                    .ok_or(DecodeError::WrongType("precedence"))?,
+            }
+        }
+
+        other => return Err(DecodeError::UnknownKind(other.to_string())),
+    };
+
+    Ok(Box::new(proto))
+}
+
+pub fn expr_from_json(value: &Value) -> Result<Box<ASTExpr<'static>>, DecodeError> {
+    use ASTExpr::*;
+
+    let node = Fields::of(value, "expression")?;
+
+    let expr = match node.kind()? {
+        "number" => NumberExpr(node.number("value")?),
+        "integer" => IntegerExpr(node.get("value").as_i64().ok_or(DecodeError::WrongType("value"))?),
+        "bool" => BoolExpr(node.get("value").as_bool().ok_or(DecodeError::WrongType("value"))?),
+        "string" => StringExpr(node.name("value")?),
+        "variable" => VariableExpr(node.name("name")?),
+        "break" => BreakExpr,
+        "continue" => ContinueExpr,
+
+        "array" => ArrayExpr(node.exprs("elements")?),
+        "block" => BlockExpr(node.exprs("exprs")?),
+
+        "array_fill" => ArrayFillExpr {
+            value: node.expr("value")?,
+            count: node.expr("count")?,
+        },
+
+        "index" => IndexExpr {
+            array: node.expr("array")?,
+            index: node.expr("index")?,
+        },
+
+        "len" => LenExpr(node.expr("operand")?),
+
+        "unary" => UnaryExpr {
+            op: node.op("op")?,
+            operand: node.expr("operand")?,
+        },
+
+        "binary" => BinaryExpr {
+            op: node.op("op")?,
+            left: node.expr("left")?,
+            right: node.expr("right")?,
+        },
+
+        "call" => CallExpr {
+            callee: node.name("callee")?,
+            args: node.exprs("args")?,
+        },
+
+        "apply" => ApplyExpr {
+            callee: node.expr("callee")?,
+            args: node.exprs("args")?,
+            callee_type: None,
+        },
+
+        "lambda" => LambdaExpr {
+            params: node.params("params")?,
+            ret_type: node.opt_type("return_type")?,
+            captures: vec![],
+            self_name: None,
+            body: node.expr("body")?,
+        },
+
+        // The backend counts on local functions being lambdas, which call themselves
+        // by the name of the function, like the parser makes them
+        "local_fn" => {
+            let name = node.name("name")?;
+            let mut lambda = node.expr("lambda")?;
+
+            match lambda.as_mut() {
+                LambdaExpr { self_name, .. } => *self_name = Some(name),
+                _ => return Err(DecodeError::WrongType("lambda")),
+            }
+
+            LocalFnExpr {
+                name,
+                lambda,
+                body: node.expr("body")?,
+            }
+        }
+
+        "if" => IfExpr {
+            cond: node.expr("cond")?,
+            then_branch: node.expr("then")?,
+            else_branch: node.expr("else")?,
+        },
+
+        // Same default step as the parser
+        "for" => ForLoopExpr {
+            varname: node.name("var")?,
+            var_type: node.opt_type("type")?,
+            start: node.expr("start")?,
+            end: node.expr("end")?,
+            step: node.opt_expr("step")?.unwrap_or(Box::new(NumberExpr(1.0))),
+            body: node.expr("body")?,
+        },
+
+        "while" => WhileExpr {
+            cond: node.expr("cond")?,
+            body: node.expr("body")?,
+        },
+
+        "var" => VarExpr {
+            var_names: node
+                .list("bindings")?
+                .iter()
+                .map(|binding| {
+                    let binding = Fields::of(binding, "bindings")?;
+
+                    Ok((
+                        binding.name("name")?,
+                        binding.opt_type("type")?,
+                        binding.opt_expr("init")?,
+                    ))
+                })
+                .collect::<Result<_, DecodeError>>()?,
+            body: node.expr("body")?,
+        },
+
+        other => return Err(DecodeError::UnknownKind(other.to_string())),
+    };
+
+    Ok(Box::new(expr))
+}
+
+// The fields of an object, missing ones read as null
+struct Fields<'a>(&'a Map<String, Value>);
+
+impl<'a> Fields<'a> {
+    fn of(value: &'a Value, what: &'static str) -> Result<Self, DecodeError> {
+        value.as_object().map(Fields).ok_or(DecodeError::WrongType(what))
+    }
+
+    fn get(&self, field: &'static str) -> &'a Value {
+        self.0.get(field).unwrap_or(&Value::Null)
+    }
+
+    fn kind(&self) -> Result<&'a str, DecodeError> {
+        self.get("kind").as_str().ok_or(DecodeError::MissingField("kind"))
+    }
+
+    fn required(&self, field: &'static str) -> Result<&'a Value, DecodeError> {
+        match self.get(field) {
+            Value::Null => Err(DecodeError::MissingField(field)),
+            value => Ok(value),
+        }
+    }
+
+    fn list(&self, field: &'static str) -> Result<&'a [Value], DecodeError> {
+        self.required(field)?
+            .as_array()
+            .map(Vec::as_slice)
+            .ok_or(DecodeError::WrongType(field))
+    }
+
+    fn name(&self, field: &'static str) -> Result<&'static str, DecodeError> {
+        self.required(field)?
+            .as_str()
+            .map(intern)
+            .ok_or(DecodeError::WrongType(field))
+    }
+
+    // A number, or one of the strings numbers that aren't finite are written as
+    fn number(&self, field: &'static str) -> Result<f64, DecodeError> {
+        match self.required(field)? {
+            Value::String(text) if text == "nan" => Ok(f64::NAN),
+            Value::String(text) if text == "inf" => Ok(f64::INFINITY),
+            Value::String(text) if text == "-inf" => Ok(f64::NEG_INFINITY),
+            value => value.as_f64().ok_or(DecodeError::WrongType(field)),
+        }
+    }
+
+    fn op(&self, field: &'static str) -> Result<Ops, DecodeError> {
+        let symbol = self.required(field)?.as_str().ok_or(DecodeError::WrongType(field))?;
+        Ops::from_symbol(symbol).ok_or_else(|| DecodeError::UnknownOperator(symbol.to_string()))
+    }
+
+    // Types are read with the parser, same as annotations in source
+    fn opt_type(&self, field: &'static str) -> Result<Option<Type>, DecodeError> {
+        match self.get(field) {
+            Value::Null => Ok(None),
+
+            Value::String(name) => parse_type(&mut name.lex().peekable())
+                .map(Some)
+                .map_err(|_| DecodeError::UnknownType(name.clone())),
+
+            _ => Err(DecodeError::WrongType(field)),
+        }
+    }
+
+    fn params(&self, field: &'static str) -> Result<Vec<(&'static str, Option<Type>)>, DecodeError> {
+        self.list(field)?
+            .iter()
+            .map(|param| {
+                let param = Fields::of(param, field)?;
+                Ok((param.name("name")?, param.opt_type("type")?))
+            })
+            .collect()
+    }
+
+    fn expr(&self, field: &'static str) -> Result<Box<ASTExpr<'static>>, DecodeError> {
+        expr_from_json(self.required(field)?)
+    }
+
+    fn opt_expr(&self, field: &'static str) -> Result<Option<Box<ASTExpr<'static>>>, DecodeError> {
+        match self.get(field) {
+            Value::Null => Ok(None),
+            value => expr_from_json(value).map(Some),
+        }
+    }
+
+    fn exprs(&self, field: &'static str) -> Result<Vec<Box<ASTExpr<'static>>>, DecodeError> {
+        self.list(field)?.iter().map(expr_from_json).collect()
+    }
+}
+
+// S-expressions
+
+pub fn to_sexp(value: &Value) -> String {
+    match value {
+        Value::Null => "nil".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(num) => num.to_string(),
+        Value::String(text) => format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")),
+
+        Value::Array(values) => {
+            format!("[{}]", values.iter().map(to_sexp).collect::<Vec<_>>().join(" "))
+        }
+
+        Value::Object(fields) => {
+            let kind = fields.get("kind").and_then(Value::as_str);
+
+            let fields = fields
+                .iter()
+                .filter(|(key, value)| *key != "kind" && !value.is_null())
+                .map(|(key, value)| format!(":{key} {}", to_sexp(value)));
+
+            format!("({})", kind.into_iter().map(str::to_string).chain(fields).collect::<Vec<_>>().join(" "))
+        }
+    }
+}
+
+pub fn from_sexp(src: &str) -> Result<Value, DecodeError> {
+    let mut reader = SexpReader { src, pos: 0 };
+
+    let value = reader.value()?;
+    reader.skip_whitespace();
+
+    match reader.pos == src.len() {
+        true => Ok(value),
+        false => Err(DecodeError::Sexp(reader.pos, "expected the end of input")),
+    }
+}
+
+struct SexpReader<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl SexpReader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn expect(&mut self, c: char, err: &'static str) -> Result<(), DecodeError> {
+        self.skip_whitespace();
+
+        match self.peek() == Some(c) {
+            true => {
+                self.pos += 1;
+                Ok(())
+            }
+            false => Err(DecodeError::Sexp(self.pos, err)),
+        }
+    }
+
+    // Symbols, keywords, numbers, true/false and nil, up to a delimiter
+    fn atom(&mut self) -> &str {
+        self.skip_whitespace();
+
+        let start = self.pos;
+
+        while self
+            .peek()
+            .is_some_and(|c| !c.is_whitespace() && !"()[]\"".contains(c))
+        {
+            self.pos += self.peek().unwrap().len_utf8();
+        }
+
+        &self.src[start..self.pos]
+    }
+
+    fn value(&mut self) -> Result<Value, DecodeError> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('(') => self.object(),
+
+            Some('[') => {
+                self.pos += 1;
+                let mut values = vec![];
+
+                loop {
+                    self.skip_whitespace();
+
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        return Ok(Value::Array(values));
+                    }
+
+                    values.push(self.value()?);
+                }
+            }
+
+            Some('"') => self.string(),
+
+            Some(_) => {
+                let start = self.pos;
+
+                match self.atom() {
+                    "nil" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+
+                    atom => match (atom.parse::<i64>(), atom.parse::<f64>()) {
+                        (Ok(int), _) => Ok(int.into()),
+                        (_, Ok(num)) => Ok(num.into()),
+                        _ => Err(DecodeError::Sexp(start, "expected a value")),
+                    },
+                }
+            }
+
+            None => Err(DecodeError::Sexp(self.pos, "reached end of input expecting a value")),
+        }
+    }
+
+    // (kind :field value ...), the kind can be left out
+    fn object(&mut self) -> Result<Value, DecodeError> {
+        self.expect('(', "expected (")?;
+
+        let mut fields = Map::new();
+
+        loop {
+            self.skip_whitespace();
+
+            if self.peek() == Some(')') {
+                self.pos += 1;
+                return Ok(Value::Object(fields));
+            }
+
+            let start = self.pos;
+
+            match self.atom() {
+                "" => return Err(DecodeError::Sexp(start, "expected a field")),
+
+                field if field.starts_with(':') => {
+                    let field = field[1..].to_string();
+                    let value = self.value()?;
+                    fields.insert(field, value);
+                }
+
+                kind if fields.is_empty() => {
+                    let kind = kind.to_string();
+                    fields.insert("kind".to_string(), kind.into());
+                }
+
+                _ => return Err(DecodeError::Sexp(start, "expected a field")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<Value, DecodeError> {
+        self.expect('"', "expected \"")?;
+
+        let mut text = String::new();
+
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(Value::String(text));
+                }
+
+                Some('\\') => {
+                    self.pos += 1;
+
+                    match self.peek() {
+                        Some(c @ ('"' | '\\')) => {
+                            text.push(c);
+                            self.pos += 1;
+                        }
+                        _ => return Err(DecodeError::Sexp(self.pos, "unknown escape")),
+                    }
+                }
+
+                Some(c) => {
+                    text.push(c);
+                    self.pos += c.len_utf8();
+                }
+
+                None => return Err(DecodeError::Sexp(self.pos, "unterminated string")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::parser::parse_item;
+
+    fn parse_program(src: &str) -> Vec<Item> {
+        let mut tokens = src.lex().peekable();
+        let mut items = vec![];
+
+        while tokens.peek().is_some() {
+            items.push(parse_item(&mut tokens).unwrap());
+            let _semicolon = tokens.next();
+        }
+
+        items
+    }
+
+    #[test]
+    fn programs_survive_the_round_trip() {
+        let src = r#"extern printd(x);
+            global count: i64 = 0;
+            def binary| 5 (a b) if a then 1 else if b then 1 else 0;
+            def unary!(v) if v then 0 else 1;
+            def sum(arr: [f64]) -> f64 var total = 0, i: i64 in { for i = 0, i < len(arr), 2 in total = total + arr[i]; total };
+            def twice(f: fn(f64) -> f64, x) f(f(x));
+            def fact(n) def go(k) -> f64 if k < 2 then 1 else k * go(k - 1) in go(n);
+            def loop(x) while x > 0 do { x = x - 1; if x < 5 then break else continue };
+            twice(\y -> y * 2, [2; 3][0]) + len([1, 2]) + len("say \"hi\"");"#;
+
+        let items = parse_program(src);
+
+        let json = serde_json::to_string_pretty(&program_to_json(&items)).unwrap();
+        assert_eq!(program_from_json_str(&json).unwrap(), items);
+
+        let sexp = program_to_sexp(&items);
+        assert_eq!(program_from_sexp_str(&sexp).unwrap(), items);
+    }
+
+    #[test]
+    fn the_schema_is_stable() {
+        let items = parse_program("def inc(x: i64) -> i64 x + 1;");
+
+        assert_eq!(
+            program_to_json(&items).to_string(),
+            r#"{"kind":"program","version":1,"items":[{"kind":"function","prototype":{"kind":"function","name":"inc","params":[{"name":"x","type":"i64"}],"return_type":"i64"},"body":{"kind":"binary","op":"+","left":{"kind":"variable","name":"x"},"right":{"kind":"number","value":1.0}}}]}"#
+        );
+
+        assert_eq!(
+            program_to_sexp(&items),
+            r#"(program :version 1 :items [
+  (function :prototype (function :name "inc" :params [(:name "x" :type "i64")] :return_type "i64") :body (binary :op "+" :left (variable :name "x") :right (number :value 1.0)))
+])
+"#
+        );
+
+        // Unknown kinds and newer versions are refused rather than half read
+        assert!(matches!(
+            program_from_sexp_str("(program :version 2 :items [])"),
+            Err(DecodeError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            program_from_sexp_str("(program :version 1 :items [(expression :body (goto :label \"x\"))])"),
+            Err(DecodeError::UnknownKind(kind)) if kind == "goto"
+        ));
+    }
+
+    #[test]
+    fn numbers_json_cant_hold_survive_too() {
+        let expression = |num| {
+            vec![Item::Function(Box::new(Function {
+                proto: Box::new(Prototype::FunctionProto {
+                    name: "__anonymous_expr",
+                    args: vec![],
+                    ret_type: None,
+                }),
+                body: Box::new(ASTExpr::NumberExpr(num)),
+            }))]
+        };
+
+        for num in [f64::INFINITY, f64::NEG_INFINITY] {
+            let items = expression(num);
+
+            assert_eq!(program_from_json_str(&program_to_json(&items).to_string()).unwrap(), items);
+            assert_eq!(program_from_sexp_str(&program_to_sexp(&items)).unwrap(), items);
+        }
+
+        // NaN isn't equal to itself, so the items can't be compared
+        let json = program_to_json(&expression(f64::NAN)).to_string();
+
+        match &program_from_json_str(&json).unwrap()[..] {
+            [Item::Function(func)] => assert!(matches!(*func.body, ASTExpr::NumberExpr(num) if num.is_nan())),
+            items => panic!("expected one expression, got {items:?}"),
+        }
+    }
+
+    #[test]
+    fn any_whitespace_separates() {
+        assert_eq!(
+            program_from_sexp_str("(program\u{3000}:version 1\u{a0}:items [])\u{2028}").unwrap(),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::frontend::{
+    ast::{fold_expr_children, walk_expr, ASTExpr, Folder, Function, Visitor},
+    lexer::Ops,
+};
+
+// Simplifies a function after it has been type checked, before any IR is generated
+// for it, so it happens whatever the optimization level, and --inspect-tree shows
+// the simplified tree. Three things are simplified:
+//
+// - Operators applied to literals are computed, exactly like the generated code
+//   would at runtime. Doubles follow IEEE 754, so identities that don't hold for
+//   every double (x * 0.0 is not 0.0 when x is NaN, infinite or negative) are left
+//   alone, and integer division by zero or overflowing is left for the runtime check
+//   the backend generates, see build_int_division.
+// - An if with a literal condition is replaced by the branch that would be taken.
+// - Loops whose condition is a literal false. A while loop like that never runs its
+//   body, a for loop checks its end condition after the body (like the tutorial),
+//   so it runs the body once, which is what a var with the loop variable does.
+pub fn simplify(func: &mut Function) {
+    let body = std::mem::replace(&mut func.body, Box::new(ASTExpr::NumberExpr(0.0)));
+    func.body = Simplifier.fold_expr(body);
+}
+
+struct Simplifier;
+
+impl<'src> Folder<'src> for Simplifier {
+    // Children first, so the literals they simplify to can be simplified further
+    fn fold_expr(&mut self, expr: Box<ASTExpr<'src>>) -> Box<ASTExpr<'src>> {
+        use ASTExpr::*;
+
+        let expr = fold_expr_children(self, expr);
+
+        match *expr {
+            UnaryExpr { op, ref operand } => match (op, &**operand) {
+                (Ops::Minus, IntegerExpr(int)) => Box::new(IntegerExpr(int.wrapping_neg())),
+                (Ops::Negate, BoolExpr(b)) => Box::new(BoolExpr(!b)),
+                (Ops::Negate, IntegerExpr(int)) => Box::new(IntegerExpr(!int)),
+
+                // Minus on a double is a user defined operator
+                _ => expr,
+            },
+
+            BinaryExpr { op, ref left, ref right } => match fold_binary(op, left, right) {
+                Some(folded) => Box::new(folded),
+                None => expr,
+            },
+
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => match literal_truth(&cond) {
+                Some(true) => then_branch,
+                Some(false) => else_branch,
+                None => Box::new(IfExpr {
+                    cond,
+                    then_branch,
+                    else_branch,
+                }),
+            },
+
+            WhileExpr { ref cond, .. } if literal_truth(cond) == Some(false) => {
+                Box::new(NumberExpr(0.0))
+            }
+
+            // A break or continue in the body would have no loop left to jump out of
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            } if literal_truth(&end) == Some(false) && !has_loop_control(&body) => {
+                let mut once = vec![body];
+
+                // The step is evaluated once too, the value it adds goes nowhere
+                if !is_literal(&step) {
+                    once.push(step);
+                }
+
+                once.push(Box::new(NumberExpr(0.0)));
+
+                Box::new(VarExpr {
+                    var_names: vec![(varname, var_type, Some(start))],
+                    body: Box::new(BlockExpr(once)),
+                })
+            }
+
+            _ => expr,
+        }
+    }
+}
+
+fn is_literal(expr: &ASTExpr) -> bool {
+    matches!(
+        expr,
+        ASTExpr::NumberExpr(_) | ASTExpr::IntegerExpr(_) | ASTExpr::BoolExpr(_)
+    )
+}
+
+// The truthiness of a literal condition, same as the backend's build_truthiness,
+// NaN is false since it compares unordered with 0.0
+fn literal_truth(expr: &ASTExpr) -> Option<bool> {
+    match expr {
+        ASTExpr::BoolExpr(b) => Some(*b),
+        ASTExpr::IntegerExpr(int) => Some(*int != 0),
+        ASTExpr::NumberExpr(num) => Some(*num < 0.0 || *num > 0.0),
+        _ => None,
+    }
+}
+
+// Operators on two literals of the same type. Booleans in arithmetic, strings and
+// user defined operators are left for the backend.
+fn fold_binary<'src>(op: Ops, left: &ASTExpr<'src>, right: &ASTExpr<'src>) -> Option<ASTExpr<'src>> {
+    use ASTExpr::*;
+
+    let folded = match (left, right) {
+        (NumberExpr(l), NumberExpr(r)) => match op {
+            Ops::Plus => NumberExpr(l + r),
+            Ops::Minus => NumberExpr(l - r),
+            Ops::Mult => NumberExpr(l * r),
+            Ops::Div => NumberExpr(l / r),
+
+            // Ordered comparisons, anything compared with NaN is false, even !=
+            Ops::Eq => BoolExpr(l == r),
+            Ops::Neq => BoolExpr(l < r || l > r),
+            Ops::Lt => BoolExpr(l < r),
+            Ops::Gt => BoolExpr(l > r),
+
+            _ => return None,
+        },
+
+        (IntegerExpr(l), IntegerExpr(r)) => match op {
+            Ops::Plus => IntegerExpr(l.wrapping_add(*r)),
+            Ops::Minus => IntegerExpr(l.wrapping_sub(*r)),
+            Ops::Mult => IntegerExpr(l.wrapping_mul(*r)),
+            Ops::Div => IntegerExpr(l.checked_div(*r)?),
+            Ops::And => IntegerExpr(l & r),
+            Ops::Or => IntegerExpr(l | r),
+            Ops::Xor => IntegerExpr(l ^ r),
+            Ops::Eq => BoolExpr(l == r),
+            Ops::Neq => BoolExpr(l != r),
+            Ops::Lt => BoolExpr(l < r),
+            Ops::Gt => BoolExpr(l > r),
+
+            _ => return None,
+        },
+
+        (BoolExpr(l), BoolExpr(r)) => match op {
+            Ops::And => BoolExpr(l & r),
+            Ops::Or => BoolExpr(l | r),
+            Ops::Xor | Ops::Neq => BoolExpr(l ^ r),
+            Ops::Eq => BoolExpr(l == r),
+
+            _ => return None,
+        },
+
+        // Integers are exact, adding zero or multiplying by one changes nothing
+        (other, IntegerExpr(0)) | (IntegerExpr(0), other) if op == Ops::Plus => other.clone(),
+        (other, IntegerExpr(0)) if op == Ops::Minus => other.clone(),
+        (other, IntegerExpr(1)) | (IntegerExpr(1), other) if op == Ops::Mult => other.clone(),
+        (other, IntegerExpr(1)) if op == Ops::Div => other.clone(),
+
+        _ => return None,
+    };
+
+    Some(folded)
+}
+
+// Whether a break or continue in expr belongs to a loop around expr. Those in the
+// body of a nested loop or a lambda belong to that loop, or aren't allowed at all.
+fn has_loop_control(expr: &ASTExpr) -> bool {
+    struct FindLoopControl(bool);
+
+    impl<'src> Visitor<'src> for FindLoopControl {
+        fn visit_expr(&mut self, expr: &ASTExpr<'src>) {
+            match expr {
+                ASTExpr::BreakExpr | ASTExpr::ContinueExpr => self.0 = true,
+
+                ASTExpr::ForLoopExpr {
+                    start, end, step, ..
+                } => {
+                    self.visit_expr(start);
+                    self.visit_expr(end);
+                    self.visit_expr(step);
+                }
+
+                ASTExpr::WhileExpr { cond, .. } => self.visit_expr(cond),
+
+                ASTExpr::LambdaExpr { .. } => {}
+
+                _ => walk_expr(self, expr),
+            }
+        }
+    }
+
+    let mut finder = FindLoopControl(false);
+    finder.visit_expr(expr);
+    finder.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, parser::parse_definition, typecheck::TypeChecker};
+
+    fn simplified(src: &str) -> Box<ASTExpr> {
+        let mut func = parse_definition(&mut src.lex().peekable()).unwrap();
+        TypeChecker::new().check_function(&mut func).unwrap();
+        simplify(&mut func);
+        func.body
+    }
+
+    #[test]
+    fn folds_constants_like_the_hardware_would() {
+        assert_eq!(simplified("def f() 1 + 2 * 3;"), Box::new(ASTExpr::NumberExpr(7.0)));
+        assert_eq!(simplified("def f() -> i64 7 / 2;"), Box::new(ASTExpr::IntegerExpr(3)));
+        assert_eq!(simplified("def f() (1 / 0) < 2;"), Box::new(ASTExpr::BoolExpr(false)));
+
+        // Neither of these are the same as x for every double
+        assert!(matches!(*simplified("def f(x) x * 0;"), ASTExpr::BinaryExpr { .. }));
+        assert!(matches!(*simplified("def f(x) x + 0;"), ASTExpr::BinaryExpr { .. }));
+
+        // But they are for integers
+        assert_eq!(
+            simplified("def f(x: i64) -> i64 (x + 0) * 1;"),
+            Box::new(ASTExpr::VariableExpr(&"x"))
+        );
+
+        // Division by zero is left for the runtime check
+        assert!(matches!(
+            *simplified("def f() -> i64 1 / 0;"),
+            ASTExpr::BinaryExpr { op: Ops::Div, .. }
+        ));
+    }
+
+    #[test]
+    fn removes_dead_branches_and_loops() {
+        assert_eq!(simplified("def f(a b) if 1 then a else b;"), Box::new(ASTExpr::VariableExpr(&"a")));
+        assert_eq!(simplified("def f(a b) if 2 < 1 then a else b;"), Box::new(ASTExpr::VariableExpr(&"b")));
+        assert_eq!(simplified("def f(x) while false do x = 1;"), Box::new(ASTExpr::NumberExpr(0.0)));
+
+        // The body of a for loop runs before the end condition is checked
+        assert_eq!(
+            simplified("def f(x) for i = 0, false in x = i;"),
+            Box::new(ASTExpr::VarExpr {
+                var_names: vec![(&"i", None, Some(Box::new(ASTExpr::NumberExpr(0.0))))],
+                body: Box::new(ASTExpr::BlockExpr(vec![
+                    Box::new(ASTExpr::BinaryExpr {
+                        op: Ops::Assign,
+                        left: Box::new(ASTExpr::VariableExpr(&"x")),
+                        right: Box::new(ASTExpr::VariableExpr(&"i")),
+                    }),
+                    Box::new(ASTExpr::NumberExpr(0.0)),
+                ])),
+            })
+        );
+
+        // Mixing a boolean and a double branch still gives a double
+        assert_eq!(
+            simplified("def f(a b) if true then a < b else 2;"),
+            Box::new(ASTExpr::IfExpr {
+                cond: Box::new(ASTExpr::BinaryExpr {
+                    op: Ops::Lt,
+                    left: Box::new(ASTExpr::VariableExpr(&"a")),
+                    right: Box::new(ASTExpr::VariableExpr(&"b")),
+                }),
+                then_branch: Box::new(ASTExpr::NumberExpr(1.0)),
+                else_branch: Box::new(ASTExpr::NumberExpr(0.0)),
+            })
+        );
+    }
+}
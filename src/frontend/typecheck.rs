@@ -0,0 +1,1253 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::frontend::{
+    ast::{ASTExpr, Function, Global, Prototype, Type, VarBinding},
+    lexer::Ops,
+    prelude::prelude,
+    scope::ScopeStack,
+};
+
+// Errors the type checker can find in a program, all of these are
+// caught before a single line of LLVM IR is generated for it.
+#[derive(Error, PartialEq, Debug)]
+pub enum TypeError<'src> {
+    #[error("Type mismatch, expected {expected} but found {found}")]
+    Mismatch { expected: Type, found: Type },
+
+    #[error("Operator {op:?} cannot be applied to {left} and {right}")]
+    IncompatibleOperands { op: Ops, left: Type, right: Type },
+
+    #[error("Unknown variable name {0}")]
+    UnknownVariable(&'src str),
+
+    #[error("Undefined function {0}")]
+    UndefinedFunction(&'src str),
+
+    #[error("Incorrect number of arguments passed to {func_name}, expected {param_cnt}")]
+    IncorrectNumberOfArgs {
+        func_name: &'src str,
+        param_cnt: usize,
+    },
+
+    #[error("Undefined operator used: {0:?}")]
+    UndefinedOperator(Ops),
+
+    #[error("Incorrect assignment of variable, left side must be a string name")]
+    BadAssignment,
+
+    #[error("Global {0} must be initialized with a literal")]
+    NonConstantGlobal(&'src str),
+
+    #[error("Global {name} cannot hold a {found}, only f64, i64 and bool")]
+    UnsupportedGlobalType { name: &'src str, found: Type },
+
+    #[error("Global {0} declared twice")]
+    GlobalRedefined(&'src str),
+
+    #[error("Cannot call a value of type {0}")]
+    NotAFunction(Type),
+
+    #[error("Variable {0} used in a var initializer before its definition")]
+    UsedBeforeDefinition(&'src str),
+
+    #[error("Number {0} does not fit in an i64")]
+    NotAnInteger(f64),
+}
+
+type TypeCheckResult<'src> = Result<Type, TypeError<'src>>;
+
+// Variables visible at some point inside of a function body, bound to their types
+type Scope = ScopeStack<Type>;
+
+// The type checker sits in between the parser and LLVMCodeGen. Besides
+// rejecting ill-typed programs, it also elaborates the tree a little: number
+// literals used where an integer is expected are rewritten into IntegerExpr,
+// so the backend never has to guess what type a literal should have.
+//
+// It remembers the signature of every prototype and the type of every global
+// it has seen, so a REPL session should hold onto one checker, just like it
+// does with LLVMContext.
+#[derive(Debug, Default, Clone)]
+pub struct TypeChecker {
+    signatures: HashMap<String, (Vec<Type>, Type)>,
+    globals: HashMap<String, Type>,
+}
+
+impl TypeChecker {
+    // Knows the signatures of the prelude from the start
+    pub fn new() -> Self {
+        let mut checker = Self::default();
+
+        for proto in prelude() {
+            checker.check_prototype(&proto);
+        }
+
+        checker
+    }
+
+    // Externs have no body to check, simply record their signature
+    pub fn check_prototype(&mut self, proto: &Prototype) {
+        self.signatures.insert(
+            proto.get_name(),
+            (proto.get_param_types(), proto.get_return_type()),
+        );
+    }
+
+    // Globals live in memory for the whole program, they can only start out as a
+    // literal (or zero when left uninitialized), which the backend turns into a constant.
+    // An unannotated global takes the type of its initializer, written back into the tree.
+    pub fn check_global<'src>(&mut self, global: &mut Global<'src>) -> TypeCheckResult<'src> {
+        if self.globals.contains_key(global.name) {
+            return Err(TypeError::GlobalRedefined(global.name));
+        }
+
+        // A negative number is parsed as minus applied to a literal, minus on a double is a
+        // user defined operator elsewhere, but here it can only mean the negative literal
+        if let Some(ASTExpr::UnaryExpr { op: Ops::Minus, operand }) = global.init.as_deref() {
+            if let ASTExpr::NumberExpr(num) = **operand {
+                global.init = Some(Box::new(ASTExpr::NumberExpr(-num)));
+            }
+        }
+
+        let var_type = match global.init.as_deref_mut() {
+            Some(init) => {
+                if !matches!(init, ASTExpr::NumberExpr(_) | ASTExpr::BoolExpr(_)) {
+                    return Err(TypeError::NonConstantGlobal(global.name));
+                }
+
+                let init_type = self.check_expr(init, &mut Scope::new(), global.var_type)?;
+
+                match global.var_type {
+                    Some(annotated) => coerce(init_type, annotated)?,
+                    None => init_type,
+                }
+            }
+
+            None => global.var_type.unwrap_or(Type::F64),
+        };
+
+        if !matches!(var_type, Type::F64 | Type::I64 | Type::Bool) {
+            return Err(TypeError::UnsupportedGlobalType {
+                name: global.name,
+                found: var_type,
+            });
+        }
+
+        global.var_type = Some(var_type);
+        self.globals.insert(global.name.to_string(), var_type);
+
+        Ok(var_type)
+    }
+
+    // Local variables shadow globals of the same name
+    fn lookup_variable<'src>(&self, name: &'src str, scope: &Scope) -> TypeCheckResult<'src> {
+        scope
+            .lookup(name)
+            .or_else(|| self.globals.get(name))
+            .copied()
+            .ok_or(TypeError::UnknownVariable(name))
+    }
+
+    // Check a function body against its signature, returning the type of the body.
+    // The signature is registered up front so that functions may recurse, if the
+    // body turns out to be ill-typed whatever was there before is put back.
+    pub fn check_function<'src>(&mut self, func: &mut Function<'src>) -> TypeCheckResult<'src> {
+        let name = func.proto.get_name();
+        let param_types = func.proto.get_param_types();
+        let ret_type = func.proto.get_return_type();
+
+        let previous = self
+            .signatures
+            .insert(name.clone(), (param_types.clone(), ret_type));
+
+        let mut scope = Scope::new();
+
+        for (param_name, param_type) in func.proto.get_param_names().into_iter().zip(param_types) {
+            scope.define(param_name, param_type);
+        }
+
+        // Top level expressions may evaluate to any number or boolean, the backend takes
+        // care of converting their result into a double for the JIT to hand back
+        let res = if name == "__anonymous_expr" {
+            self.check_expr(&mut func.body, &mut scope, None)
+                .and_then(|body_type| match body_type {
+                    found @ (Type::Str | Type::Array | Type::Fn(_)) => Err(TypeError::Mismatch {
+                        expected: Type::F64,
+                        found,
+                    }),
+                    body_type => Ok(body_type),
+                })
+        } else {
+            self.check_expr(&mut func.body, &mut scope, Some(ret_type))
+                .and_then(|body_type| coerce(body_type, ret_type))
+        };
+
+        if res.is_err() {
+            match previous {
+                Some(signature) => self.signatures.insert(name, signature),
+                None => self.signatures.remove(&name),
+            };
+        }
+
+        res
+    }
+
+    // Infer the type of an expression, "hint" is the type the surrounding
+    // expression would like to see, only used to decide the type of literals.
+    fn check_expr<'src>(
+        &self,
+        expr: &mut ASTExpr<'src>,
+        scope: &mut Scope,
+        hint: Option<Type>,
+    ) -> TypeCheckResult<'src> {
+        use ASTExpr::*;
+
+        match expr {
+            NumberExpr(num) => {
+                if hint == Some(Type::I64) && num.fract() == 0.0 {
+                    // A whole number too big for an i64 would be quietly
+                    // saturated by `as`, i64::MAX as f64 rounds up to 2^63
+                    // so that end of the range is open
+                    if *num < i64::MIN as f64 || *num >= i64::MAX as f64 {
+                        return Err(TypeError::NotAnInteger(*num));
+                    }
+                    *expr = IntegerExpr(*num as i64);
+                    Ok(Type::I64)
+                } else {
+                    Ok(Type::F64)
+                }
+            }
+
+            IntegerExpr(_) => Ok(Type::I64),
+
+            BoolExpr(_) => Ok(Type::Bool),
+
+            StringExpr(_) => Ok(Type::Str),
+
+            ArrayExpr(elements) => {
+                for element in elements.iter_mut() {
+                    let element_type = self.check_expr(element, scope, Some(Type::F64))?;
+                    coerce(element_type, Type::F64)?;
+                }
+
+                Ok(Type::Array)
+            }
+
+            ArrayFillExpr { value, count } => {
+                let value_type = self.check_expr(value, scope, Some(Type::F64))?;
+                coerce(value_type, Type::F64)?;
+
+                self.check_array_index(count, scope)?;
+
+                Ok(Type::Array)
+            }
+
+            IndexExpr { array, index } => self.check_array_access(array, index, scope),
+
+            LenExpr(operand) => match self.check_expr(operand, scope, None)? {
+                Type::Array | Type::Str => Ok(Type::I64),
+                found => Err(TypeError::Mismatch {
+                    expected: Type::Array,
+                    found,
+                }),
+            },
+
+            // Naming a function, rather than calling it, gives a function value
+            VariableExpr(name) => self.lookup_variable(name, scope).or_else(|err| {
+                self.signatures
+                    .get(*name)
+                    .map(|(param_types, ret_type)| Type::function(param_types.clone(), *ret_type))
+                    .ok_or(err)
+            }),
+
+            UnaryExpr { op, operand } => {
+                let operand_type = self.check_expr(operand, scope, hint)?;
+
+                match (*op, operand_type) {
+                    (Ops::Negate, Type::Bool) => Ok(Type::Bool),
+                    (Ops::Negate, Type::I64) => Ok(Type::I64),
+                    (Ops::Minus, Type::I64) => Ok(Type::I64),
+
+                    // Everything else has to be a user defined operator on doubles
+                    (op, operand_type) => {
+                        if !self.signatures.contains_key(&format!("unary{}", op.as_str())) {
+                            return Err(TypeError::UndefinedOperator(op));
+                        }
+
+                        coerce(operand_type, Type::F64)
+                    }
+                }
+            }
+
+            BinaryExpr { op, left, right } => {
+                if let Ops::Assign = op {
+                    let var_type = match **left {
+                        VariableExpr(name) => self.lookup_variable(name, scope),
+
+                        IndexExpr {
+                            ref mut array,
+                            ref mut index,
+                        } => self.check_array_access(array, index, scope),
+
+                        _ => Err(TypeError::BadAssignment),
+                    }?;
+
+                    let right_type = self.check_expr(right, scope, Some(var_type))?;
+
+                    return coerce(right_type, var_type);
+                }
+
+                // Comparisons produce a boolean, what we were hinted says nothing about operands
+                let operand_hint = match op {
+                    Ops::Eq | Ops::Neq | Ops::Lt | Ops::Gt => None,
+                    _ => hint,
+                };
+
+                let (left_type, right_type) =
+                    self.check_operands(left, right, scope, operand_hint)?;
+
+                let is_double = |ty| matches!(ty, Type::F64 | Type::Bool);
+
+                match (*op, left_type, right_type) {
+                    (Ops::Plus | Ops::Minus | Ops::Mult | Ops::Div, Type::I64, Type::I64) => {
+                        Ok(Type::I64)
+                    }
+
+                    // Adding strings concatenates them
+                    (Ops::Plus, Type::Str, Type::Str) => Ok(Type::Str),
+
+                    // Booleans are promoted to doubles in arithmetic, like the tutorial's 0.0/1.0
+                    (Ops::Plus | Ops::Minus | Ops::Mult | Ops::Div, l, r)
+                        if is_double(l) && is_double(r) =>
+                    {
+                        Ok(Type::F64)
+                    }
+
+                    (Ops::Eq | Ops::Neq, l, r)
+                        if (l == r && !matches!(l, Type::Array | Type::Fn(_)))
+                            || (is_double(l) && is_double(r)) =>
+                    {
+                        Ok(Type::Bool)
+                    }
+
+                    (Ops::Lt | Ops::Gt, Type::I64, Type::I64) => Ok(Type::Bool),
+
+                    (Ops::Lt | Ops::Gt, Type::Str, Type::Str) => Ok(Type::Bool),
+
+                    (Ops::Lt | Ops::Gt, l, r) if is_double(l) && is_double(r) => Ok(Type::Bool),
+
+                    (Ops::And | Ops::Or | Ops::Xor, l, r)
+                        if l == r && matches!(l, Type::Bool | Type::I64) =>
+                    {
+                        Ok(l)
+                    }
+
+                    (op @ (Ops::Plus | Ops::Minus | Ops::Mult | Ops::Div | Ops::Eq | Ops::Neq | Ops::Lt | Ops::Gt), left, right) => {
+                        Err(TypeError::IncompatibleOperands { op, left, right })
+                    }
+
+                    // Whatever is left must be a user defined operator, which only work on doubles
+                    (op, left, right) => {
+                        if !self.signatures.contains_key(&format!("binary{}", op.as_str())) {
+                            return Err(TypeError::UndefinedOperator(op));
+                        }
+
+                        if !(is_double(left) && is_double(right)) {
+                            return Err(TypeError::IncompatibleOperands { op, left, right });
+                        }
+
+                        Ok(Type::F64)
+                    }
+                }
+            }
+
+            // Variables holding functions are called like any other function, but
+            // need an indirect call, so rewrite those calls into ApplyExpr
+            CallExpr { callee, args } if self.lookup_variable(callee, scope).is_ok() => {
+                *expr = ApplyExpr {
+                    callee: Box::new(VariableExpr(callee)),
+                    args: std::mem::take(args),
+                    callee_type: None,
+                };
+
+                self.check_expr(expr, scope, hint)
+            }
+
+            CallExpr { callee, args } => {
+                let (param_types, ret_type) = self
+                    .signatures
+                    .get(*callee)
+                    .ok_or(TypeError::UndefinedFunction(callee))?;
+
+                if param_types.len() != args.len() {
+                    return Err(TypeError::IncorrectNumberOfArgs {
+                        func_name: callee,
+                        param_cnt: param_types.len(),
+                    });
+                }
+
+                for (arg, param_type) in args.iter_mut().zip(param_types) {
+                    let arg_type = self.check_expr(arg, scope, Some(*param_type))?;
+                    coerce(arg_type, *param_type)?;
+                }
+
+                Ok(*ret_type)
+            }
+
+            ApplyExpr {
+                callee,
+                args,
+                callee_type,
+            } => {
+                let fn_type = self.check_expr(callee, scope, None)?;
+
+                let Type::Fn(sig) = fn_type else {
+                    return Err(TypeError::NotAFunction(fn_type));
+                };
+
+                if sig.params.len() != args.len() {
+                    return Err(TypeError::IncorrectNumberOfArgs {
+                        func_name: match **callee {
+                            VariableExpr(name) => name,
+                            _ => &"<function value>",
+                        },
+                        param_cnt: sig.params.len(),
+                    });
+                }
+
+                for (arg, param_type) in args.iter_mut().zip(&sig.params) {
+                    let arg_type = self.check_expr(arg, scope, Some(*param_type))?;
+                    coerce(arg_type, *param_type)?;
+                }
+
+                *callee_type = Some(fn_type);
+
+                Ok(sig.ret)
+            }
+
+            // Unannotated parameters take their type from the function type we were
+            // hinted, if any, like a lambda passed straight to map(xs, \x -> x * 2),
+            // and are doubles otherwise. Unless annotated (as local functions always
+            // are), the return type is whatever the body is.
+            LambdaExpr {
+                params,
+                ret_type,
+                captures,
+                self_name,
+                body,
+            } => {
+                let hinted_sig = match hint {
+                    Some(Type::Fn(sig)) if sig.params.len() == params.len() => Some(sig),
+                    _ => None,
+                };
+
+                for (idx, (_, annotation)) in params.iter_mut().enumerate() {
+                    let param_type = annotation
+                        .or(hinted_sig.map(|sig| sig.params[idx]))
+                        .unwrap_or(Type::F64);
+
+                    *annotation = Some(param_type);
+                }
+
+                let param_types = params.iter().map(|(_, ty)| ty.unwrap()).collect::<Vec<_>>();
+
+                // The body sees everything the lambda was written next to, the lambda
+                // itself if it is a local function, then its parameters
+                let mut lambda_scope = scope.clone();
+                lambda_scope.push_scope();
+
+                if let (Some(name), Some(annotated)) = (*self_name, *ret_type) {
+                    lambda_scope.define(name, Type::function(param_types.clone(), annotated));
+                }
+
+                lambda_scope.push_scope();
+
+                for (name, param_type) in params.iter() {
+                    lambda_scope.define(name, param_type.unwrap());
+                }
+
+                let body_hint = ret_type.or(hinted_sig.map(|sig| sig.ret));
+                let body_type = self.check_expr(body, &mut lambda_scope, body_hint)?;
+
+                let body_type = match ret_type {
+                    Some(annotated) => coerce(body_type, *annotated)?,
+                    None => body_type,
+                };
+
+                *ret_type = Some(body_type);
+
+                // The local variables of enclosing scopes the body refers to are captured,
+                // globals and functions can be reached from anywhere and need not be
+                let mut bound = params.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                bound.extend(*self_name);
+
+                let mut free = vec![];
+                free_variables(body, &mut bound, &mut free);
+
+                *captures = free.into_iter().filter(|name| scope.contains(name)).collect();
+
+                Ok(Type::function(param_types, body_type))
+            }
+
+            LocalFnExpr { name, lambda, body } => {
+                let fn_type = self.check_expr(lambda, scope, None)?;
+
+                scope.push_scope();
+                scope.define(name, fn_type);
+
+                let body_type = self.check_expr(body, scope, hint);
+
+                scope.pop_scope();
+
+                body_type
+            }
+
+            IfExpr {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.check_condition(cond, scope)?;
+
+                let (then_type, else_type) =
+                    self.check_operands(then_branch, else_branch, scope, hint)?;
+
+                // A branch that breaks out of (or continues) a loop never produces a
+                // value, so the if takes on the type of the other branch
+                match (then_type, else_type) {
+                    _ if then_branch.is_loop_control() => Ok(else_type),
+                    _ if else_branch.is_loop_control() => Ok(then_type),
+                    (then_type, else_type) if then_type == else_type => Ok(then_type),
+
+                    // The boolean branch is converted explicitly, so the branches still agree
+                    // on a type after the simplifier has thrown one of them away
+                    (Type::F64 | Type::Bool, Type::F64 | Type::Bool) => {
+                        promote_to_double(then_branch, then_type);
+                        promote_to_double(else_branch, else_type);
+
+                        Ok(Type::F64)
+                    }
+
+                    (expected, found) => Err(TypeError::Mismatch { expected, found }),
+                }
+            }
+
+            ForLoopExpr {
+                varname,
+                var_type,
+                start,
+                end,
+                step,
+                body,
+            } => {
+                let start_type = self.check_expr(start, scope, *var_type)?;
+
+                let loop_var_type = match var_type {
+                    Some(annotated) => coerce(start_type, *annotated)?,
+                    None => start_type,
+                };
+
+                // The step is added on to the loop variable, which only numbers can do
+                if !matches!(loop_var_type, Type::F64 | Type::I64 | Type::Bool) {
+                    return Err(TypeError::Mismatch {
+                        expected: Type::F64,
+                        found: loop_var_type,
+                    });
+                }
+
+                scope.push_scope();
+                scope.define(varname, loop_var_type);
+
+                let loop_res = self.check_loop_parts(end, step, body, loop_var_type, scope);
+
+                scope.pop_scope();
+
+                // For loops always evaluate to 0.0
+                loop_res.map(|_| Type::F64)
+            }
+
+            // Only the last expression is given the hint, the rest are evaluated for
+            // their side effects and may be of any type
+            BlockExpr(exprs) => {
+                let (last, rest) = exprs.split_last_mut().expect("blocks are never empty");
+
+                for expr in rest {
+                    self.check_expr(expr, scope, None)?;
+                }
+
+                self.check_expr(last, scope, hint)
+            }
+
+            WhileExpr { cond, body } => {
+                self.check_condition(cond, scope)?;
+                self.check_expr(body, scope, None)?;
+
+                // Same as for loops, while loops evaluate to 0.0
+                Ok(Type::F64)
+            }
+
+            // Control never continues past these, but like loops they are given a value of 0.0
+            BreakExpr | ContinueExpr => Ok(Type::F64),
+
+            // Each variable is in scope for the initializers of the ones that follow it,
+            // as well as the body. Like the tutorial, an initializer referring to the name
+            // it initializes (var x = x in ...) sees the x of an enclosing scope.
+            VarExpr { var_names, body } => {
+                scope.push_scope();
+
+                let var_res = self.check_var_bindings(var_names, scope).and_then(|_| {
+                    self.check_expr(body, scope, hint)
+                });
+
+                scope.pop_scope();
+
+                var_res
+            }
+        }
+    }
+
+    fn check_var_bindings<'src>(
+        &self,
+        var_names: &mut [VarBinding<'src>],
+        scope: &mut Scope,
+    ) -> Result<(), TypeError<'src>> {
+        for idx in 0..var_names.len() {
+            let (current, later) = var_names[idx..].split_first_mut().unwrap();
+            let (var_name, annotation, init) = current;
+
+            let var_type = match init {
+                Some(initializer) => {
+                    let init_type = self
+                        .check_expr(initializer, scope, *annotation)
+                        .map_err(|err| match err {
+                            // Naming this variable, or one defined after it in the same var,
+                            // is a use before definition rather than an unknown variable
+                            TypeError::UnknownVariable(name)
+                                if name == *var_name || later.iter().any(|(n, ..)| *n == name) =>
+                            {
+                                TypeError::UsedBeforeDefinition(name)
+                            }
+                            err => err,
+                        })?;
+
+                    match annotation {
+                        Some(annotated) => coerce(init_type, *annotated)?,
+                        None => init_type,
+                    }
+                }
+
+                None => annotation.unwrap_or(Type::F64),
+            };
+
+            scope.define(var_name, var_type);
+        }
+
+        Ok(())
+    }
+
+    // The end condition, step and body of a for loop, with the loop variable in scope
+    fn check_loop_parts<'src>(
+        &self,
+        end: &mut ASTExpr<'src>,
+        step: &mut ASTExpr<'src>,
+        body: &mut ASTExpr<'src>,
+        loop_var_type: Type,
+        scope: &mut Scope,
+    ) -> TypeCheckResult<'src> {
+        self.check_condition(end, scope)?;
+
+        let step_type = self.check_expr(step, scope, Some(loop_var_type))?;
+        coerce(step_type, loop_var_type)?;
+
+        self.check_expr(body, scope, None)
+    }
+
+    // Any number or boolean can be a condition, C like truthiness
+    fn check_condition<'src>(
+        &self,
+        cond: &mut ASTExpr<'src>,
+        scope: &mut Scope,
+    ) -> TypeCheckResult<'src> {
+        match self.check_expr(cond, scope, None)? {
+            found @ (Type::Str | Type::Array | Type::Fn(_)) => Err(TypeError::Mismatch {
+                expected: Type::Bool,
+                found,
+            }),
+            cond_type => Ok(cond_type),
+        }
+    }
+
+    // Indices and lengths of arrays are integers, but doubles are accepted too
+    // (and truncated), otherwise untyped code could never index an array
+    fn check_array_index<'src>(
+        &self,
+        index: &mut ASTExpr<'src>,
+        scope: &mut Scope,
+    ) -> TypeCheckResult<'src> {
+        match self.check_expr(index, scope, Some(Type::I64))? {
+            index_type @ (Type::I64 | Type::F64) => Ok(index_type),
+            found => Err(TypeError::Mismatch {
+                expected: Type::I64,
+                found,
+            }),
+        }
+    }
+
+    // Reading a[i] or assigning to it, the element is always a double
+    fn check_array_access<'src>(
+        &self,
+        array: &mut ASTExpr<'src>,
+        index: &mut ASTExpr<'src>,
+        scope: &mut Scope,
+    ) -> TypeCheckResult<'src> {
+        let array_type = self.check_expr(array, scope, None)?;
+        coerce(array_type, Type::Array)?;
+
+        self.check_array_index(index, scope)?;
+
+        Ok(Type::F64)
+    }
+
+    // Checks two expressions that should end up with the same type, like the operands
+    // of a binary operator or the branches of an if. If a literal on the left only
+    // learns it should be an integer from the right, it gets checked again.
+    fn check_operands<'src>(
+        &self,
+        left: &mut ASTExpr<'src>,
+        right: &mut ASTExpr<'src>,
+        scope: &mut Scope,
+        hint: Option<Type>,
+    ) -> Result<(Type, Type), TypeError<'src>> {
+        let mut left_type = self.check_expr(left, scope, hint)?;
+        let right_type = self.check_expr(right, scope, Some(left_type))?;
+
+        if left_type == Type::F64 && right_type == Type::I64 && is_number_literal(left) {
+            left_type = self.check_expr(left, scope, Some(Type::I64))?;
+        }
+
+        Ok((left_type, right_type))
+    }
+}
+
+// The only implicit conversion in the language is a boolean used where a
+// double is expected, which becomes 1.0 or 0.0
+fn coerce<'src>(found: Type, expected: Type) -> TypeCheckResult<'src> {
+    match (found, expected) {
+        (found, expected) if found == expected => Ok(expected),
+        (Type::Bool, Type::F64) => Ok(Type::F64),
+        (found, expected) => Err(TypeError::Mismatch { expected, found }),
+    }
+}
+
+// Rewrites a boolean expression b into if b then 1.0 else 0.0
+fn promote_to_double(expr: &mut Box<ASTExpr>, expr_type: Type) {
+    if expr_type == Type::Bool {
+        let cond = std::mem::replace(expr, Box::new(ASTExpr::NumberExpr(0.0)));
+
+        **expr = ASTExpr::IfExpr {
+            cond,
+            then_branch: Box::new(ASTExpr::NumberExpr(1.0)),
+            else_branch: Box::new(ASTExpr::NumberExpr(0.0)),
+        };
+    }
+}
+
+fn is_number_literal(expr: &ASTExpr) -> bool {
+    match expr {
+        ASTExpr::NumberExpr(_) => true,
+        ASTExpr::UnaryExpr { operand, .. } => is_number_literal(operand),
+        _ => false,
+    }
+}
+
+// Collects the variables an expression refers to without binding them itself,
+// in order of first use, which is what a lambda has to capture
+fn free_variables<'src>(expr: &ASTExpr<'src>, bound: &mut Vec<&'src str>, free: &mut Vec<&'src str>) {
+    use ASTExpr::*;
+
+    let depth = bound.len();
+
+    match expr {
+        NumberExpr(_) | IntegerExpr(_) | BoolExpr(_) | StringExpr(_) | BreakExpr | ContinueExpr => {}
+
+        VariableExpr(name) => {
+            if !bound.contains(name) && !free.contains(name) {
+                free.push(name);
+            }
+        }
+
+        ArrayExpr(exprs) | BlockExpr(exprs) | CallExpr { args: exprs, .. } => {
+            for expr in exprs {
+                free_variables(expr, bound, free);
+            }
+        }
+
+        LenExpr(operand) | UnaryExpr { operand, .. } => free_variables(operand, bound, free),
+
+        ArrayFillExpr {
+            value: left,
+            count: right,
+        }
+        | IndexExpr {
+            array: left,
+            index: right,
+        }
+        | BinaryExpr { left, right, .. }
+        | WhileExpr {
+            cond: left,
+            body: right,
+        } => {
+            free_variables(left, bound, free);
+            free_variables(right, bound, free);
+        }
+
+        ApplyExpr { callee, args, .. } => {
+            free_variables(callee, bound, free);
+
+            for arg in args {
+                free_variables(arg, bound, free);
+            }
+        }
+
+        IfExpr {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            free_variables(cond, bound, free);
+            free_variables(then_branch, bound, free);
+            free_variables(else_branch, bound, free);
+        }
+
+        ForLoopExpr {
+            varname,
+            start,
+            end,
+            step,
+            body,
+            ..
+        } => {
+            free_variables(start, bound, free);
+            bound.push(varname);
+            free_variables(end, bound, free);
+            free_variables(step, bound, free);
+            free_variables(body, bound, free);
+        }
+
+        VarExpr { var_names, body } => {
+            for (name, _, init) in var_names {
+                if let Some(init) = init {
+                    free_variables(init, bound, free);
+                }
+
+                bound.push(name);
+            }
+
+            free_variables(body, bound, free);
+        }
+
+        LambdaExpr {
+            params,
+            self_name,
+            body,
+            ..
+        } => {
+            bound.extend(params.iter().map(|(name, _)| *name));
+            bound.extend(*self_name);
+            free_variables(body, bound, free);
+        }
+
+        LocalFnExpr { name, lambda, body } => {
+            free_variables(lambda, bound, free);
+            bound.push(name);
+            free_variables(body, bound, free);
+        }
+    }
+
+    bound.truncate(depth);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{lexer::Lex, parser::*};
+
+    fn check_all<'src>(
+        checker: &mut TypeChecker,
+        src: &'src str,
+    ) -> Result<Vec<Box<Function<'src>>>, TypeError<'src>> {
+        let mut tokens = src.lex().peekable();
+        let mut funcs = vec![];
+
+        while tokens.peek().is_some() {
+            let mut func = parse_definition(&mut tokens).unwrap();
+            checker.check_function(&mut func)?;
+            funcs.push(func);
+            let _semicolon = tokens.next();
+        }
+
+        Ok(funcs)
+    }
+
+    #[test]
+    fn untyped_code_defaults_to_doubles() {
+        let mut checker = TypeChecker::new();
+
+        let res = check_all(
+            &mut checker,
+            "def fib(x) if x < 3 then 1 else fib(x-1)+fib(x-2);",
+        );
+
+        assert!(res.is_ok());
+        assert_eq!(
+            checker.signatures.get("fib"),
+            Some(&(vec![Type::F64], Type::F64))
+        );
+    }
+
+    #[test]
+    fn literals_become_integers_in_integer_context() {
+        let mut checker = TypeChecker::new();
+
+        let funcs = check_all(&mut checker, "def inc(x: i64) -> i64 1 + x;").unwrap();
+
+        assert_eq!(
+            funcs[0].body,
+            Box::new(ASTExpr::BinaryExpr {
+                op: Ops::Plus,
+                left: Box::new(ASTExpr::IntegerExpr(1)),
+                right: Box::new(ASTExpr::VariableExpr(&"x")),
+            })
+        );
+    }
+
+    #[test]
+    fn literals_that_are_not_i64s_are_rejected_in_integer_context() {
+        let mut checker = TypeChecker::new();
+
+        assert_eq!(
+            check_all(&mut checker, "def half(x: i64) -> i64 2.5 + x;").err(),
+            Some(TypeError::IncompatibleOperands { op: Ops::Plus, left: Type::F64, right: Type::I64 })
+        );
+        assert_eq!(
+            check_all(&mut checker, "def huge(x: i64) -> i64 x + 1e300;").err(),
+            Some(TypeError::NotAnInteger(1e300))
+        );
+        assert_eq!(
+            check_all(&mut checker, "def edge(x: i64) -> i64 x + 9223372036854775808;").err(),
+            Some(TypeError::NotAnInteger(9223372036854775808.0))
+        );
+        assert_eq!(
+            check_all(&mut checker, "def far(xs: [f64]) xs[1e300];").err(),
+            Some(TypeError::NotAnInteger(1e300))
+        );
+
+        // Without an i64 around them they're still doubles
+        assert!(check_all(&mut checker, "def fine(x) x + 2.5;").is_ok());
+    }
+
+    #[test]
+    fn booleans_promote_to_doubles() {
+        let mut checker = TypeChecker::new();
+
+        assert!(check_all(&mut checker, "def gt(a b) a > b;").is_ok());
+        assert!(check_all(&mut checker, "def both(a: bool, b: bool) -> bool a & b;").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def wrong(a: bool) -> i64 a;").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::I64,
+                found: Type::Bool
+            })
+        );
+    }
+
+    #[test]
+    fn not_works_on_booleans_and_integers() {
+        let mut checker = TypeChecker::new();
+
+        assert!(check_all(&mut checker, "def flip(b: bool) -> bool !b;").is_ok());
+        assert!(check_all(&mut checker, "def mask(n: i64) -> i64 !n & 255;").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def wrong(x) !x;").err(),
+            Some(TypeError::UndefinedOperator(Ops::Negate))
+        );
+    }
+
+    #[test]
+    fn rejects_mixed_integer_and_double_arithmetic() {
+        let mut checker = TypeChecker::new();
+
+        assert_eq!(
+            check_all(&mut checker, "def mixed(a: i64, b: f64) a + b;").err(),
+            Some(TypeError::IncompatibleOperands {
+                op: Ops::Plus,
+                left: Type::I64,
+                right: Type::F64
+            })
+        );
+
+        // A failed definition should not leave its signature behind
+        assert!(!checker.signatures.contains_key("mixed"));
+    }
+
+    #[test]
+    fn strings_concatenate_and_compare() {
+        let mut checker = TypeChecker::new();
+
+        assert!(check_all(&mut checker, "def greet(name: str) -> str \"hi \" + name;").is_ok());
+        assert!(check_all(&mut checker, "def same(a: str, b: str) -> bool a == b;").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def bad(a: str) a * 2;").err(),
+            Some(TypeError::IncompatibleOperands {
+                op: Ops::Mult,
+                left: Type::Str,
+                right: Type::F64
+            })
+        );
+        assert_eq!(
+            check_all(&mut checker, "def cond(a: str) if a then 1 else 0;").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::Bool,
+                found: Type::Str
+            })
+        );
+    }
+
+    #[test]
+    fn loop_variables_are_numbers() {
+        let mut checker = TypeChecker::new();
+
+        assert!(check_all(&mut checker, "def count(n) for i = 0, i < n in 0;").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def words() for s = \"a\", false, \"b\" in 0;").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::F64,
+                found: Type::Str
+            })
+        );
+        assert_eq!(
+            check_all(&mut checker, "def rows() for xs = [0; 2], false in 0;").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::F64,
+                found: Type::Array
+            })
+        );
+    }
+
+    #[test]
+    fn arrays_hold_doubles() {
+        let mut checker = TypeChecker::new();
+
+        let funcs = check_all(
+            &mut checker,
+            "def first(xs: [f64]) xs[0] = xs[len(xs) - 1];",
+        )
+        .unwrap();
+
+        // Indices are integers where possible, element values stay doubles
+        let ASTExpr::BinaryExpr { ref left, .. } = *funcs[0].body else {
+            panic!("Expected an assignment")
+        };
+
+        assert_eq!(
+            **left,
+            ASTExpr::IndexExpr {
+                array: Box::new(ASTExpr::VariableExpr(&"xs")),
+                index: Box::new(ASTExpr::IntegerExpr(0)),
+            }
+        );
+
+        assert!(check_all(&mut checker, "def make() -> [f64] [1, 2, true];").is_ok());
+        assert!(check_all(&mut checker, "def zeros(n: i64) -> [f64] [0; n];").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def bad(x) x[0];").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::Array,
+                found: Type::F64
+            })
+        );
+        assert_eq!(
+            check_all(&mut checker, "def bad2(xs: [f64]) xs[\"0\"];").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::I64,
+                found: Type::Str
+            })
+        );
+    }
+
+    #[test]
+    fn checks_calls_against_signatures() {
+        let mut checker = TypeChecker::new();
+
+        check_all(&mut checker, "def id(x: i64) -> i64 x;").unwrap();
+
+        assert!(check_all(&mut checker, "def caller() -> i64 id(2);").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def caller2() id(2.5);").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::I64,
+                found: Type::F64
+            })
+        );
+        assert_eq!(
+            check_all(&mut checker, "def caller3() nope(1);").err(),
+            Some(TypeError::UndefinedFunction(&"nope"))
+        );
+    }
+
+    #[test]
+    fn while_loops_evaluate_to_doubles() {
+        let mut checker = TypeChecker::new();
+
+        assert!(check_all(
+            &mut checker,
+            "def count(n: i64) var i: i64 = 0 in while i < n do if i == 5 then break else i = i + 1;"
+        )
+        .is_ok());
+        assert_eq!(checker.signatures.get("count"), Some(&(vec![Type::I64], Type::F64)));
+
+        assert_eq!(
+            check_all(&mut checker, "def spin(s: str) while s do continue;").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::Bool,
+                found: Type::Str
+            })
+        );
+    }
+
+    #[test]
+    fn blocks_take_the_type_of_their_last_expression() {
+        let mut checker = TypeChecker::new();
+
+        assert!(check_all(&mut checker, "def seq(s: str) -> i64 { s + s; true; 4 };").is_ok());
+        assert_eq!(
+            check_all(&mut checker, "def last(n: i64) -> i64 { n; 1.5 };").err(),
+            Some(TypeError::Mismatch {
+                expected: Type::I64,
+                found: Type::F64
+            })
+        );
+    }
+
+    #[test]
+    fn globals_are_visible_from_functions() {
+        let mut checker = TypeChecker::new();
+
+        let mut tokens = "global count: i64; global ratio = 0.5;".lex().peekable();
+        let mut count = parse_global(&mut tokens).unwrap();
+        let _semicolon = tokens.next();
+        let mut ratio = parse_global(&mut tokens).unwrap();
+
+        assert_eq!(checker.check_global(&mut count), Ok(Type::I64));
+        assert_eq!(checker.check_global(&mut ratio), Ok(Type::F64));
+        assert_eq!(ratio.var_type, Some(Type::F64));
+
+        assert!(check_all(&mut checker, "def tick() -> i64 count = count + 1;").is_ok());
+
+        // Parameters shadow globals
+        assert!(check_all(&mut checker, "def shadow(count: str) -> str count;").is_ok());
+
+        assert_eq!(checker.check_global(&mut count), Err(TypeError::GlobalRedefined(&"count")));
+
+        tokens = "global low: i64 = -3;".lex().peekable();
+        let mut low = parse_global(&mut tokens).unwrap();
+        assert_eq!(checker.check_global(&mut low), Ok(Type::I64));
+        assert_eq!(low.init, Some(Box::new(ASTExpr::IntegerExpr(-3))));
+
+        tokens = "global bad = count;".lex().peekable();
+        assert_eq!(
+            checker.check_global(&mut parse_global(&mut tokens).unwrap()),
+            Err(TypeError::NonConstantGlobal(&"bad"))
+        );
+    }
+
+    #[test]
+    fn lambdas_capture_and_infer_from_context() {
+        let mut checker = TypeChecker::new();
+
+        let funcs = check_all(
+            &mut checker,
+            r"def apply(f: fn(f64) -> f64, x) f(x);
+              def scale(k) var two = 2 in apply(\x -> x * k * two, 3);
+              def make_adder(n: i64) -> fn(i64) -> i64 \x -> x + n;
+              def double(x) x * 2;
+              def doubled(x) apply(double, x);",
+        )
+        .unwrap();
+
+        // Calling a parameter becomes an indirect call through the function value
+        assert!(matches!(
+            *funcs[0].body,
+            ASTExpr::ApplyExpr { ref callee_type, .. }
+                if *callee_type == Some(Type::function(vec![Type::F64], Type::F64))
+        ));
+
+        let ASTExpr::VarExpr { ref body, .. } = *funcs[1].body else {
+            panic!("expected a var expression");
+        };
+        let ASTExpr::CallExpr { ref args, .. } = **body else {
+            panic!("expected a call to apply");
+        };
+        assert!(matches!(
+            *args[0],
+            ASTExpr::LambdaExpr { ref captures, ret_type: Some(Type::F64), .. } if *captures == vec!["k", "two"]
+        ));
+
+        // Parameters are typed by the return type the lambda is checked against
+        assert!(matches!(
+            *funcs[2].body,
+            ASTExpr::LambdaExpr { ref params, ret_type: Some(Type::I64), .. } if params[0].1 == Some(Type::I64)
+        ));
+
+        assert_eq!(
+            check_all(&mut checker, "def bad(x) x(1);").err(),
+            Some(TypeError::NotAFunction(Type::F64))
+        );
+    }
+
+    #[test]
+    fn local_functions_recurse_and_capture() {
+        let mut checker = TypeChecker::new();
+
+        let funcs = check_all(
+            &mut checker,
+            "def sum_to(n: i64, k: i64) -> i64
+               def go(i: i64) -> i64 if i > n then 0 else i * k + go(i + 1) in go(1);",
+        )
+        .unwrap();
+
+        let ASTExpr::LocalFnExpr { ref lambda, .. } = *funcs[0].body else {
+            panic!("expected a local function");
+        };
+        assert!(matches!(
+            **lambda,
+            ASTExpr::LambdaExpr { ref captures, ret_type: Some(Type::I64), .. } if *captures == vec!["n", "k"]
+        ));
+
+        // The function is only in scope for the expression after in
+        assert_eq!(
+            check_all(&mut checker, "def leak(x) (def f(y) y in f(x)) + f(x);").err(),
+            Some(TypeError::UndefinedFunction(&"f"))
+        );
+    }
+
+    #[test]
+    fn var_initializers_cannot_see_later_variables() {
+        let mut checker = TypeChecker::new();
+
+        assert_eq!(
+            check_all(&mut checker, "def early(x) var a = b, b = 1 in a;").err(),
+            Some(TypeError::UsedBeforeDefinition(&"b"))
+        );
+        assert_eq!(
+            check_all(&mut checker, "def itself(x) var a = a in a;").err(),
+            Some(TypeError::UsedBeforeDefinition(&"a"))
+        );
+
+        // Earlier variables and outer variables of the same name are fine
+        assert!(check_all(&mut checker, "def later(x) var a = 1, b = a + x in b;").is_ok());
+        assert!(check_all(&mut checker, "def outer(a) var a = a * 2 in a;").is_ok());
+    }
+}
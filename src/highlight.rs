@@ -0,0 +1,29 @@
+use std::fs::read_to_string;
+use std::io::{read_to_string as read_all, stdin};
+use std::path::Path;
+use std::process::exit;
+
+use crate::{
+    cli::HighlightFormat,
+    frontend::highlight::{classify, to_html, to_json},
+};
+
+// Driver for `kaleidrs highlight`, prints the classified tokens of file, or stdin
+// when there's no file, as JSON or HTML. Source that doesn't parse is highlighted
+// all the same, names in the broken parts just aren't known to be anything.
+pub fn driver(format: HighlightFormat, file: Option<&Path>) {
+    let src = match file {
+        Some(file) => read_to_string(file).unwrap_or_else(|_| {
+            eprintln!("{}: File not found, please make sure it exists!", file.display());
+            exit(1);
+        }),
+        None => read_all(stdin()).expect("Failed to read from stdin"),
+    };
+
+    let highlights = classify(&src);
+
+    match format {
+        HighlightFormat::Json => println!("{:#}", to_json(&src, &highlights)),
+        HighlightFormat::Html => print!("{}", to_html(&src, &highlights)),
+    }
+}
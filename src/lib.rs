@@ -0,0 +1,12 @@
+// The compiler as a library, for running Kaleidoscope from other Rust programs, see
+// Session in session.rs. The command line (main.rs) is built on the same modules.
+#[macro_use]
+extern crate lazy_static;
+
+pub mod backend;
+pub mod frontend;
+pub mod runtime;
+pub mod session;
+
+pub use backend::llvm_backend::CodegenOptions;
+pub use session::{Session, SessionError};
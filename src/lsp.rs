@@ -0,0 +1,600 @@
+use std::collections::HashMap;
+use std::io::{self, stdin, stdout, BufRead, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use inkwell::context::Context;
+use inkwell::targets::{InitializationConfig, Target};
+use serde_json::{json, Value};
+
+use crate::backend::llvm_backend::{CodegenOptions, LLVMCodeGen, LLVMContext};
+use crate::frontend::{
+    analysis::{analyze_with, Analysis, SymbolKind},
+    ast::{Item, Span},
+    highlight::{classify_analyzed, Class},
+    simplify::simplify,
+};
+
+// Driver for `kaleidrs lsp`, a language server speaking the Language Server Protocol
+// over stdin and stdout, for editors to show errors as you type, jump to where things
+// are defined and used, and complete names. Every message is a JSON-RPC object,
+// preceded by a Content-Length header, see
+// https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/
+//
+// Documents are run through the frontend (see frontend::analysis), then code is
+// generated for them, so errors LLVM finds are reported too, but nothing is ever run.
+// Returns whether the client asked the server to shut down before exiting, which is
+// what the exit code should say.
+pub fn driver() -> bool {
+    match serve(stdin().lock(), stdout().lock()) {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            eprintln!("Language server failed to talk to the client: {}", e);
+            false
+        }
+    }
+}
+
+// Error codes from JSON-RPC, and the protocol itself
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+// Kinds of completion items
+const FUNCTION_ITEM: u64 = 3;
+const OPERATOR_ITEM: u64 = 24;
+
+// Semantic tokens are sent as indices into these, the types are ones editors already
+// have colors for, modifiers are bit flags telling apart the ones we have more of
+const TOKEN_TYPES: [&str; 9] = [
+    "keyword",
+    "number",
+    "string",
+    "function",
+    "parameter",
+    "variable",
+    "type",
+    "operator",
+    "comment",
+];
+const TOKEN_MODIFIERS: [&str; 2] = ["global", "userDefined"];
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+struct Server<W: Write> {
+    output: W,
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<bool> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+
+    while let Some(body) = read_message(&mut input)? {
+        let message: Value = match serde_json::from_str(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                server.respond(&Value::Null, Err((PARSE_ERROR, e.to_string())))?;
+                continue;
+            }
+        };
+
+        // Responses to requests we never sent, nothing to do with them
+        let Some(method) = message["method"].as_str() else {
+            continue;
+        };
+
+        let params = &message["params"];
+
+        match message.get("id") {
+            Some(id) if server.shutdown => {
+                server.respond(id, Err((INVALID_REQUEST, "Server is shutting down".to_string())))?;
+            }
+
+            // A bug in the server fails the request, rather than the server
+            Some(id) => {
+                let result = catch_unwind(AssertUnwindSafe(|| server.request(method, params)))
+                    .unwrap_or_else(|_| Err((INTERNAL_ERROR, format!("Server failed handling {method}"))));
+                server.respond(id, result)?;
+            }
+
+            None if method == "exit" => break,
+
+            None => server.notify(method, params)?,
+        }
+    }
+
+    Ok(server.shutdown)
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        match line.trim_end().split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                length = value.trim().parse::<usize>().ok();
+            }
+
+            // Headers end at an empty line
+            None if line.trim_end().is_empty() && length.is_some() => break,
+
+            _ => {}
+        }
+    }
+
+    let mut body = vec![0; length.unwrap()];
+    input.read_exact(&mut body)?;
+
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+impl<W: Write> Server<W> {
+    fn respond(&mut self, id: &Value, result: Result<Value, (i64, String)>) -> io::Result<()> {
+        let message = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+
+        write_message(&mut self.output, &message)
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1, // The whole text is sent on every change
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": TOKEN_TYPES, "tokenModifiers": TOKEN_MODIFIERS },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "kaleidrs", "version": env!("CARGO_PKG_VERSION") },
+            })),
+
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+
+            "textDocument/definition" => Ok(self.definition(params).unwrap_or(Value::Null)),
+            "textDocument/references" => Ok(self.references(params).unwrap_or(json!([]))),
+            "textDocument/hover" => Ok(self.hover(params).unwrap_or(Value::Null)),
+            "textDocument/completion" => Ok(self.completion(params).unwrap_or(json!([]))),
+            "textDocument/semanticTokens/full" => Ok(self.semantic_tokens(params).unwrap_or(Value::Null)),
+
+            _ => Err((METHOD_NOT_FOUND, format!("Unknown method {method}"))),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let Some(uri) = params["textDocument"]["uri"].as_str() else {
+            return Ok(());
+        };
+
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+
+            // Only ever whole documents, that's the sync asked for on initialize
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.publish_diagnostics(uri);
+            }
+
+            _ => None,
+        };
+
+        let Some(text) = text else {
+            return Ok(());
+        };
+
+        // A bug in the compiler shouldn't take the editor's server down with it
+        let analysis = catch_unwind(AssertUnwindSafe(|| analyze_document(text))).unwrap_or_default();
+
+        self.documents.insert(
+            uri.to_string(),
+            Document {
+                text: text.to_string(),
+                analysis,
+            },
+        );
+
+        self.publish_diagnostics(uri)
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(doc) => doc
+                .analysis
+                .diagnostics
+                .iter()
+                .map(|diag| {
+                    json!({
+                        "range": range(&doc.text, diag.span),
+                        "severity": 1, // Error
+                        "source": "kaleidrs",
+                        "message": diag.message,
+                    })
+                })
+                .collect(),
+            None => vec![],
+        };
+
+        let message = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+
+        write_message(&mut self.output, &message)
+    }
+
+    fn document<'a>(&'a self, params: &'a Value) -> Option<(&'a str, &'a Document)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        Some((uri, self.documents.get(uri)?))
+    }
+
+    // The symbol under the cursor, along with the document it's in
+    fn symbol_at<'a>(&'a self, params: &'a Value) -> Option<(&'a str, &'a Document, usize)> {
+        let (uri, doc) = self.document(params)?;
+        let offset = offset_of(&doc.text, &params["position"])?;
+
+        Some((uri, doc, doc.analysis.symbol_at(offset)?))
+    }
+
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (uri, doc, symbol) = self.symbol_at(params)?;
+        let span = doc.analysis.symbols[symbol].span?;
+
+        Some(json!({ "uri": uri, "range": range(&doc.text, span) }))
+    }
+
+    fn references(&self, params: &Value) -> Option<Value> {
+        let (uri, doc, symbol) = self.symbol_at(params)?;
+
+        let mut spans = doc.analysis.references_to(symbol).collect::<Vec<_>>();
+
+        if params["context"]["includeDeclaration"].as_bool().unwrap_or(false) {
+            spans.extend(doc.analysis.symbols[symbol].span);
+        }
+
+        spans.sort_by_key(|span| span.start);
+
+        let locations = spans
+            .into_iter()
+            .map(|span| json!({ "uri": uri, "range": range(&doc.text, span) }))
+            .collect();
+
+        Some(Value::Array(locations))
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let (_, doc, symbol) = self.symbol_at(params)?;
+        let symbol = &doc.analysis.symbols[symbol];
+
+        let mut contents = format!("```kaleidoscope\n{}\n```", symbol.detail);
+
+        match symbol.arity {
+            Some(1) => contents.push_str("\n\nTakes 1 argument"),
+            Some(arity) => contents.push_str(&format!("\n\nTakes {arity} arguments")),
+            None => {}
+        }
+
+        Some(json!({ "contents": { "kind": "markdown", "value": contents } }))
+    }
+
+    fn completion(&self, params: &Value) -> Option<Value> {
+        let (_, doc) = self.document(params)?;
+        let mut items: Vec<Value> = vec![];
+
+        for symbol in &doc.analysis.symbols {
+            let kind = match symbol.kind {
+                SymbolKind::Function | SymbolKind::Extern => FUNCTION_ITEM,
+                SymbolKind::Operator => OPERATOR_ITEM,
+                _ => continue,
+            };
+
+            // Operators can be defined more than once, unary and binary
+            if items.iter().any(|item| item["label"] == symbol.name.as_str()) {
+                continue;
+            }
+
+            items.push(json!({ "label": symbol.name, "kind": kind, "detail": symbol.detail }));
+        }
+
+        Some(Value::Array(items))
+    }
+
+    // Every token as five numbers: its line and start relative to the token before it
+    // (the start only when on the same line), its length, type and modifiers
+    fn semantic_tokens(&self, params: &Value) -> Option<Value> {
+        let (_, doc) = self.document(params)?;
+        let text = doc.text.as_str();
+
+        let mut data = vec![];
+        let (mut line, mut line_start) = (0, 0);
+        let (mut last_line, mut last_character) = (0, 0);
+
+        for highlight in classify_analyzed(text, &doc.analysis) {
+            let Span { start, end } = highlight.span;
+
+            let Some((token_type, modifiers)) = token_type(highlight.class) else {
+                continue;
+            };
+
+            // Tokens can't span lines, only a string literal could
+            if text[start..end].contains('\n') {
+                continue;
+            }
+
+            let skipped = &text[line_start..start];
+
+            if let Some(last_newline) = skipped.rfind('\n') {
+                line += skipped.matches('\n').count();
+                line_start += last_newline + 1;
+            }
+
+            let character = text[line_start..start].encode_utf16().count();
+            let relative = if line == last_line { character - last_character } else { character };
+
+            data.extend([
+                line - last_line,
+                relative,
+                text[start..end].encode_utf16().count(),
+                token_type,
+                modifiers,
+            ]);
+
+            (last_line, last_character) = (line, character);
+        }
+
+        Some(json!({ "data": data }))
+    }
+}
+
+// Code is generated in a context of its own for every document, into a module that's
+// thrown away after. Top level expressions are deleted after each one, like the REPL.
+fn analyze_document(text: &str) -> Analysis {
+    Target::initialize_native(&InitializationConfig::default())
+        .expect("Failed to initialize native machine target!");
+
+    let context = Context::create();
+    let llvm_ctx = LLVMContext::new(&context, &CodegenOptions::default());
+
+    analyze_with(text, |item| {
+        let generated = match item {
+            Item::Extern(proto) => proto.codegen(&llvm_ctx).map(drop),
+            Item::Global(global) => global.codegen(&llvm_ctx).map(drop),
+
+            Item::Function(func) => {
+                simplify(func);
+                let generated = func.codegen(&llvm_ctx).map(drop);
+                llvm_ctx.delete_top_level_expr();
+                generated
+            }
+        };
+
+        generated.err().map(|e| e.to_string())
+    })
+}
+
+// The index of the token type in TOKEN_TYPES, and the modifiers, for a class of token.
+// Editors color punctuation well enough on their own.
+fn token_type(class: Class) -> Option<(usize, usize)> {
+    Some(match class {
+        Class::Keyword => (0, 0),
+        Class::Number => (1, 0),
+        Class::String => (2, 0),
+        Class::Function => (3, 0),
+        Class::Parameter => (4, 0),
+        Class::Variable => (5, 0),
+        Class::Global => (5, 1),
+        Class::Type => (6, 0),
+        Class::Operator => (7, 0),
+        Class::UserOperator => (7, 2),
+        Class::Comment => (8, 0),
+        Class::Punctuation => return None,
+    })
+}
+
+// Positions are lines and columns counting from 0, columns in UTF-16 code units
+fn position_of(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+fn offset_of(text: &str, position: &Value) -> Option<usize> {
+    let line = position["line"].as_u64()? as usize;
+    let character = position["character"].as_u64()? as usize;
+
+    let line_start = match line {
+        0 => 0,
+        _ => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+
+    let mut units = 0;
+
+    for (idx, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + idx);
+        }
+
+        units += c.len_utf16();
+    }
+
+    Some(text.len())
+}
+
+fn range(text: &str, span: Span) -> Value {
+    json!({ "start": position_of(text, span.start), "end": position_of(text, span.end) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+    use std::iter;
+
+    fn frame(message: Value) -> String {
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn request(id: u64, method: &str, params: Value) -> String {
+        frame(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+    }
+
+    fn notification(method: &str, params: Value) -> String {
+        frame(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    fn at(line: u64, character: u64) -> Value {
+        json!({ "textDocument": { "uri": "file:///twice.k" }, "position": { "line": line, "character": character } })
+    }
+
+    #[test]
+    fn scripted_session() {
+        let text = "def add(a b) a + b;\ndef twice(x) var y = x in add(y, y);\ntwice(undefined);\n";
+
+        let mut references = at(1, 17);
+        references["context"] = json!({ "includeDeclaration": true });
+
+        let script = [
+            request(1, "initialize", json!({ "capabilities": {} })),
+            notification("initialized", json!({})),
+            notification(
+                "textDocument/didOpen",
+                json!({ "textDocument": { "uri": "file:///twice.k", "languageId": "kaleidoscope", "version": 1, "text": text } }),
+            ),
+            request(2, "textDocument/definition", at(1, 26)),
+            request(3, "textDocument/references", references),
+            request(4, "textDocument/hover", at(1, 27)),
+            request(5, "textDocument/completion", at(2, 0)),
+            request(6, "textDocument/rename", at(1, 26)),
+            request(8, "textDocument/semanticTokens/full", at(0, 0)),
+            request(7, "shutdown", Value::Null),
+            notification("exit", Value::Null),
+        ]
+        .concat();
+
+        let mut output = vec![];
+        let shutdown = serve(Cursor::new(script), &mut output).unwrap();
+        assert!(shutdown);
+
+        let mut reader = BufReader::new(output.as_slice());
+        let messages = iter::from_fn(|| read_message(&mut reader).unwrap())
+            .map(|body| serde_json::from_str::<Value>(&body).unwrap())
+            .collect::<Vec<_>>();
+
+        let response = |id: u64| messages.iter().find(|message| message["id"] == id).unwrap();
+        let span = |line: u64, start: u64, end: u64| {
+            json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+        };
+
+        assert_eq!(response(1)["result"]["capabilities"]["definitionProvider"], true);
+
+        let published = messages
+            .iter()
+            .find(|message| message["method"] == "textDocument/publishDiagnostics")
+            .unwrap();
+        let diagnostics = published["params"]["diagnostics"].as_array().unwrap();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"], span(2, 6, 15));
+        assert_eq!(diagnostics[0]["message"], "Unknown variable name undefined");
+
+        assert_eq!(
+            response(2)["result"],
+            json!({ "uri": "file:///twice.k", "range": span(0, 4, 7) })
+        );
+
+        let references = response(3)["result"].as_array().unwrap();
+        let ranges = references.iter().map(|location| location["range"].clone()).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![span(1, 17, 18), span(1, 30, 31), span(1, 33, 34)]);
+
+        let hover = response(4)["result"]["contents"]["value"].as_str().unwrap();
+        assert!(hover.contains("def add(") && hover.ends_with("Takes 2 arguments"), "{hover}");
+
+        let labels = response(5)["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, vec!["add", "twice"]);
+
+        assert_eq!(response(6)["error"]["code"], METHOD_NOT_FOUND);
+
+        // def add(a b) on the first line, then def twice on the next
+        let tokens = response(8)["result"]["data"].as_array().unwrap();
+        assert_eq!(tokens[..20], [0, 0, 3, 0, 0, 0, 4, 3, 3, 0, 0, 4, 1, 4, 0, 0, 2, 1, 4, 0]);
+        assert_eq!(tokens[35..45], [1, 0, 3, 0, 0, 0, 4, 5, 3, 0]);
+        assert_eq!(response(7)["result"], Value::Null);
+    }
+
+    #[test]
+    fn backend_errors_are_published() {
+        let text = "def f(x) x;\ndef f(x) x + 1;\n1;\n2;\n";
+
+        let script = [
+            notification(
+                "textDocument/didOpen",
+                json!({ "textDocument": { "uri": "file:///twice.k", "languageId": "kaleidoscope", "version": 1, "text": text } }),
+            ),
+            notification("exit", Value::Null),
+        ]
+        .concat();
+
+        let mut output = vec![];
+        serve(Cursor::new(script), &mut output).unwrap();
+
+        let body = read_message(&mut BufReader::new(output.as_slice())).unwrap().unwrap();
+        let published = serde_json::from_str::<Value>(&body).unwrap();
+        let diagnostics = published["params"]["diagnostics"].as_array().unwrap();
+
+        // The second top level expression is fine, the first is gone by then
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        assert_eq!(diagnostics[0]["range"]["start"], json!({ "line": 1, "character": 0 }));
+        assert_eq!(diagnostics[0]["message"], "Function f defined twice");
+    }
+}
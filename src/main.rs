@@ -1,31 +1,39 @@
 use std::fs::read_to_string;
 use std::process::exit;
 
-#[macro_use]
-extern crate lazy_static;
-
-mod backend;
 mod cli;
 mod compile;
-mod frontend;
+mod fmt;
+mod highlight;
+mod lsp;
 mod repl;
 
 use clap::Parser;
 use inkwell::targets;
-
-extern "C" {
-    fn putchard(ascii_code: f64) -> f64;
-    fn printd(float_value: f64) -> f64;
-}
+use kaleidrs::{backend, frontend, runtime};
 
 fn main() {
-    let _externs: &[*const extern "C" fn(f64) -> f64] = &[
-        putchard as _,
-        printd as _,
-    ];
+    runtime::link();
 
     let cli = cli::Cli::parse();
 
+    // Formatting and highlighting only need the frontend, the language server sets LLVM up itself
+    match cli.command {
+        Some(cli::Command::Fmt { check, ref files }) => {
+            fmt::driver(check, files);
+            exit(0);
+        }
+
+        Some(cli::Command::Lsp) => exit(if lsp::driver() { 0 } else { 1 }),
+
+        Some(cli::Command::Highlight { format, ref file }) => {
+            highlight::driver(format, file.as_deref());
+            exit(0);
+        }
+
+        None => {}
+    }
+
     let target_config = targets::InitializationConfig::default();
 
     targets::Target::initialize_native(&target_config)
@@ -33,6 +41,17 @@ fn main() {
 
     targets::Target::initialize_all(&target_config);
 
+    // A pass LLVM doesn't know is better found now than after compiling a whole file
+    if let Err(e) = backend::llvm_backend::check_passes(cli.passes(), &cli.codegen_options()) {
+        eprintln!("{e}");
+        exit(1);
+    }
+
+    if cli.print_passes_p {
+        println!("{}", cli.passes());
+        exit(0);
+    }
+
     // If a positional argument of file was passed, then the program runs in compile mode,
     // taking that file and compiling it to an object/assembly file
     if let Some(ref file_path) = cli.file {
@@ -1,10 +1,18 @@
+use std::fs::write;
 use std::io::Write;
+use std::path::Path;
 
 use crate::{
     cli::Cli,
     frontend::{
+        ast::Item,
+        format::format_items,
+        intern::OwnedItem,
         lexer::{Lex, Token},
-        parser::{parse_definition, parse_extern, parse_top_level_expr},
+        parser::{parse_definition, parse_extern, parse_global, parse_top_level_expr},
+        resolve::{report_semantic_errors, Resolver},
+        simplify::simplify,
+        typecheck::TypeChecker,
     },
     backend::llvm_backend::{LLVMCodeGen, LLVMContext}
 };
@@ -48,6 +56,17 @@ pub fn ast_parser_driver() {
                 }
             },
 
+            Some(Token::Global) => match parse_global(&mut tokens) {
+                Ok(ast) => {
+                    println!("Parsed a global.");
+                    dbg!(ast);
+                }
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    _ = tokens.next();
+                }
+            },
+
             Some(Token::Semicolon) => {
                 _ = tokens.next();
             }
@@ -72,33 +91,72 @@ pub fn ast_parser_driver() {
 pub fn driver(cli_args: &Cli) {
     let context = inkwell::context::Context::create();
 
-    let sesh_ctx = LLVMContext::new(&context, &cli_args);
+    let sesh_ctx = LLVMContext::new(&context, &cli_args.codegen_options());
+
+    for library in &cli_args.link_libs {
+        if let Err(e) = sesh_ctx.link_library(library) {
+            eprintln!("Backend error: {}", e);
+        }
+    }
+
+    let mut resolver = Resolver::new();
+    let mut type_checker = TypeChecker::new();
     let mut input_buf = String::new();
 
+    // Every definition, extern and global accepted so far, as they were written.
+    // Lines are read into the same buffer, so these are interned to outlive it.
+    let mut definitions: Vec<OwnedItem> = vec![];
+
     loop {
         print!("Ready >> ");
         std::io::stdout().flush().unwrap();
         let _ = std::io::stdin().read_line(&mut input_buf);
 
+        if let Some(command) = input_buf.trim().strip_prefix(':') {
+            run_command(command, &definitions, &sesh_ctx);
+            input_buf.clear();
+            continue;
+        }
+
         let mut tokens = input_buf.lex().peekable();
 
         match tokens.peek() {
             None => continue,
 
             Some(Token::FuncDef) => match parse_definition(&mut tokens) {
-                Ok(ast) => {
-                    match ast.codegen(&sesh_ctx) {
-                        Ok(_ir) => {
-                            sesh_ctx.run_passes(&cli_args.passes);
+                Ok(mut ast) => {
+                    let written = ast.interned();
 
-                            cli_args.inspect_tree_p
-                                .then(|| println!("Abstract Syntax Tree Representation:\n{:#?}\n", &ast));
-                            cli_args.inspect_ir_p
-                                .then(|| sesh_ctx.dump_module());
-                            cli_args.inspect_asm_p
-                                .then(|| sesh_ctx.dump_assembly());
+                    // The frontend forgets a definition it rejects, one the backend rejects
+                    // has to be taken back out of it
+                    let checkpoint = (resolver.clone(), type_checker.clone());
+
+                    if let Err(errors) = resolver.resolve_function(&ast) {
+                        report_semantic_errors(&input_buf, &errors);
+                    } else if let Err(e) = type_checker.check_function(&mut ast) {
+                        eprintln!("Type error: {}", e);
+                    } else {
+                        simplify(&mut ast);
+
+                        match ast.codegen(&sesh_ctx) {
+                            Ok(_ir) => {
+                                definitions.push(Item::Function(Box::new(written)));
+                                if let Err(e) = sesh_ctx.run_passes(cli_args.passes()) {
+                                    eprintln!("Backend error: {}", e);
+                                }
+
+                                cli_args.inspect_tree_p
+                                    .then(|| println!("Abstract Syntax Tree Representation:\n{:#?}\n", &ast));
+                                cli_args.inspect_ir_p
+                                    .then(|| sesh_ctx.dump_module());
+                                cli_args.inspect_asm_p
+                                    .then(|| sesh_ctx.dump_assembly());
+                            }
+                            Err(e) => {
+                                eprintln!("Backend error: {}", e);
+                                (resolver, type_checker) = checkpoint;
+                            }
                         }
-                        Err(e) => eprintln!("Backend error: {}", e),
                     }
                 }
                 Err(err) => {
@@ -109,8 +167,13 @@ pub fn driver(cli_args: &Cli) {
 
             Some(Token::Extern) => match parse_extern(&mut tokens) {
                 Ok(ast) => {
+                    // Only declared once the backend accepts it, a conflicting extern is forgotten
                     match ast.codegen(&sesh_ctx) {
                         Ok(_ir) => {
+                            resolver.declare(&ast);
+                            type_checker.check_prototype(&ast);
+                            definitions.push(Item::Extern(Box::new(ast.interned())));
+
                             cli_args.inspect_tree_p
                                 .then(|| println!("Abstract Syntax Tree Representation:\n{:#?}\n", &ast));
                             cli_args.inspect_ir_p
@@ -127,32 +190,72 @@ pub fn driver(cli_args: &Cli) {
                 }
             },
 
+            Some(Token::Global) => match parse_global(&mut tokens) {
+                Ok(mut ast) => {
+                    let written = ast.interned();
+                    let checkpoint = (resolver.clone(), type_checker.clone());
+
+                    if let Err(errors) = resolver.resolve_global(&ast) {
+                        report_semantic_errors(&input_buf, &errors);
+                    } else if let Err(e) = type_checker.check_global(&mut ast) {
+                        eprintln!("Type error: {}", e);
+                    } else {
+                        match ast.codegen(&sesh_ctx) {
+                            Ok(_ir) => {
+                                definitions.push(Item::Global(Box::new(written)));
+
+                                cli_args.inspect_tree_p
+                                    .then(|| println!("Abstract Syntax Tree Representation:\n{:#?}\n", &ast));
+                                cli_args.inspect_ir_p
+                                    .then(|| sesh_ctx.dump_module());
+                            }
+                            Err(e) => {
+                                eprintln!("Backend error: {}", e);
+                                (resolver, type_checker) = checkpoint;
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Frontend Error: {}", err);
+                    _ = tokens.next();
+                }
+            },
+
             Some(Token::Semicolon) => {
                 _ = tokens.next();
             }
 
             Some(_top_level_token) => match parse_top_level_expr(&mut tokens) {
-                Ok(ast) => {
-                    match ast.codegen(&sesh_ctx) {
-                        Ok(_ir) => {
-                            sesh_ctx.run_passes(&cli_args.passes);
+                Ok(mut ast) => {
+                    if let Err(errors) = resolver.resolve_function(&ast) {
+                        report_semantic_errors(&input_buf, &errors);
+                    } else if let Err(e) = type_checker.check_function(&mut ast) {
+                        eprintln!("Type error: {}", e);
+                    } else {
+                        simplify(&mut ast);
 
-                            cli_args.inspect_tree_p
-                                .then(|| println!("Abstract Syntax Tree Representation:\n{:#?}\n", &ast));
-                            cli_args.inspect_ir_p
-                                .then(|| sesh_ctx.dump_module());
-                            cli_args.inspect_asm_p
-                                .then(|| sesh_ctx.dump_assembly());
+                        match ast.codegen(&sesh_ctx) {
+                            Ok(_ir) => {
+                                if let Err(e) = sesh_ctx.run_passes(cli_args.passes()) {
+                                    eprintln!("Backend error: {}", e);
+                                }
 
-                            unsafe {
-                                let res = sesh_ctx
-                                    .jit_eval()
-                                    .expect("Failed to JIT top level expression into function!");
+                                cli_args.inspect_tree_p
+                                    .then(|| println!("Abstract Syntax Tree Representation:\n{:#?}\n", &ast));
+                                cli_args.inspect_ir_p
+                                    .then(|| sesh_ctx.dump_module());
+                                cli_args.inspect_asm_p
+                                    .then(|| sesh_ctx.dump_assembly());
 
-                                println!("Jit compiled and evaluated to: {res}");
+                                // Code stopped for running too long leaves the session as it was
+                                match unsafe { sesh_ctx.jit_eval() } {
+                                    Ok(res) => println!("Jit compiled and evaluated to: {res}"),
+                                    Err(e) => eprintln!("Execution error: {}", e),
+                                }
                             }
+                            Err(e) => eprintln!("Backend error: {}", e),
                         }
-                        Err(e) => eprintln!("Backend error: {}", e),
                     }
 
                     sesh_ctx.delete_top_level_expr();
@@ -168,3 +271,28 @@ pub fn driver(cli_args: &Cli) {
         input_buf.clear();
     }
 }
+
+// Lines starting with a colon are commands to the REPL itself, rather than code
+//   :save <file>     writes every definition, extern and global so far to file, formatted
+//   :dlopen <file>   loads a shared library, for externs to call into, like --link-lib
+fn run_command(command: &str, definitions: &[OwnedItem], sesh_ctx: &LLVMContext) {
+    let (name, arg) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+
+    match (name, arg.trim()) {
+        ("save", "") => eprintln!("Usage: :save <file>"),
+
+        ("save", file) => match write(file, format_items(definitions)) {
+            Ok(()) => println!("Saved {} definition(s) to {file}", definitions.len()),
+            Err(e) => eprintln!("Failed to save to {file}: {e}"),
+        },
+
+        ("dlopen", "") => eprintln!("Usage: :dlopen <file>"),
+
+        ("dlopen", file) => match sesh_ctx.link_library(Path::new(file)) {
+            Ok(()) => println!("Loaded {file}"),
+            Err(e) => eprintln!("Backend error: {}", e),
+        },
+
+        _ => eprintln!("Unknown command :{name}"),
+    }
+}
@@ -0,0 +1,236 @@
+// The runtime library Kaleidoscope programs call into, src/clib/io.c, which build.rs
+// compiles and links against. JIT compiled code finds these functions by name in the
+// running process, which only has them if something refers to them, otherwise the
+// linker is free to leave the library out. Anything that JIT compiles calls link first.
+
+use std::ffi::c_void;
+use std::sync::Once;
+
+// Kaleidoscope strings as the runtime lays them out
+#[repr(C)]
+struct KStr {
+    ptr: *const u8,
+    len: i64,
+}
+
+extern "C" {
+    fn putchard(ascii_code: f64) -> f64;
+    fn printd(float_value: f64) -> f64;
+    fn prints(string: KStr) -> f64;
+    fn kstr_concat(left: KStr, right: KStr) -> KStr;
+    fn kstr_compare(left: KStr, right: KStr) -> i64;
+    fn karray_new(len: i64, fill: f64) -> *mut f64;
+    fn karray_out_of_bounds(index: i64, len: i64);
+    fn writechar(ascii_code: f64) -> f64;
+    fn writed(float_value: f64) -> f64;
+    fn writefixed(float_value: f64, digits: f64) -> f64;
+    fn writes(string: KStr) -> f64;
+    fn newline() -> f64;
+    fn readd() -> f64;
+    fn clockd() -> f64;
+    fn seedrand(seed: f64) -> f64;
+    fn randd() -> f64;
+    fn abortmsg(message: KStr) -> f64;
+    fn kaleidrs_guard_install();
+    fn kaleidrs_guarded_call(function: unsafe extern "C" fn() -> f64, result: *mut f64) -> i32;
+    fn kaleidrs_guard_suspend() -> *mut c_void;
+    fn kaleidrs_guard_resume(env: *mut c_void);
+    fn kaleidrs_trap(outcome: i32) -> !;
+    fn kaleidrs_out_of_fuel(fuel: i64);
+    fn kaleidrs_division_fault(left: i64, right: i64);
+    fn kaleidrs_flush_output();
+}
+
+// The math functions of the prelude, see frontend/prelude.rs
+#[cfg_attr(unix, link(name = "m"))]
+extern "C" {
+    fn sin(x: f64) -> f64;
+    fn cos(x: f64) -> f64;
+    fn tan(x: f64) -> f64;
+    fn asin(x: f64) -> f64;
+    fn acos(x: f64) -> f64;
+    fn atan(x: f64) -> f64;
+    fn atan2(y: f64, x: f64) -> f64;
+    fn sinh(x: f64) -> f64;
+    fn cosh(x: f64) -> f64;
+    fn tanh(x: f64) -> f64;
+    fn exp(x: f64) -> f64;
+    fn log(x: f64) -> f64;
+    fn log2(x: f64) -> f64;
+    fn log10(x: f64) -> f64;
+    fn pow(x: f64, y: f64) -> f64;
+    fn sqrt(x: f64) -> f64;
+    fn cbrt(x: f64) -> f64;
+    fn hypot(x: f64, y: f64) -> f64;
+    fn floor(x: f64) -> f64;
+    fn ceil(x: f64) -> f64;
+    fn round(x: f64) -> f64;
+    fn trunc(x: f64) -> f64;
+    fn fabs(x: f64) -> f64;
+    fn fmod(x: f64, y: f64) -> f64;
+    fn fmin(x: f64, y: f64) -> f64;
+    fn fmax(x: f64, y: f64) -> f64;
+}
+
+// What stopped a guarded call before it finished
+pub(crate) enum Stopped {
+    Crashed(&'static str),
+    OutOfFuel,
+    TimedOut,
+}
+
+// Calls a top level expression with signal handlers in place, for the REPL to carry on after
+// a stack overflow or an abort, see kaleidrs_guarded_call in io.c. Says what went wrong when
+// the expression didn't finish.
+pub(crate) unsafe fn guarded_call(function: unsafe extern "C" fn() -> f64) -> Result<f64, Stopped> {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| kaleidrs_guard_install());
+
+    let mut result = 0.0;
+
+    // The numbers of enum guarded_outcome
+    match kaleidrs_guarded_call(function, &mut result) {
+        0 => Ok(result),
+        1 => Err(Stopped::Crashed("a stack overflow")),
+        2 => Err(Stopped::Crashed("an invalid memory access")),
+        3 => Err(Stopped::Crashed("an abort")),
+        4 => Err(Stopped::Crashed("an arithmetic error, like an integer division by zero")),
+        6 => Err(Stopped::Crashed("a panic in a Rust function")),
+        7 => Err(Stopped::OutOfFuel),
+        8 => Err(Stopped::TimedOut),
+        9 => Err(Stopped::Crashed("running out of memory")),
+        _ => Err(Stopped::Crashed("a trap")),
+    }
+}
+
+// Runs Rust code called from a guarded call, a crash in it isn't jumped out of, that would
+// skip over its destructors (or worse), it takes the process down like anywhere else
+pub(crate) fn outside_guard<T>(f: impl FnOnce() -> T) -> T {
+    let env = unsafe { kaleidrs_guard_suspend() };
+    let result = f();
+    unsafe { kaleidrs_guard_resume(env) };
+
+    result
+}
+
+// Stops the guarded call a Rust function that panicked was called from, the panic can't
+// unwind through generated code. Nothing on the Rust side of the call may be left to drop.
+pub(crate) fn host_panicked() -> ! {
+    unsafe { kaleidrs_trap(6) }
+}
+
+// Writes out what generated code printed to stdout without a newline, see io.c
+pub(crate) fn flush_output() {
+    unsafe { kaleidrs_flush_output() }
+}
+
+pub fn link() {
+    let _externs: &[*const extern "C" fn(f64) -> f64] = &[
+        putchard as _,
+        printd as _,
+        prints as _,
+        kstr_concat as _,
+        kstr_compare as _,
+        karray_new as _,
+        karray_out_of_bounds as _,
+        writechar as _,
+        writed as _,
+        writefixed as _,
+        writes as _,
+        newline as _,
+        readd as _,
+        clockd as _,
+        seedrand as _,
+        randd as _,
+        abortmsg as _,
+        kaleidrs_out_of_fuel as _,
+        kaleidrs_division_fault as _,
+        sin as _,
+        cos as _,
+        tan as _,
+        asin as _,
+        acos as _,
+        atan as _,
+        atan2 as _,
+        sinh as _,
+        cosh as _,
+        tanh as _,
+        exp as _,
+        log as _,
+        log2 as _,
+        log10 as _,
+        pow as _,
+        sqrt as _,
+        cbrt as _,
+        hypot as _,
+        floor as _,
+        ceil as _,
+        round as _,
+        trunc as _,
+        fabs as _,
+        fmod as _,
+        fmin as _,
+        fmax as _,
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use inkwell::context::Context;
+
+    use crate::{
+        backend::llvm_backend::BackendError,
+        session::{Session, SessionError},
+    };
+
+    #[test]
+    fn arrays_too_big_to_allocate_stop_the_expression() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        // Eight bytes for each element would wrap around to a handful
+        assert!(matches!(
+            session.eval("len([0; 2305843009213693953])"),
+            Err(SessionError::Backend(BackendError::Crashed("running out of memory")))
+        ));
+        assert_eq!(session.eval("len([1; 3])").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn crashes_are_errors_and_the_session_carries_on() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session.define("extern abortmsg(message: str)").unwrap();
+
+        assert!(matches!(
+            session.eval("abortmsg(\"on purpose\")"),
+            Err(SessionError::Backend(BackendError::Crashed("an abort")))
+        ));
+
+        session.define("def down(x) down(x + 1) + 1").unwrap();
+
+        assert!(matches!(
+            session.eval("down(0)"),
+            Err(SessionError::Backend(BackendError::Crashed("a stack overflow")))
+        ));
+
+        assert_eq!(session.eval("1 + 2").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn random_numbers_take_any_seed() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        session.load("extern seedrand(seed); extern randd()").unwrap();
+
+        let first = |session: &mut Session, seed: &str| {
+            session.eval(&format!("seedrand({seed})")).unwrap();
+            session.eval("randd()").unwrap()
+        };
+
+        assert_eq!(first(&mut session, "0/0"), first(&mut session, "0"));
+        assert_eq!(first(&mut session, "1e300"), first(&mut session, "9223372036854775807"));
+    }
+}
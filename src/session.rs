@@ -0,0 +1,289 @@
+use std::iter;
+use std::path::Path;
+
+use inkwell::context::Context;
+use inkwell::execution_engine::{JitFunction, UnsafeFunctionPointer};
+use inkwell::targets::{InitializationConfig, Target};
+use thiserror::Error;
+
+use crate::{
+    backend::host::IntoHostFunction,
+    backend::llvm_backend::{check_passes, BackendError, CodegenOptions, LLVMCodeGen, LLVMContext, DEFAULT_PASSES},
+    frontend::{
+        ast::Item,
+        lexer::{Lex, Token},
+        parser::{parse_item, ParserError},
+        resolve::{Resolver, SemanticError},
+        simplify::simplify,
+        typecheck::{TypeChecker, TypeError},
+    },
+    runtime,
+};
+
+// A session is the REPL without the reading and printing, for running Kaleidoscope
+// from Rust. Everything defined in it stays defined, and can be called from later
+// code, or from Rust itself through a function pointer.
+//
+//     let context = Context::create();
+//     let mut session = Session::new(&context);
+//
+//     session.define("def square(x) x * x")?;
+//     assert_eq!(session.eval("square(4) + 1")?, 17.0);
+//
+//     let square = unsafe { session.function::<unsafe extern "C" fn(f64) -> f64>("square")? };
+//     assert_eq!(unsafe { square.call(3.0) }, 9.0);
+//
+// Source is compiled the same way the REPL compiles it, one item at a time, and the
+// semicolon after the last item may be left out.
+pub struct Session<'ctx> {
+    llvm: LLVMContext<'ctx>,
+    resolver: Resolver,
+    type_checker: TypeChecker,
+    passes: String,
+}
+
+// Whatever stopped a piece of source from compiling, errors borrow from the source
+// like everywhere else in the compiler. A semantic error's render method says where
+// in the source it is.
+#[derive(Error, Debug)]
+pub enum SessionError<'src> {
+    #[error("Parser error: {0}")]
+    Parser(ParserError<'src>),
+
+    #[error("Semantic error: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+    Semantic(Vec<SemanticError<'src>>),
+
+    #[error("Type error: {0}")]
+    Type(TypeError<'src>),
+
+    #[error("Backend error: {0}")]
+    Backend(BackendError<'src>),
+
+    #[error("Expected a definition, extern or global, found an expression")]
+    NotADefinition,
+
+    #[error("Expected one expression")]
+    NotAnExpression,
+}
+
+// Not #[from], thiserror would make these the source of the error, which has to be 'static
+impl<'src> From<ParserError<'src>> for SessionError<'src> {
+    fn from(err: ParserError<'src>) -> Self {
+        SessionError::Parser(err)
+    }
+}
+
+impl<'src> From<TypeError<'src>> for SessionError<'src> {
+    fn from(err: TypeError<'src>) -> Self {
+        SessionError::Type(err)
+    }
+}
+
+impl<'src> From<BackendError<'src>> for SessionError<'src> {
+    fn from(err: BackendError<'src>) -> Self {
+        SessionError::Backend(err)
+    }
+}
+
+type SessionResult<'src, T> = Result<T, SessionError<'src>>;
+
+impl<'ctx> Session<'ctx> {
+    pub fn new(context: &'ctx Context) -> Self {
+        Self::with_options(context, CodegenOptions::default(), DEFAULT_PASSES)
+            .expect("The default passes are always valid")
+    }
+
+    // A session only ever JIT compiles, whatever options.jit says. Passes LLVM doesn't
+    // know are an error here, like they are for the command line, rather than later on.
+    pub fn with_options(context: &'ctx Context, options: CodegenOptions, passes: &str) -> SessionResult<'static, Self> {
+        runtime::link();
+
+        Target::initialize_native(&InitializationConfig::default())
+            .expect("Failed to initialize native machine target!");
+
+        let options = CodegenOptions { jit: true, ..options };
+        check_passes(passes, &options)?;
+
+        Ok(Self {
+            llvm: LLVMContext::new(context, &options),
+            resolver: Resolver::new(),
+            type_checker: TypeChecker::new(),
+            passes: passes.to_string(),
+        })
+    }
+
+    // Compiles every item in src, like a file passed to the compiler, except top level
+    // expressions are run as they're reached. Items before an error stay defined.
+    pub fn load<'src>(&mut self, src: &'src str) -> SessionResult<'src, ()> {
+        for item in parse(src)? {
+            self.run(item)?;
+        }
+
+        Ok(())
+    }
+
+    // Defines a single function, extern or global
+    pub fn define<'src>(&mut self, src: &'src str) -> SessionResult<'src, ()> {
+        let mut items = parse(src)?;
+
+        match (items.pop(), items.is_empty()) {
+            (Some(item), true) if !is_top_level_expr(&item) => self.run(item).map(drop),
+            _ => Err(SessionError::NotADefinition),
+        }
+    }
+
+    // Runs a single expression, anything it defines (a local function, say) is gone after
+    pub fn eval<'src>(&mut self, src: &'src str) -> SessionResult<'src, f64> {
+        let mut items = parse(src)?;
+
+        match (items.pop(), items.is_empty()) {
+            (Some(item), true) if is_top_level_expr(&item) => Ok(self.run(item)?.unwrap()),
+            _ => Err(SessionError::NotAnExpression),
+        }
+    }
+
+    // Loads a shared library, for externs declared after to call into
+    pub fn load_library(&mut self, path: impl AsRef<Path>) -> SessionResult<'static, ()> {
+        Ok(self.llvm.link_library(path.as_ref())?)
+    }
+
+    // Makes a Rust function callable from Kaleidoscope, through an extern of the same name
+    // declared after, its parameter and return types have to match the Rust function's.
+    // A name can only be registered once. A panic in the function stops the expression
+    // that called it, which comes back as an error, like a crash.
+    //
+    //     session.register("clamp", |x: f64, lo: f64, hi: f64| x.clamp(lo, hi))?;
+    //     session.load("extern clamp(x lo hi); clamp(12, 0, 10)")?;
+    pub fn register<Args>(&mut self, name: &str, function: impl IntoHostFunction<Args>) -> SessionResult<'static, ()> {
+        Ok(self.llvm.register_host_function(name, function.into_host_function())?)
+    }
+
+    // A function defined in the session, to call from Rust. F has to be the function's
+    // signature, an unsafe extern "C" fn taking and returning what the Kaleidoscope types
+    // are generated as, f64 for f64, i64 for i64 and bool for bool. Calling it through
+    // any other signature is undefined behavior.
+    pub unsafe fn function<'a, F: UnsafeFunctionPointer>(
+        &self,
+        name: &'a str,
+    ) -> SessionResult<'a, JitFunction<'ctx, F>> {
+        Ok(self.llvm.jit_function(name)?)
+    }
+
+    // An item that fails to compile is forgotten, along with any name it declared on the
+    // way, so the session is left as it was before
+    fn run<'src>(&mut self, item: Item<'src>) -> SessionResult<'src, Option<f64>> {
+        let checkpoint = (self.resolver.clone(), self.type_checker.clone());
+        let res = self.run_item(item);
+
+        if res.is_err() {
+            (self.resolver, self.type_checker) = checkpoint;
+        }
+
+        res
+    }
+
+    // The same steps the REPL takes for each item, the value of a top level expression
+    // is the only thing that comes back
+    fn run_item<'src>(&mut self, item: Item<'src>) -> SessionResult<'src, Option<f64>> {
+        match item {
+            Item::Extern(proto) => {
+                proto.codegen(&self.llvm)?;
+                self.resolver.declare(&proto);
+                self.type_checker.check_prototype(&proto);
+
+                Ok(None)
+            }
+
+            Item::Global(mut global) => {
+                self.resolver.resolve_global(&global).map_err(SessionError::Semantic)?;
+                self.type_checker.check_global(&mut global)?;
+                global.codegen(&self.llvm)?;
+
+                Ok(None)
+            }
+
+            Item::Function(mut func) => {
+                let top_level = func.proto.get_name() == "__anonymous_expr";
+
+                self.resolver.resolve_function(&func).map_err(SessionError::Semantic)?;
+                self.type_checker.check_function(&mut func)?;
+                simplify(&mut func);
+                func.codegen(&self.llvm)?;
+                let passes = self.llvm.run_passes(&self.passes);
+
+                if !top_level {
+                    return passes.map(|_| None).map_err(SessionError::Backend);
+                }
+
+                // Whatever happens, the expression is gone after
+                let result = passes.and_then(|_| unsafe { self.llvm.jit_eval() });
+                self.llvm.delete_top_level_expr();
+
+                Ok(Some(result?))
+            }
+        }
+    }
+}
+
+// Every item in src, the last one doesn't need a semicolon after it
+fn parse(src: &str) -> SessionResult<'_, Vec<Item<'_>>> {
+    let mut tokens = src.lex().chain(iter::once(Token::Semicolon)).peekable();
+    let mut items = vec![];
+
+    while let Some(token) = tokens.peek() {
+        if let Token::Semicolon = token {
+            tokens.next();
+            continue;
+        }
+
+        items.push(parse_item(&mut tokens)?);
+    }
+
+    Ok(items)
+}
+
+fn is_top_level_expr(item: &Item) -> bool {
+    matches!(item, Item::Function(func) if func.proto.get_name() == "__anonymous_expr")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_passes_are_an_error_up_front() {
+        let context = Context::create();
+
+        assert!(matches!(
+            Session::with_options(&context, CodegenOptions::default(), "instcombine,bogus"),
+            Err(SessionError::Backend(BackendError::InvalidPasses(_)))
+        ));
+
+        let mut session = Session::with_options(&context, CodegenOptions::default(), "instcombine").unwrap();
+        assert_eq!(session.eval("1 + 2").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn items_that_fail_are_forgotten_and_the_rest_stay() {
+        let context = Context::create();
+        let mut session = Session::new(&context);
+
+        assert!(matches!(
+            session.load("def one() 1; def bad() nope(); def two() 2"),
+            Err(SessionError::Semantic(_))
+        ));
+        assert_eq!(session.eval("one()").unwrap(), 1.0);
+        assert!(matches!(session.eval("two()"), Err(SessionError::Semantic(_))));
+
+        // Nothing of bad is left over to get in the way of defining it again
+        session.define("def bad() one() + 1").unwrap();
+        assert_eq!(session.eval("bad()").unwrap(), 2.0);
+
+        assert!(matches!(session.define("def typed(x: i64) -> bool x"), Err(SessionError::Type(_))));
+        session.define("def typed(x: i64) -> i64 x + 1").unwrap();
+        assert_eq!(session.eval("typed(2)").unwrap(), 3.0);
+
+        assert!(matches!(session.define("1 + 2"), Err(SessionError::NotADefinition)));
+        assert!(matches!(session.eval("def three() 3"), Err(SessionError::NotAnExpression)));
+    }
+}